@@ -12,22 +12,32 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::config::BackupConfig;
+use crate::catalog::{Catalog, Entry, Status};
+use crate::config::{BackupConfig, Compression, RetryConfig};
+use crate::filter::Filter;
+use crate::notify::{Notification, Notifier};
 use crate::remotes::remote;
-use crate::services::service::Service;
+use crate::repository::Repository;
+use crate::restore::Restore;
+use crate::services::service::{Dump, Service};
 
 use cron::Schedule;
 use regex::Regex;
+use std::collections::HashSet;
 use std::fmt;
+use std::future::Future;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio_cron_scheduler::JobSchedulerError;
 use tokio_cron_scheduler::{Job, JobScheduler};
 
-use chrono::Weekday;
-use log::{error, info};
+use chrono::{DateTime, TimeDelta, Utc, Weekday};
+use log::{error, info, warn};
+use std::sync::Mutex;
 
 use uuid::Uuid;
 
@@ -36,7 +46,10 @@ pub enum Error {
     InvalidCronConfiguration(cron::error::Error),
     RuntimeError(io::Error),
     InvalidWhenConfiguration(String),
+    InvalidConfiguration(String),
+    HookFailed(String),
     GeneralError(Box<dyn std::error::Error>),
+    UploadError(Box<remote::Error>),
 }
 
 impl std::error::Error for Error {}
@@ -46,7 +59,163 @@ impl fmt::Display for Error {
             Error::InvalidCronConfiguration(error) => write!(f, "Invalid cron string: {}", error),
             Error::RuntimeError(error) => write!(f, "Runtime error: {}", error),
             Error::InvalidWhenConfiguration(msg) => write!(f, "Invalid when string: {}", msg),
+            Error::InvalidConfiguration(msg) => write!(f, "Invalid configuration: {}", msg),
+            Error::HookFailed(msg) => write!(f, "Hook failed: {}", msg),
             Error::GeneralError(error) => write!(f, "{}", error),
+            Error::UploadError(error) => write!(f, "Upload failed: {}", error),
+        }
+    }
+}
+
+/// The outcome of a single execution of a backup.
+#[derive(Debug, Clone)]
+pub struct RunReport {
+    pub name: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub dump_duration: TimeDelta,
+    pub uploads: Vec<remote::Upload>,
+    pub deleted: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+impl RunReport {
+    pub fn new(name: &str) -> RunReport {
+        let now = Utc::now();
+        RunReport {
+            name: String::from(name),
+            start: now,
+            end: now,
+            dump_duration: TimeDelta::zero(),
+            uploads: vec![],
+            deleted: vec![],
+            errors: vec![],
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn status(&self) -> Status {
+        if self.success() {
+            Status::Success
+        } else {
+            Status::Failure
+        }
+    }
+
+    pub fn duration(&self) -> TimeDelta {
+        self.end - self.start
+    }
+
+    pub fn files_uploaded(&self) -> usize {
+        self.uploads.len()
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.uploads.iter().map(|upload| upload.size).sum()
+    }
+}
+
+impl fmt::Display for RunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in {}s (dump {}s): {} files uploaded, {} bytes sent, {} deleted, {} errors",
+            if self.success() {
+                "succeeded"
+            } else {
+                "failed"
+            },
+            self.duration().num_seconds(),
+            self.dump_duration.num_seconds(),
+            self.files_uploaded(),
+            self.bytes_sent(),
+            self.deleted.len(),
+            self.errors.len(),
+        )?;
+        for error in &self.errors {
+            write!(f, "\n - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// How the remote operations failing with a transient error are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, the first one included. 1 means no retries.
+    pub attempts: u32,
+    /// Delay before the first retry
+    pub delay: Duration,
+    /// Factor applied to the delay after every retry
+    pub backoff: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 1,
+            delay: Duration::from_secs(30),
+            backoff: 2.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(config: &RetryConfig) -> Result<RetryPolicy, Error> {
+        let default = RetryPolicy::default();
+        let policy = RetryPolicy {
+            attempts: config.attempts,
+            delay: config.delay.map_or(default.delay, Duration::from_secs),
+            backoff: config.backoff.unwrap_or(default.backoff),
+        };
+        if policy.attempts == 0 {
+            return Err(Error::InvalidConfiguration(String::from(
+                "retry.attempts must be at least 1",
+            )));
+        }
+        if !policy.backoff.is_finite() || policy.backoff < 1.0 {
+            return Err(Error::InvalidConfiguration(String::from(
+                "retry.backoff must be greater or equal to 1",
+            )));
+        }
+        Ok(policy)
+    }
+
+    /// Executes `operation` until it succeeds, fails with a non transient error,
+    /// or the attempts are exhausted. Returns the last result.
+    pub async fn run<T, F, Fut>(
+        &self,
+        name: &str,
+        what: &str,
+        mut operation: F,
+    ) -> Result<T, remote::Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, remote::Error>>,
+    {
+        let mut attempt = 1;
+        let mut delay = self.delay;
+        loop {
+            match operation().await {
+                Err(error) if error.is_transient() && attempt < self.attempts => {
+                    warn!(
+                        "[{}] {} failed (attempt {}/{}): {}. Retrying in {}s",
+                        name,
+                        what,
+                        attempt,
+                        self.attempts,
+                        error,
+                        delay.as_secs_f64()
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = delay.mul_f64(self.backoff);
+                    attempt += 1;
+                }
+                result => return result,
+            }
         }
     }
 }
@@ -58,8 +227,28 @@ pub struct Backup {
     pub remote_path: PathBuf,
     pub when: String,
     pub compress: bool,
+    /// How the archives are created when compress is true (e.g. the encryption recipient)
+    pub archive: remote::ArchiveOptions,
+    /// Store the backup as deduplicated snapshots in a Repository
+    pub repository: bool,
     pub schedule: Schedule,
     pub keep_last: Option<u32>,
+    pub retry: RetryPolicy,
+    /// The remote as named in the configuration (e.g. aws.bucket_name)
+    pub remote_name: String,
+    pub catalog: Option<Arc<Catalog>>,
+    pub last_report: Mutex<Option<RunReport>>,
+    /// End of the last successful execution
+    pub last_success: Mutex<Option<DateTime<Utc>>>,
+    /// Failed executions since the start of the daemon
+    pub failures: AtomicU64,
+    /// Channels of the [notify] section
+    pub notifiers: Vec<Arc<dyn Notifier>>,
+    /// Outcomes sent to the notifiers
+    pub notify_on: Vec<Status>,
+    pub pre_command: Option<String>,
+    pub post_command: Option<String>,
+    pub on_failure_command: Option<String>,
 }
 
 impl Backup {
@@ -74,8 +263,8 @@ impl Backup {
         None
     }
 
-    fn log_result(
-        result: Result<(), remote::Error>,
+    fn log_result<T>(
+        result: &Result<T, remote::Error>,
         name: &str,
         file: &Path,
         remote_name: &str,
@@ -99,7 +288,7 @@ impl Backup {
                 if compress { " or compression" } else { "" },
                 if file.is_dir() { "folder" } else { "file" },
                 file.display(),
-                result.err().unwrap()
+                result.as_ref().err().unwrap()
             );
         }
     }
@@ -277,18 +466,742 @@ impl Backup {
             return Err(Error::InvalidCronConfiguration(schedule.err().unwrap()));
         };
 
+        let retry = match &config.retry {
+            Some(retry) => RetryPolicy::new(retry)?,
+            None => RetryPolicy::default(),
+        };
+
+        // compression implies compress, compress alone means gzip
+        let compression = match config.compression {
+            Some(compression) => Some(compression),
+            None if config.compress => Some(Compression::Gzip),
+            None => None,
+        };
+        let repository = config.repository.unwrap_or(false);
+        if repository && (compression.is_some() || config.encrypt.unwrap_or(false)) {
+            return Err(Error::InvalidConfiguration(String::from(
+                "repository can't be used with compress or encrypt: the chunks are compressed with zstd and not encrypted",
+            )));
+        }
+        let mirror = config.mirror.unwrap_or(false);
+        if mirror && (compression.is_some() || repository) {
+            return Err(Error::InvalidConfiguration(String::from(
+                "mirror requires compress = false",
+            )));
+        }
+        if config.encrypt.unwrap_or(false) && compression.is_none() {
+            return Err(Error::InvalidConfiguration(String::from(
+                "encrypt requires compress = true",
+            )));
+        }
+        if let Some(level) = config.compression_level {
+            let levels = compression.and_then(|compression| compression.levels());
+            match levels {
+                Some(levels) if levels.contains(&level) => {}
+                Some(levels) => {
+                    return Err(Error::InvalidConfiguration(format!(
+                        "compression_level must be in [{}, {}]",
+                        levels.start(),
+                        levels.end()
+                    )))
+                }
+                None => {
+                    return Err(Error::InvalidConfiguration(String::from(
+                        "compression_level requires a compression codec",
+                    )))
+                }
+            }
+        }
+
+        let archive = remote::ArchiveOptions {
+            filter: service.filter(),
+            compression: compression.unwrap_or_default(),
+            level: config.compression_level,
+            mirror,
+            ..Default::default()
+        };
         Ok(Backup {
             name: String::from(name),
             what: service,
             r#where: remote,
             remote_path: PathBuf::from(config.remote_path.clone()),
             when: config.when.clone(),
-            compress: config.compress,
+            compress: compression.is_some(),
+            archive,
+            repository,
             schedule: schedule.unwrap(),
             keep_last: config.keep_last,
+            retry,
+            remote_name: config.r#where.clone(),
+            catalog: None,
+            last_report: Mutex::new(None),
+            last_success: Mutex::new(None),
+            failures: AtomicU64::new(0),
+            notifiers: vec![],
+            notify_on: config
+                .notify_on
+                .clone()
+                .unwrap_or_else(|| vec![Status::Failure]),
+            pre_command: config.pre_command.clone(),
+            post_command: config.post_command.clone(),
+            on_failure_command: config.on_failure_command.clone(),
         })
     }
 
+    /// Lists the objects in the remote `folder` that are subject to the keep_last retention.
+    ///
+    /// When the catalog is available, only the objects written by this backup (and not yet
+    /// deleted) are considered, together with the archives older than the first object of
+    /// the catalog: they have been written before the catalog existed. When the catalog
+    /// can't be read or has no objects for this folder, the whole listing of the folder
+    /// is considered.
+    async fn retention_candidates(
+        &self,
+        folder: &Path,
+        report: &RunReport,
+    ) -> Result<Vec<String>, Error> {
+        let listed = self
+            .retry
+            .run(&self.name, "Listing", || self.r#where.enumerate(folder))
+            .await
+            .map_err(|error| Error::GeneralError(Box::new(error)))?;
+        let catalog = match &self.catalog {
+            Some(catalog) => catalog,
+            None => return Ok(listed),
+        };
+
+        let objects = match catalog.objects(&self.name, &self.remote_name).await {
+            Ok(objects) => objects,
+            Err(error) => {
+                warn!(
+                    "[{}] Unable to read the catalog: {}. Retention applied to every object in {}",
+                    self.name,
+                    error,
+                    folder.display()
+                );
+                return Ok(listed);
+            }
+        };
+
+        // The remotes list the objects with or without the leading /
+        let normalize = |object: &str| object.trim_start_matches('/').to_string();
+        let folder = normalize(folder.to_str().unwrap());
+        let written: HashSet<String> = objects
+            .iter()
+            .map(|object| normalize(object))
+            .chain(
+                report
+                    .uploads
+                    .iter()
+                    .map(|upload| normalize(upload.remote_path.to_str().unwrap())),
+            )
+            .filter(|object| Path::new(object).parent() == Some(Path::new(&folder)))
+            .collect();
+        let oldest = match written.iter().min() {
+            Some(oldest) => oldest,
+            None => {
+                warn!(
+                    "[{}] The catalog has no objects in {}. Retention applied to every object in the folder",
+                    self.name, folder
+                );
+                return Ok(listed);
+            }
+        };
+
+        let mut candidates = vec![];
+        let mut untracked = 0;
+        for object in listed {
+            let normalized = normalize(&object);
+            if written.contains(&normalized) {
+                candidates.push(object);
+            } else if Restore::is_archive(&normalized)
+                && normalized < *oldest
+                // The listing can contain the sibling folders sharing the name as prefix
+                // and the subfolders: their archives belong to other backups
+                && Path::new(&normalized).parent() == Some(Path::new(&folder))
+            {
+                untracked += 1;
+                candidates.push(object);
+            }
+        }
+        if untracked > 0 {
+            warn!(
+                "[{}] {} archives in {} predate the catalog: they are subject to the retention too",
+                self.name, untracked, folder
+            );
+        }
+        Ok(candidates)
+    }
+
+    /// Executes the hook `command` (pre_command, post_command, ...) with sh -c.
+    /// The backup is described by the BACUP_* environment variables.
+    async fn hook(
+        &self,
+        kind: &str,
+        command: &Option<String>,
+        dump: Option<&Dump>,
+        report: &RunReport,
+        status: Option<Status>,
+    ) -> Result<(), Error> {
+        let command = match command {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        info!("[{}] Executing {}...", self.name, kind);
+        let dump = dump.and_then(|dump| dump.path.clone()).unwrap_or_default();
+        let output = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("BACUP_BACKUP", &self.name)
+            .env("BACUP_REMOTE", &self.remote_name)
+            .env("BACUP_DUMP", dump)
+            .env(
+                "BACUP_STATUS",
+                status.map_or_else(String::new, |status| status.to_string()),
+            )
+            .env("BACUP_ERRORS", report.errors.join("\n"))
+            .output()
+            .await
+            .map_err(Error::RuntimeError)?;
+        if !output.status.success() {
+            return Err(Error::HookFailed(format!(
+                "{} {} exited with {}: {}",
+                kind,
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Executes the backup: dumps the service, uploads the dumped files to the remote
+    /// and applies the retention policy. Every error is recorded in the returned report.
+    ///
+    /// The pre_command is executed before the dump, and the run is aborted if it fails.
+    /// The post_command is executed after the upload (while the dump still exists) and
+    /// the on_failure_command when anything failed.
+    pub async fn run(&self) -> RunReport {
+        let mut report = RunReport::new(&self.name);
+        let pre = self
+            .hook("pre_command", &self.pre_command, None, &report, None)
+            .await
+            .map_err(|error| error.to_string());
+        match pre {
+            Err(error) => report.errors.push(error),
+            Ok(()) => {
+                let mut dump = None;
+                if let Err(error) = self.execute(&mut report, &mut dump).await {
+                    report.errors.push(error.to_string());
+                }
+                let status = Some(report.status());
+                if let Err(error) = self
+                    .hook(
+                        "post_command",
+                        &self.post_command,
+                        dump.as_ref(),
+                        &report,
+                        status,
+                    )
+                    .await
+                {
+                    report.errors.push(error.to_string());
+                }
+            }
+        }
+        if !report.success() {
+            let status = Some(report.status());
+            if let Err(error) = self
+                .hook(
+                    "on_failure_command",
+                    &self.on_failure_command,
+                    None,
+                    &report,
+                    status,
+                )
+                .await
+            {
+                error!("[{}] {}", self.name, error);
+                report.errors.push(error.to_string());
+            }
+        }
+        report.end = Utc::now();
+
+        if let Some(catalog) = &self.catalog {
+            if let Err(error) = catalog
+                .append(&Entry::new(&self.remote_name, &report), self.keep_last)
+                .await
+            {
+                error!("[{}] Unable to update the catalog: {}", self.name, error);
+            }
+        }
+        report
+    }
+
+    /// Stores the report of the last execution and updates the execution statistics.
+    pub fn record(&self, report: RunReport) {
+        if report.success() {
+            *self.last_success.lock().unwrap() = Some(report.end);
+        } else {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        *self.last_report.lock().unwrap() = Some(report);
+    }
+
+    /// Loads the end of the last successful execution from the catalog, if any.
+    pub async fn load_last_success(&self) -> Result<(), Error> {
+        let catalog = match &self.catalog {
+            Some(catalog) => catalog,
+            None => return Ok(()),
+        };
+        let last_success = catalog
+            .entries(Some(&self.name))
+            .await
+            .map_err(|error| Error::GeneralError(Box::new(error)))?
+            .into_iter()
+            .filter(|entry| entry.status == Status::Success)
+            .map(|entry| entry.end)
+            .max();
+        *self.last_success.lock().unwrap() = last_success;
+        Ok(())
+    }
+
+    /// Sends the report to the notifiers, when its status is one of notify_on.
+    pub async fn notify(&self, report: &RunReport) {
+        if !self.notify_on.contains(&report.status()) {
+            return;
+        }
+        let notification = Notification::new(
+            &self.remote_name,
+            report,
+            self.schedule.upcoming(Utc).next(),
+        );
+        for notifier in &self.notifiers {
+            match notifier.notify(&notification).await {
+                Ok(()) => info!("[{}] Notified {}", self.name, notifier.name()),
+                Err(error) => error!(
+                    "[{}] Unable to notify {}: {}",
+                    self.name,
+                    notifier.name(),
+                    error
+                ),
+            }
+        }
+    }
+
+    /// Dumps the service and uploads the dump. The dump is stored in `dump`, so that it is
+    /// removed by the caller.
+    async fn execute(&self, report: &mut RunReport, dump: &mut Option<Dump>) -> Result<(), Error> {
+        let remote = &self.r#where;
+        let service = &self.what;
+        let compress = self.compress;
+        let name = self.name.clone();
+        let remote_prefix = self.remote_path.clone();
+        let keep_last = self.keep_last;
+
+        // First call dump, to trigger the dump service if present
+        info!("[{}] Calling dump...", &name);
+        let dump_start = Utc::now();
+        let path = match service.dump().await {
+            Err(error) => {
+                report.dump_duration = Utc::now() - dump_start;
+                return Err(Error::GeneralError(error));
+            }
+            Ok(dumped) => dump.insert(dumped).path.clone().unwrap_or_default(),
+        };
+        report.dump_duration = Utc::now() - dump_start;
+
+        if path.exists() {
+            // When dump goes out of scope, the dump is removed by Drop.
+            info!("[{}] Dumped {}. Backing it up", name, path.display());
+        }
+
+        // Then loop over all the dumped files and backup them as specified
+        let mut local_files = service.list().await;
+
+        if self.repository {
+            self.upload_snapshot(report, &local_files).await;
+            return Ok(());
+        }
+
+        // The files of different roots don't share a prefix
+        let roots = service.roots();
+        if roots.len() > 1 {
+            self.upload_roots(report, &roots, &local_files).await;
+            return Ok(());
+        }
+
+        // If the local_files list contains a single file, the upload should be in the form:
+        // /remote/prefix/filename
+        // even if the local file is in /local/path/in/folder/filename
+        let mut single_file = local_files.len() == 1;
+
+        // If the local_files list is a list of multiple files, we suppose these files all
+        // share the same root. To find the root we can simply find the shortest string.
+        // In this way, we can remove the "root prefix" and upload correctly.
+        // From:
+        // - /local/path/in/folder/A
+        // - /local/path/in/folder/B
+        // To
+        // - /remote/prefix/A
+        // - /remote/prefix/B
+        let local_files_clone = local_files.clone();
+        let mut local_prefix = local_files_clone
+            .iter()
+            .min_by(|a, b| a.cmp(b))
+            .unwrap()
+            .as_path();
+
+        // The local_prefix found is:
+        // In case of a folder: the shortest path inside the folder we want to backup.
+        // In case of a file: the file itself.
+
+        // If is a folder, we of course don't want to consider this a prefix, but its parent.
+        if !single_file {
+            local_prefix = local_prefix.parent().unwrap();
+        }
+
+        // If we are going to compress the local_files we need to take care of the content of
+        // the .list()-ed files.
+        // In case of compression of a folder, e.g. if the list_contains glob(/a/folder/**)
+        // we have to pass the the Remote.upload_folder_compressed only /a/folder for creating
+        // a single archive.
+        // Otherwise we'll create a different archive for every file/folder and this is wrong.
+        let all_with_same_prefix = local_files_clone
+            .iter()
+            .all(|path| path.starts_with(local_prefix));
+        if compress && !single_file && all_with_same_prefix {
+            single_file = true;
+            local_files = vec![PathBuf::from(local_prefix)];
+        }
+
+        // Special case in which we want to upload a folder without compression
+        // If all the files share the same prefix, we upload all the files in this prefix.
+        // The remote should handle eventual incremental backup.
+        if !single_file && all_with_same_prefix && !compress {
+            let remote_path = &remote_prefix;
+            info!(
+                "[{}] Uploading a list of files to {}",
+                name,
+                remote_path.display()
+            );
+            let result = self
+                .retry
+                .run(&name, "Upload", || {
+                    remote.upload_folder(&local_files, remote_path, &self.archive)
+                })
+                .await;
+            Backup::log_result(
+                &result,
+                &name,
+                local_prefix,
+                &remote.name(),
+                remote_path,
+                compress,
+            );
+            match result {
+                Ok(uploads) => report.uploads.extend(uploads),
+                Err(error) => report
+                    .errors
+                    .push(Error::UploadError(Box::new(error)).to_string()),
+            }
+            info!("[{}] Uploaded completed.", name);
+            // Set local_files to empty vector for skipping the next loop
+            // and avoid to add another else branch that will increase the
+            // indentation again.
+            local_files = vec![];
+        }
+
+        for file in local_files {
+            let remote_path = if single_file {
+                remote_prefix.join(file.file_name().unwrap())
+            } else {
+                remote_prefix.join(file.strip_prefix(local_prefix).unwrap())
+            };
+
+            let result: Result<remote::Upload, remote::Error>;
+            if file.is_dir() {
+                // compress for sure, the uncompressed scenarios has been treated
+                // outside this loop
+                info!(
+                    "[{}] Compressing folder {} and uploading to {}",
+                    name,
+                    file.display(),
+                    remote_path.display()
+                );
+                result = self
+                    .retry
+                    .run(&name, "Upload", || {
+                        remote.upload_folder_compressed(
+                            std::slice::from_ref(&file),
+                            &remote_path,
+                            &self.archive,
+                        )
+                    })
+                    .await;
+            } else if compress {
+                info!(
+                    "[{}] Compressing file {} and uploading to {}",
+                    name,
+                    file.display(),
+                    remote_path.display()
+                );
+                result = self
+                    .retry
+                    .run(&name, "Upload", || {
+                        remote.upload_file_compressed(&file, &remote_path, &self.archive)
+                    })
+                    .await;
+            } else {
+                info!(
+                    "[{}] Uploading file {} to {}",
+                    name,
+                    file.display(),
+                    remote_path.display()
+                );
+                result = self
+                    .retry
+                    .run(&name, "Upload", || remote.upload_file(&file, &remote_path))
+                    .await;
+            }
+
+            Backup::log_result(
+                &result,
+                &name,
+                &file,
+                &remote.name(),
+                &remote_path,
+                compress,
+            );
+            match result {
+                Ok(upload) => report.uploads.push(upload),
+                Err(error) => report
+                    .errors
+                    .push(Error::UploadError(Box::new(error)).to_string()),
+            }
+
+            // Handle keep_last
+            if let Some(to_keep) = keep_last {
+                self.apply_retention(remote_path.parent().unwrap(), to_keep as usize, report)
+                    .await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes from the remote `folder` the oldest objects, keeping the last `to_keep`.
+    async fn apply_retention(&self, folder: &Path, to_keep: usize, report: &mut RunReport) {
+        let name = &self.name;
+        let mut list = match self.retention_candidates(folder, report).await {
+            Ok(list) => list,
+            Err(error) => {
+                error!("[{}] Error during retention listing: {}", name, error);
+                report
+                    .errors
+                    .push(format!("Error during retention listing: {}", error));
+                vec![]
+            }
+        };
+        if list.len() > to_keep {
+            list.sort();
+            list.reverse();
+            for delete_me in &list[to_keep..] {
+                let delete_path = PathBuf::from(delete_me);
+                let deleted = self
+                    .retry
+                    .run(name, "Delete", || self.r#where.delete(&delete_path))
+                    .await;
+                if let Some(error) = deleted.err() {
+                    error!("[{}] Error during delete of {}: {}", name, delete_me, error);
+                    report
+                        .errors
+                        .push(format!("Error during delete of {}: {}", delete_me, error));
+                } else {
+                    info!("[{}] Deleted {}", name, delete_me);
+                    report.deleted.push(delete_me.clone());
+                }
+            }
+        }
+    }
+
+    /// Uploads the files listed by a service with multiple `roots`, preserving the layout
+    /// of every root: the files are uploaded into remote_path/<root name> or, when
+    /// compressed, in a single archive with a top-level folder for every root.
+    async fn upload_roots(&self, report: &mut RunReport, roots: &[PathBuf], files: &[PathBuf]) {
+        let remote = &self.r#where;
+        let name = &self.name;
+
+        if self.compress {
+            let remote_path = self.remote_path.join(name);
+            info!(
+                "[{}] Compressing {} folders and uploading to {}",
+                name,
+                roots.len(),
+                remote_path.display()
+            );
+            // The roots of the file and glob patterns contain paths that are not listed
+            let options = remote::ArchiveOptions {
+                files: Some(files.to_vec()),
+                ..self.archive.clone()
+            };
+            let result = self
+                .retry
+                .run(name, "Upload", || {
+                    remote.upload_folder_compressed(roots, &remote_path, &options)
+                })
+                .await;
+            for root in roots {
+                Backup::log_result(&result, name, root, &remote.name(), &remote_path, true);
+            }
+            match result {
+                Ok(upload) => report.uploads.push(upload),
+                Err(error) => report
+                    .errors
+                    .push(Error::UploadError(Box::new(error)).to_string()),
+            }
+            if let Some(to_keep) = self.keep_last {
+                self.apply_retention(&self.remote_path, to_keep as usize, report)
+                    .await;
+            }
+            return;
+        }
+
+        for root in roots {
+            let remote_path = self.remote_path.join(root.file_name().unwrap());
+            let paths: Vec<PathBuf> = files
+                .iter()
+                .filter(|path| path.starts_with(root) && *path != root)
+                .cloned()
+                .collect();
+            // upload_folder requires at least two paths to find their prefix
+            let result = match paths.as_slice() {
+                [] => continue,
+                [path] if path.is_dir() => {
+                    let error = format!(
+                        "Nothing to upload from {}: the patterns match only the folder {}, not its content",
+                        root.display(),
+                        path.display()
+                    );
+                    error!("[{}] {}", name, error);
+                    report.errors.push(error);
+                    continue;
+                }
+                [path] => {
+                    let remote_path = remote_path.join(path.strip_prefix(root).unwrap());
+                    info!(
+                        "[{}] Uploading file {} to {}",
+                        name,
+                        path.display(),
+                        remote_path.display()
+                    );
+                    self.retry
+                        .run(name, "Upload", || remote.upload_file(path, &remote_path))
+                        .await
+                        .map(|upload| vec![upload])
+                }
+                _ => {
+                    info!(
+                        "[{}] Uploading a list of files to {}",
+                        name,
+                        remote_path.display()
+                    );
+                    self.retry
+                        .run(name, "Upload", || {
+                            remote.upload_folder(&paths, &remote_path, &self.archive)
+                        })
+                        .await
+                }
+            };
+            Backup::log_result(&result, name, root, &remote.name(), &remote_path, false);
+            match result {
+                Ok(uploads) => report.uploads.extend(uploads),
+                Err(error) => report
+                    .errors
+                    .push(Error::UploadError(Box::new(error)).to_string()),
+            }
+        }
+    }
+
+    /// Uploads a snapshot of the `files` listed by the service into the repository and,
+    /// with keep_last, deletes the oldest snapshots and their unreferenced chunks.
+    ///
+    /// The snapshot has the layout of the compressed archives: the paths are relative
+    /// to the root of a service with a single root, prefixed by the root name with
+    /// multiple roots, and the dumped files and folders keep their name.
+    async fn upload_snapshot(&self, report: &mut RunReport, files: &[PathBuf]) {
+        let name = &self.name;
+        let repository =
+            Repository::new(name, self.r#where.as_ref(), &self.remote_path, &self.retry);
+
+        let roots = self.what.roots();
+        let mut paths = vec![];
+        for file in files {
+            match roots.iter().find(|root| file.starts_with(root)) {
+                Some(root) if roots.len() > 1 => {
+                    let relative = file.strip_prefix(root.parent().unwrap()).unwrap();
+                    paths.push((file.clone(), relative.to_path_buf()));
+                }
+                Some(root) => {
+                    if file != root {
+                        let relative = file.strip_prefix(root).unwrap();
+                        paths.push((file.clone(), relative.to_path_buf()));
+                    }
+                }
+                None => {
+                    let parent = file.parent().unwrap();
+                    paths.push((file.clone(), PathBuf::from(file.file_name().unwrap())));
+                    if file.is_dir() {
+                        let filter = Filter::new(file, &[], false).unwrap();
+                        match filter.walk(file).await {
+                            Ok(walk) => {
+                                for entry in walk.included {
+                                    let relative = entry.strip_prefix(parent).unwrap();
+                                    paths.push((entry.clone(), relative.to_path_buf()));
+                                }
+                            }
+                            Err(error) => {
+                                report.errors.push(format!(
+                                    "Unable to list {}: {}",
+                                    file.display(),
+                                    error
+                                ));
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        info!(
+            "[{}] Uploading a snapshot of {} files and folders to {}",
+            name,
+            paths.len(),
+            self.remote_path.display()
+        );
+        match repository.backup(&paths).await {
+            Ok(uploads) => report.uploads.extend(uploads),
+            Err(error) => {
+                error!("[{}] Snapshot failed: {}", name, error);
+                report.errors.push(format!("Snapshot failed: {}", error));
+                return;
+            }
+        }
+
+        if let Some(to_keep) = self.keep_last {
+            match repository.forget(to_keep as usize).await {
+                Ok(deleted) => report.deleted.extend(deleted),
+                Err(error) => {
+                    error!("[{}] Error during retention: {}", name, error);
+                    report
+                        .errors
+                        .push(format!("Error during retention: {}", error));
+                }
+            }
+        }
+    }
+
     pub async fn schedule(
         self: Arc<Self>,
         scheduler: &mut JobScheduler,
@@ -300,181 +1213,18 @@ impl Backup {
                     let inst = self.clone();
                     Box::pin(async move {
                         let inst = inst.as_ref();
-                        let remote = &inst.r#where;
-                        let service = &inst.what;
-                        let compress = inst.compress;
-                        let name = inst.name.clone();
-                        let remote_prefix = inst.remote_path.clone();
-                        let keep_last = inst.keep_last;
-
-                        // First call dump, to trigger the dump service if present
-                        info!("[{}] Calling dump...", &name);
-                        let dump = match service.dump().await {
-                            Err(error) => {
-                                error!("{}", Error::GeneralError(error));
-                                return;
-                            }
-                            Ok(dump) => dump,
-                        };
-
-                        let path = dump.path.clone().unwrap_or_default();
-                        if path.exists() {
-                            // When dump goes out of scope, the dump is removed by Drop.
-                            info!("[{}] Dumped {}. Backing it up", name, path.display());
-                        }
-
-                        // Then loop over all the dumped files and backup them as specified
-                        let mut local_files = service.list().await;
-
-                        // If the local_files list contains a single file, the upload should be in the form:
-                        // /remote/prefix/filename
-                        // even if the local file is in /local/path/in/folder/filename
-                        let mut single_file = local_files.len() == 1;
-
-                        // If the local_files list is a list of multiple files, we suppose these files all
-                        // share the same root. To find the root we can simply find the shortest string.
-                        // In this way, we can remove the "root prefix" and upload correctly.
-                        // From:
-                        // - /local/path/in/folder/A
-                        // - /local/path/in/folder/B
-                        // To
-                        // - /remote/prefix/A
-                        // - /remote/prefix/B
-                        let local_files_clone = local_files.clone();
-                        let mut local_prefix = local_files_clone
-                            .iter()
-                            .min_by(|a, b| a.cmp(b))
-                            .unwrap()
-                            .as_path();
-
-                        // The local_prefix found is:
-                        // In case of a folder: the shortest path inside the folder we want to backup.
-                        // In case of a file: the file itself.
-
-                        // If is a folder, we of course don't want to consider this a prefix, but its parent.
-                        if !single_file {
-                            local_prefix = local_prefix.parent().unwrap();
-                        }
-
-                        // If we are going to compress the local_files we need to take care of the content of
-                        // the .list()-ed files.
-                        // In case of compression of a folder, e.g. if the list_contains glob(/a/folder/**)
-                        // we have to pass the the Remote.upload_folder_compressed only /a/folder for creating
-                        // a single archive.
-                        // Otherwise we'll create a different archive for every file/folder and this is wrong.
-                        let all_with_same_prefix = local_files_clone
-                            .iter()
-                            .all(|path| path.starts_with(local_prefix));
-                        if compress && !single_file && all_with_same_prefix {
-                            single_file = true;
-                            local_files = vec![PathBuf::from(local_prefix)];
-                        }
-
-                        // Special case in which we want to upload a folder without compression
-                        // If all the files share the same prefix, we upload all the files in this prefix.
-                        // The remote should handle eventual incremental backup.
-                        if !single_file && all_with_same_prefix && !compress {
-                            let remote_path = &remote_prefix;
-                            info!(
-                                "[{}] Uploading a list of files to {}",
-                                name,
-                                remote_path.display()
-                            );
-                            let result = remote.upload_folder(&local_files, remote_path).await;
-                            Backup::log_result(
-                                result,
-                                &name,
-                                local_prefix,
-                                &remote.name(),
-                                remote_path,
-                                compress,
-                            );
-                            info!("[{}] Uploaded completed.", name);
-                            // Set local_files to empty vector for skipping the next loop
-                            // and avoid to add another else branch that will increase the
-                            // indentation again.
-                            local_files = vec![];
-                        }
-
-                        for file in local_files {
-                            let remote_path = if single_file {
-                                remote_prefix.join(file.file_name().unwrap())
-                            } else {
-                                remote_prefix.join(file.strip_prefix(local_prefix).unwrap())
-                            };
-
-                            let result: Result<(), remote::Error>;
-                            if file.is_dir() {
-                                // compress for sure, the uncompressed scenarios has been treated
-                                // outside this loop
-                                info!(
-                                    "[{}] Compressing folder {} and uploading to {}",
-                                    name,
-                                    file.display(),
-                                    remote_path.display()
-                                );
-                                result = remote.upload_folder_compressed(&file, &remote_path).await;
-                            } else if compress {
-                                info!(
-                                    "[{}] Compressing file {} and uploading to {}",
-                                    name,
-                                    file.display(),
-                                    remote_path.display()
-                                );
-                                result = remote.upload_file_compressed(&file, &remote_path).await;
-                            } else {
-                                info!(
-                                    "[{}] Uploading file {} to {}",
-                                    name,
-                                    file.display(),
-                                    remote_path.display()
-                                );
-                                result = remote.upload_file(&file, &remote_path).await;
-                            }
-
-                            // Handle keep_last
-                            if let Some(to_keep) = keep_last {
-                                let to_keep = to_keep as usize;
-                                match remote.enumerate(remote_path.parent().unwrap()).await {
-                                    Ok(mut list) => {
-                                        if list.len() > to_keep {
-                                            list.sort();
-                                            list.reverse();
-                                            for delete_me in &list[to_keep..] {
-                                                if let Some(error) = remote
-                                                    .delete(&PathBuf::from(delete_me))
-                                                    .await
-                                                    .err()
-                                                {
-                                                    error!(
-                                                        "[{}] Error during delete of {}: {}",
-                                                        name, delete_me, error
-                                                    );
-                                                } else {
-                                                    info!("[{}] Deleted {}", name, delete_me);
-                                                }
-                                            }
-                                        }
-                                    }
-                                    Err(error) => {
-                                        error!("Error during remote.enumerate: {}", error)
-                                    }
-                                }
-                            }
-
-                            Backup::log_result(
-                                result,
-                                &name,
-                                &file,
-                                &remote.name(),
-                                &remote_path,
-                                compress,
-                            );
+                        let report = inst.run().await;
+                        if report.success() {
+                            info!("[{}] Backup {}", inst.name, report);
+                        } else {
+                            error!("[{}] Backup {}", inst.name, report);
                         }
+                        inst.notify(&report).await;
+                        inst.record(report);
 
                         info!(
                             "[{}] Next run: {}",
-                            name,
+                            inst.name,
                             inst.schedule.upcoming(chrono::Utc).take(1).next().unwrap()
                         );
                     })
@@ -488,6 +1238,11 @@ impl Backup {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::LocalhostConfig;
+    use crate::remotes::localhost::tests::PrefixListing;
+    use crate::remotes::localhost::Localhost;
+    use crate::restore::Restore;
+    use crate::services::folders::Folder;
     use croner::Cron;
 
     fn validate_cron_expression(when: &str) {
@@ -570,4 +1325,684 @@ mod tests {
         assert!(Backup::parse_when("Monthtly 0 00:00").is_err());
         assert!(Backup::parse_when("Monthtly 32 00:00").is_err());
     }
+
+    #[tokio::test]
+    async fn test_run_folder_compressed() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let remote = Localhost::new(
+            LocalhostConfig {
+                path: String::from(tmp_dir.path().to_str().unwrap()),
+            },
+            "test_remote",
+        )
+        .unwrap();
+        let service = Folder::new(
+            std::env::current_dir()
+                .unwrap()
+                .join("src")
+                .to_str()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        let config = BackupConfig {
+            what: String::from("folders.test"),
+            r#where: String::from("localhost.test"),
+            when: String::from("daily 00:00"),
+            remote_path: String::from("/backup/src"),
+            compress: true,
+            ..Default::default()
+        };
+
+        let mut backup = Backup::new("test", Box::new(remote), Box::new(service), &config)
+            .await
+            .unwrap();
+        let catalog = Arc::new(Catalog::new(&tmp_dir.path().join("catalog.toml")));
+        backup.catalog = Some(catalog.clone());
+        let report = backup.run().await;
+        assert!(report.success());
+        assert_eq!(report.files_uploaded(), 1);
+        assert!(report.bytes_sent() > 0);
+        assert!(report.uploads[0]
+            .remote_path
+            .to_str()
+            .unwrap()
+            .ends_with("-src.tar.gz"));
+
+        let archives = std::fs::read_dir(tmp_dir.path().join("backup").join("src")).unwrap();
+        assert_eq!(archives.count(), 1);
+
+        let entries = catalog.entries(Some("test")).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].remote, "localhost.test");
+        assert_eq!(entries[0].objects.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retention_with_catalog() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let folder = tmp_dir.path().join("backup").join("src");
+        std::fs::create_dir_all(&folder).unwrap();
+        // Archives written before the catalog existed, and a file not written by bacup
+        for name in [
+            "2020-01-01-00.00-src.tar.gz",
+            "2020-01-02-00.00-src.tar.gz",
+            "notes.txt",
+        ] {
+            std::fs::write(folder.join(name), "content").unwrap();
+        }
+
+        let remote = Localhost::new(
+            LocalhostConfig {
+                path: String::from(tmp_dir.path().to_str().unwrap()),
+            },
+            "test_remote",
+        )
+        .unwrap();
+        let service = Folder::new(
+            std::env::current_dir()
+                .unwrap()
+                .join("src")
+                .to_str()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        let config = BackupConfig {
+            what: String::from("folders.test"),
+            r#where: String::from("localhost.test"),
+            when: String::from("daily 00:00"),
+            remote_path: String::from("/backup/src"),
+            compress: true,
+            keep_last: Some(2),
+            ..Default::default()
+        };
+
+        let mut backup = Backup::new("test", Box::new(remote), Box::new(service), &config)
+            .await
+            .unwrap();
+        backup.catalog = Some(Arc::new(Catalog::new(&tmp_dir.path().join("catalog.toml"))));
+        let report = backup.run().await;
+        assert!(report.success());
+        assert_eq!(report.deleted.len(), 1);
+        assert!(report.deleted[0].ends_with("2020-01-01-00.00-src.tar.gz"));
+
+        assert!(!folder.join("2020-01-01-00.00-src.tar.gz").exists());
+        assert!(folder.join("2020-01-02-00.00-src.tar.gz").exists());
+        assert!(folder.join("notes.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_retention_with_catalog_siblings() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let backup_dir = tmp_dir.path().join("backup");
+        // Older archives of other backups: in a sibling folder sharing the name as
+        // prefix and in a subfolder
+        for path in [
+            "src/2020-01-01-00.00-src.tar.gz",
+            "src-old/2019-01-01-00.00-src.tar.gz",
+            "src/nested/2019-01-01-00.00-src.tar.gz",
+        ] {
+            let path = backup_dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "content").unwrap();
+        }
+
+        // The remote lists them, like the S3 prefix listing and the SSH find
+        let remote = PrefixListing::new(tmp_dir.path());
+        let service = Folder::new(
+            std::env::current_dir()
+                .unwrap()
+                .join("src")
+                .to_str()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        let config = BackupConfig {
+            what: String::from("folders.test"),
+            r#where: String::from("localhost.test"),
+            when: String::from("daily 00:00"),
+            remote_path: String::from("/backup/src"),
+            compress: true,
+            keep_last: Some(1),
+            ..Default::default()
+        };
+
+        let mut backup = Backup::new("test", Box::new(remote), Box::new(service), &config)
+            .await
+            .unwrap();
+        backup.catalog = Some(Arc::new(Catalog::new(&tmp_dir.path().join("catalog.toml"))));
+        let report = backup.run().await;
+        assert!(report.success());
+        assert_eq!(report.deleted.len(), 1);
+        assert!(report.deleted[0].ends_with("src/2020-01-01-00.00-src.tar.gz"));
+
+        assert!(backup_dir
+            .join("src-old")
+            .join("2019-01-01-00.00-src.tar.gz")
+            .exists());
+        assert!(backup_dir
+            .join("src")
+            .join("nested")
+            .join("2019-01-01-00.00-src.tar.gz")
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_requires_compress() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let remote = Localhost::new(
+            LocalhostConfig {
+                path: String::from(tmp_dir.path().to_str().unwrap()),
+            },
+            "test_remote",
+        )
+        .unwrap();
+        let service = Folder::new("Cargo.toml").await.unwrap();
+        let config = BackupConfig {
+            what: String::from("folders.test"),
+            r#where: String::from("localhost.test"),
+            when: String::from("daily 00:00"),
+            remote_path: String::from("/backup"),
+            compress: false,
+            encrypt: Some(true),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            Backup::new("test", Box::new(remote), Box::new(service), &config).await,
+            Err(Error::InvalidConfiguration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_compression_level() {
+        for (compression, level, valid) in [
+            (Some(Compression::Zstd), 19, true),
+            (Some(Compression::Gzip), 19, false),
+            (Some(Compression::None), 1, false),
+            (None, 1, false),
+        ] {
+            let tmp_dir = tempfile::tempdir().unwrap();
+            let remote = Localhost::new(
+                LocalhostConfig {
+                    path: String::from(tmp_dir.path().to_str().unwrap()),
+                },
+                "test_remote",
+            )
+            .unwrap();
+            let service = Folder::new("Cargo.toml").await.unwrap();
+            let config = BackupConfig {
+                what: String::from("folders.test"),
+                r#where: String::from("localhost.test"),
+                when: String::from("daily 00:00"),
+                remote_path: String::from("/backup"),
+                compression,
+                compression_level: Some(level),
+                ..Default::default()
+            };
+
+            let backup = Backup::new("test", Box::new(remote), Box::new(service), &config).await;
+            match backup {
+                Ok(backup) => {
+                    assert!(valid);
+                    assert!(backup.compress);
+                    assert_eq!(backup.archive.level, Some(level));
+                }
+                Err(error) => {
+                    assert!(!valid);
+                    assert!(matches!(error, Error::InvalidConfiguration(_)));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_repository() {
+        let local = tempfile::tempdir().unwrap();
+        std::fs::create_dir(local.path().join("site")).unwrap();
+        std::fs::write(local.path().join("site").join("index.html"), "bacup").unwrap();
+        let service = Folder::new(local.path().to_str().unwrap()).await.unwrap();
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let remote = Localhost::new(
+            LocalhostConfig {
+                path: String::from(tmp_dir.path().to_str().unwrap()),
+            },
+            "test_remote",
+        )
+        .unwrap();
+        let mut config = BackupConfig {
+            what: String::from("folders.test"),
+            r#where: String::from("localhost.test"),
+            when: String::from("daily 00:00"),
+            remote_path: String::from("/backup"),
+            repository: Some(true),
+            keep_last: Some(1),
+            compress: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            Backup::new(
+                "test",
+                Box::new(remote.clone()),
+                Box::new(service.clone()),
+                &config
+            )
+            .await,
+            Err(Error::InvalidConfiguration(_))
+        ));
+
+        config.compress = false;
+        let backup = Backup::new("test", Box::new(remote.clone()), Box::new(service), &config)
+            .await
+            .unwrap();
+        let report = backup.run().await;
+        assert!(report.success());
+        // A chunk and the manifest
+        assert_eq!(report.files_uploaded(), 2);
+
+        std::fs::write(local.path().join("site").join("index.html"), "changed").unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let report = backup.run().await;
+        assert!(report.success());
+        // The first snapshot and its chunk are deleted
+        assert_eq!(report.deleted.len(), 2);
+
+        let restore = Restore::new("test", Box::new(remote), &config);
+        assert_eq!(restore.archives().await.unwrap().len(), 1);
+        let dest = tempfile::tempdir().unwrap();
+        restore.restore(None, dest.path()).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest.path().join("site").join("index.html")).unwrap(),
+            "changed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_folder_roots() {
+        let local = tempfile::tempdir().unwrap();
+        for path in ["nginx/nginx.conf", "nginx/sites/app", "site/index.html"] {
+            let path = local.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "bacup").unwrap();
+        }
+        let service = Folder::from_patterns(&[
+            String::from(local.path().join("nginx").to_str().unwrap()),
+            String::from(local.path().join("site").to_str().unwrap()),
+        ])
+        .await
+        .unwrap();
+
+        for compress in [false, true] {
+            let tmp_dir = tempfile::tempdir().unwrap();
+            let remote = Localhost::new(
+                LocalhostConfig {
+                    path: String::from(tmp_dir.path().to_str().unwrap()),
+                },
+                "test_remote",
+            )
+            .unwrap();
+            let config = BackupConfig {
+                what: String::from("folders.test"),
+                r#where: String::from("localhost.test"),
+                when: String::from("daily 00:00"),
+                remote_path: String::from("/backup"),
+                compress,
+                ..Default::default()
+            };
+            let backup = Backup::new("test", Box::new(remote), Box::new(service.clone()), &config)
+                .await
+                .unwrap();
+            let report = backup.run().await;
+            assert!(report.success());
+
+            // Every root keeps its layout in its own folder
+            let dest = if compress {
+                assert_eq!(report.files_uploaded(), 1);
+                let archive = tmp_dir
+                    .path()
+                    .join(report.uploads[0].remote_path.strip_prefix("/").unwrap());
+                let dest = tempfile::tempdir().unwrap();
+                remote::decompress_folder(&archive, dest.path(), Compression::Gzip)
+                    .await
+                    .unwrap();
+                dest
+            } else {
+                assert_eq!(report.files_uploaded(), 3);
+                tmp_dir
+            };
+            let root = if compress {
+                dest.path().to_path_buf()
+            } else {
+                dest.path().join("backup")
+            };
+            assert!(root.join("nginx").join("sites").join("app").exists());
+            assert!(root.join("site").join("index.html").exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_folder_roots_files() {
+        let local = tempfile::tempdir().unwrap();
+        for path in [
+            "etc/hosts",
+            "etc/passwd",
+            "etc/ssl/cert.pem",
+            "site/index.html",
+        ] {
+            let path = local.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "bacup").unwrap();
+        }
+        // The root of the file pattern is etc, that contains files not listed
+        let service = Folder::from_patterns(&[
+            String::from(local.path().join("etc").join("hosts").to_str().unwrap()),
+            String::from(local.path().join("site").to_str().unwrap()),
+        ])
+        .await
+        .unwrap();
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let remote = Localhost::new(
+            LocalhostConfig {
+                path: String::from(tmp_dir.path().to_str().unwrap()),
+            },
+            "test_remote",
+        )
+        .unwrap();
+        let config = BackupConfig {
+            what: String::from("folders.test"),
+            r#where: String::from("localhost.test"),
+            when: String::from("daily 00:00"),
+            remote_path: String::from("/backup"),
+            compress: true,
+            ..Default::default()
+        };
+        let backup = Backup::new("test", Box::new(remote), Box::new(service), &config)
+            .await
+            .unwrap();
+        let report = backup.run().await;
+        assert!(report.success());
+
+        let archive = tmp_dir
+            .path()
+            .join(report.uploads[0].remote_path.strip_prefix("/").unwrap());
+        let dest = tempfile::tempdir().unwrap();
+        remote::decompress_folder(&archive, dest.path(), Compression::Gzip)
+            .await
+            .unwrap();
+        assert!(dest.path().join("etc").join("hosts").exists());
+        assert!(!dest.path().join("etc").join("passwd").exists());
+        assert!(!dest.path().join("etc").join("ssl").exists());
+        assert!(dest.path().join("site").join("index.html").exists());
+    }
+
+    #[tokio::test]
+    async fn test_run_folder_roots_only_folder() {
+        let local = tempfile::tempdir().unwrap();
+        for path in ["etc/ssl/cert.pem", "site/index.html"] {
+            let path = local.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "bacup").unwrap();
+        }
+        // The pattern matches only the ssl folder, not its content
+        let service = Folder::from_patterns(&[
+            String::from(local.path().join("etc").join("*").to_str().unwrap()),
+            String::from(local.path().join("site").to_str().unwrap()),
+        ])
+        .await
+        .unwrap();
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let remote = Localhost::new(
+            LocalhostConfig {
+                path: String::from(tmp_dir.path().to_str().unwrap()),
+            },
+            "test_remote",
+        )
+        .unwrap();
+        let config = BackupConfig {
+            what: String::from("folders.test"),
+            r#where: String::from("localhost.test"),
+            when: String::from("daily 00:00"),
+            remote_path: String::from("/backup"),
+            ..Default::default()
+        };
+        let backup = Backup::new("test", Box::new(remote), Box::new(service), &config)
+            .await
+            .unwrap();
+        let report = backup.run().await;
+        assert!(!report.success());
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].contains("ssl"));
+        assert!(tmp_dir
+            .path()
+            .join("backup")
+            .join("site")
+            .join("index.html")
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let remote = Localhost::new(
+            LocalhostConfig {
+                path: String::from(tmp_dir.path().to_str().unwrap()),
+            },
+            "test_remote",
+        )
+        .unwrap();
+        let service = Folder::new("Cargo.toml").await.unwrap();
+        let log = tmp_dir.path().join("hooks.log");
+        let hook = |kind: &str| {
+            Some(format!(
+                "echo \"{} $BACUP_BACKUP $BACUP_STATUS\" >> {}",
+                kind,
+                log.display()
+            ))
+        };
+        let mut config = BackupConfig {
+            what: String::from("folders.test"),
+            r#where: String::from("localhost.test"),
+            when: String::from("daily 00:00"),
+            remote_path: String::from("/backup"),
+            compress: true,
+            pre_command: hook("pre"),
+            post_command: hook("post"),
+            on_failure_command: hook("failure"),
+            ..Default::default()
+        };
+
+        let backup = Backup::new(
+            "test",
+            Box::new(remote.clone()),
+            Box::new(service.clone()),
+            &config,
+        )
+        .await
+        .unwrap();
+        let report = backup.run().await;
+        assert!(report.success());
+        assert_eq!(report.files_uploaded(), 1);
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "pre test \npost test success\n"
+        );
+
+        // A failing pre_command aborts the run
+        std::fs::remove_file(&log).unwrap();
+        config.pre_command = Some(String::from("exit 1"));
+        let backup = Backup::new("test", Box::new(remote), Box::new(service), &config)
+            .await
+            .unwrap();
+        let report = backup.run().await;
+        assert!(!report.success());
+        assert!(report.errors[0].starts_with("Hook failed: pre_command exit 1"));
+        assert!(report.uploads.is_empty());
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "failure test failure\n"
+        );
+    }
+
+    struct RecordingNotifier {
+        notifications: std::sync::Mutex<Vec<Notification>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Notifier for RecordingNotifier {
+        fn name(&self) -> String {
+            String::from("recording")
+        }
+
+        async fn notify(&self, notification: &Notification) -> Result<(), crate::notify::Error> {
+            self.notifications
+                .lock()
+                .unwrap()
+                .push(notification.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_notify_on() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let remote = Localhost::new(
+            LocalhostConfig {
+                path: String::from(tmp_dir.path().to_str().unwrap()),
+            },
+            "test_remote",
+        )
+        .unwrap();
+        let service = Folder::new("Cargo.toml").await.unwrap();
+        let config = BackupConfig {
+            what: String::from("folders.test"),
+            r#where: String::from("localhost.test"),
+            when: String::from("daily 00:00"),
+            remote_path: String::from("/backup"),
+            compress: true,
+            ..Default::default()
+        };
+
+        let mut backup = Backup::new("test", Box::new(remote), Box::new(service), &config)
+            .await
+            .unwrap();
+        assert_eq!(backup.notify_on, vec![Status::Failure]);
+        let notifier = Arc::new(RecordingNotifier {
+            notifications: std::sync::Mutex::new(vec![]),
+        });
+        backup.notifiers = vec![notifier.clone()];
+
+        let mut report = RunReport::new("test");
+        backup.notify(&report).await;
+        assert!(notifier.notifications.lock().unwrap().is_empty());
+
+        report.errors.push(String::from("Upload failed"));
+        backup.notify(&report).await;
+        {
+            let notifications = notifier.notifications.lock().unwrap();
+            assert_eq!(notifications.len(), 1);
+            assert_eq!(notifications[0].backup, "test");
+            assert_eq!(notifications[0].remote, "localhost.test");
+            assert_eq!(notifications[0].status, Status::Failure);
+            assert!(notifications[0].next_run.is_some());
+        }
+
+        backup.notify_on = vec![Status::Success];
+        backup.notify(&RunReport::new("test")).await;
+        backup.notify(&report).await;
+        assert_eq!(notifier.notifications.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_policy() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            delay: Duration::from_millis(1),
+            backoff: 2.0,
+        };
+
+        // Transient errors are retried until success
+        let mut calls = 0;
+        let result = policy
+            .run("test", "Upload", || {
+                calls += 1;
+                let failed = calls < 3;
+                async move {
+                    if failed {
+                        Err(remote::Error::ConnectionFailed(String::from("ssh")))
+                    } else {
+                        Ok(calls)
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 3);
+
+        // ...until the attempts are exhausted
+        let mut calls = 0;
+        let result: Result<(), remote::Error> = policy
+            .run("test", "Upload", || {
+                calls += 1;
+                async {
+                    Err(remote::Error::LocalError(io::Error::from(
+                        io::ErrorKind::TimedOut,
+                    )))
+                }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+
+        // Non transient errors are not retried
+        let mut calls = 0;
+        let result: Result<(), remote::Error> = policy
+            .run("test", "Upload", || {
+                calls += 1;
+                async { Err(remote::Error::NotADirectory) }
+            })
+            .await;
+        assert!(matches!(result, Err(remote::Error::NotADirectory)));
+        assert_eq!(calls, 1);
+
+        // ...as the commands that reached the remote host and failed
+        let mut calls = 0;
+        let result: Result<(), remote::Error> = policy
+            .run("test", "Upload", || {
+                calls += 1;
+                async { Err(remote::Error::CommandFailed(String::from("rm"))) }
+            })
+            .await;
+        assert!(matches!(result, Err(remote::Error::CommandFailed(_))));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_retry_policy_config() {
+        let policy = RetryPolicy::new(&RetryConfig {
+            attempts: 5,
+            delay: Some(10),
+            backoff: None,
+        })
+        .unwrap();
+        assert_eq!(policy.attempts, 5);
+        assert_eq!(policy.delay, Duration::from_secs(10));
+        assert_eq!(policy.backoff, 2.0);
+
+        assert!(RetryPolicy::new(&RetryConfig {
+            attempts: 0,
+            delay: None,
+            backoff: None,
+        })
+        .is_err());
+        assert!(RetryPolicy::new(&RetryConfig {
+            attempts: 3,
+            delay: None,
+            backoff: Some(0.5),
+        })
+        .is_err());
+    }
 }
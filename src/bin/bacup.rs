@@ -14,13 +14,19 @@
 
 use std::collections::HashMap;
 use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use std::string::String;
 
 use bacup::backup::Backup;
+use bacup::catalog::Catalog;
 use bacup::config::Config;
+use bacup::encryption;
+use bacup::encryption::Keys;
+use bacup::metrics;
+use bacup::notify;
+use bacup::restore::Restore;
 
 use bacup::remotes::aws::AwsBucket;
 use bacup::remotes::git::Git;
@@ -31,14 +37,69 @@ use bacup::remotes::remote::Remote;
 
 use bacup::services::docker::Docker;
 use bacup::services::folders::Folder;
+use bacup::services::mongodb::MongoDb;
+use bacup::services::mysql::MySql;
 use bacup::services::postgresql::PostgreSql;
+use bacup::services::redis::Redis;
 use bacup::services::service::Service;
+use bacup::services::sqlite::Sqlite;
 
 use log::*;
 use structopt::StructOpt;
 
 use tokio_cron_scheduler::JobScheduler;
 
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Download a backup from its remote and restore it into a local folder
+    Restore {
+        /// Name of the backup, as defined in the [backup] section of the configuration
+        backup: String,
+        /// Local folder where to restore the backup
+        #[structopt(parse(from_os_str))]
+        destination: PathBuf,
+        /// Archive to restore (name or remote path). Defaults to the most recent one
+        #[structopt(short = "a", long = "archive")]
+        archive: Option<String>,
+    },
+    /// List the archives, or the snapshots of a repository backup, stored on the remote
+    List {
+        /// Name of the backup, as defined in the [backup] section of the configuration
+        backup: String,
+    },
+    /// Load a dump back into a configured service (e.g. replay a SQL dump into PostgreSQL)
+    Load {
+        /// Name of the service, in the form <kind>.<name> (e.g. postgres.service1)
+        service: String,
+        /// Dump file (or folder, for folders services) to load
+        #[structopt(parse(from_os_str))]
+        dump: PathBuf,
+    },
+    /// Run one (or all) of the configured backups immediately and exit
+    Run {
+        /// Name of the backup, as defined in the [backup] section of the configuration
+        #[structopt(required_unless = "all")]
+        backup: Option<String>,
+        /// Run all the configured backups
+        #[structopt(long = "all", conflicts_with = "backup")]
+        all: bool,
+    },
+    /// Show the history of the backup executions, stored in the local catalog
+    History {
+        /// Show only the executions of this backup
+        backup: Option<String>,
+    },
+    /// Decrypt an encrypted (.age) archive with the identity of the [encryption] section
+    Decrypt {
+        /// Encrypted archive
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Destination file. Defaults to the archive path without the .age suffix
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt()]
 struct Opt {
@@ -48,132 +109,381 @@ struct Opt {
     /// Verbose mode (-v, -vv, -vvv, etc)
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     verbose: usize,
+    /// Without a command, bacup runs as a daemon and executes the scheduled backups
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), i32> {
-    let opt = Opt::from_args();
-    stderrlog::new()
-        //.modules(vec![module_path!(), "bacup"])
-        .quiet(opt.quiet)
-        .verbosity(opt.verbose)
-        .timestamp(stderrlog::Timestamp::Second)
-        .init()
-        .unwrap();
+/// Prefixes the names of the configured `kind` entries with their kind: <kind>.<name>.
+fn names<T>(kind: &str, configs: &Option<HashMap<String, T>>) -> Vec<String> {
+    configs
+        .iter()
+        .flat_map(|configs| configs.keys())
+        .map(|name| format!("{}.{}", kind, name))
+        .collect()
+}
 
-    let path = match env::var("CONF_FILE") {
-        Ok(x) => x,
-        Err(_) => "config.toml".to_string(),
+/// Names of the remotes defined in the configuration.
+fn remote_names(config: &Config) -> Vec<String> {
+    [
+        names("aws", &config.aws),
+        names("ssh", &config.ssh),
+        names("localhost", &config.localhost),
+        names("git", &config.git),
+    ]
+    .concat()
+}
+
+/// Names of the services defined in the configuration.
+fn service_names(config: &Config) -> Vec<String> {
+    [
+        names("folders", &config.folders),
+        names("postgres", &config.postgres),
+        names("mysql", &config.mysql),
+        names("sqlite", &config.sqlite),
+        names("mongodb", &config.mongodb),
+        names("redis", &config.redis),
+        names("docker", &config.docker),
+    ]
+    .concat()
+}
+
+/// Creates the remote `key` (<kind>.<name>), moving its configuration out of `config`.
+async fn remote(config: &mut Config, key: &str) -> Result<Box<dyn Remote + Send + Sync>, i32> {
+    let (kind, name) = key.split_once('.').unwrap_or((key, ""));
+    let remote: Result<Box<dyn Remote + Send + Sync>, String> = match kind {
+        "aws" => match config.aws.as_mut().and_then(|r| r.remove(name)) {
+            Some(aws) => AwsBucket::new(aws, name)
+                .await
+                .map(|r| Box::new(r) as _)
+                .map_err(|e| e.to_string()),
+            None => Err("not configured".to_string()),
+        },
+        "ssh" => match config.ssh.as_mut().and_then(|r| r.remove(name)) {
+            Some(ssh) => Ssh::new(ssh, name)
+                .await
+                .map(|r| Box::new(r) as _)
+                .map_err(|e| e.to_string()),
+            None => Err("not configured".to_string()),
+        },
+        "localhost" => match config.localhost.as_mut().and_then(|r| r.remove(name)) {
+            Some(localhost) => Localhost::new(localhost, name)
+                .map(|r| Box::new(r) as _)
+                .map_err(|e| e.to_string()),
+            None => Err("not configured".to_string()),
+        },
+        "git" => match config.git.as_mut().and_then(|r| r.remove(name)) {
+            Some(git) => Git::new(git, name)
+                .await
+                .map(|r| Box::new(r) as _)
+                .map_err(|e| e.to_string()),
+            None => Err("not configured".to_string()),
+        },
+        _ => Err("unknown remote kind".to_string()),
     };
+    match remote {
+        Ok(remote) => {
+            info!("Remote {} configured", key);
+            Ok(remote)
+        }
+        Err(error) => {
+            error!("Remote {}: {}", key, error);
+            Err(-1)
+        }
+    }
+}
 
-    let path = Path::new(&path);
+/// Creates the service `key` (<kind>.<name>), moving its configuration out of `config`.
+async fn service(config: &mut Config, key: &str) -> Result<Box<dyn Service + Send + Sync>, i32> {
+    let (kind, name) = key.split_once('.').unwrap_or((key, ""));
+    let service: Result<Box<dyn Service + Send + Sync>, String> = match kind {
+        "folders" => match config.folders.as_mut().and_then(|s| s.remove(name)) {
+            Some(folder) => {
+                let patterns: Vec<String> = folder
+                    .pattern
+                    .into_iter()
+                    .chain(folder.patterns.unwrap_or_default())
+                    .collect();
+                match Folder::from_patterns(&patterns).await {
+                    Ok(service) => service
+                        .with_exclude(
+                            &folder.exclude.unwrap_or_default(),
+                            folder.bacupignore.unwrap_or(false),
+                        )
+                        .map(|s| Box::new(s) as _)
+                        .map_err(|e| e.to_string()),
+                    Err(error) => Err(error.to_string()),
+                }
+            }
+            None => Err("not configured".to_string()),
+        },
+        "postgres" => match config.postgres.as_mut().and_then(|s| s.remove(name)) {
+            Some(postgres) => PostgreSql::new(postgres, name)
+                .await
+                .map(|s| Box::new(s) as _)
+                .map_err(|e| e.to_string()),
+            None => Err("not configured".to_string()),
+        },
+        "mysql" => match config.mysql.as_mut().and_then(|s| s.remove(name)) {
+            Some(mysql) => MySql::new(mysql, name)
+                .await
+                .map(|s| Box::new(s) as _)
+                .map_err(|e| e.to_string()),
+            None => Err("not configured".to_string()),
+        },
+        "sqlite" => match config.sqlite.as_mut().and_then(|s| s.remove(name)) {
+            Some(sqlite) => Sqlite::new(sqlite, name)
+                .await
+                .map(|s| Box::new(s) as _)
+                .map_err(|e| e.to_string()),
+            None => Err("not configured".to_string()),
+        },
+        "mongodb" => match config.mongodb.as_mut().and_then(|s| s.remove(name)) {
+            Some(mongodb) => MongoDb::new(mongodb, name)
+                .await
+                .map(|s| Box::new(s) as _)
+                .map_err(|e| e.to_string()),
+            None => Err("not configured".to_string()),
+        },
+        "redis" => match config.redis.as_mut().and_then(|s| s.remove(name)) {
+            Some(redis) => Redis::new(redis, name)
+                .await
+                .map(|s| Box::new(s) as _)
+                .map_err(|e| e.to_string()),
+            None => Err("not configured".to_string()),
+        },
+        "docker" => match config.docker.as_mut().and_then(|s| s.remove(name)) {
+            Some(docker) => Docker::new(docker, name)
+                .await
+                .map(|s| Box::new(s) as _)
+                .map_err(|e| e.to_string()),
+            None => Err("not configured".to_string()),
+        },
+        _ => Err("unknown service kind".to_string()),
+    };
+    service.map_err(|error| {
+        error!("Service {}: {}", key, error);
+        -1
+    })
+}
 
-    if !path.exists() {
-        error!("The configuration file {:?} doesn't exist.", path);
-        return Err(-1);
+async fn remotes(
+    config: &mut Config,
+) -> Result<HashMap<String, Box<dyn Remote + Send + Sync>>, i32> {
+    let mut remotes: HashMap<String, Box<dyn Remote + Send + Sync>> = HashMap::new();
+    for key in remote_names(config) {
+        let remote = remote(config, &key).await?;
+        remotes.insert(key, remote);
+    }
+    if remotes.is_empty() {
+        warn!("No remotes configured.");
     }
+    Ok(remotes)
+}
 
-    let config = match Config::new(path).await {
-        Ok(config) => config,
-        Err(error) => {
-            error!("Config error: {}", error);
+async fn services(
+    config: &mut Config,
+) -> Result<HashMap<String, Box<dyn Service + Send + Sync>>, i32> {
+    let mut services: HashMap<String, Box<dyn Service + Send + Sync>> = HashMap::new();
+    for key in service_names(config) {
+        let service = service(config, &key).await?;
+        services.insert(key, service);
+    }
+    if services.is_empty() {
+        warn!("No services to backup.");
+    }
+    Ok(services)
+}
+
+/// Creates the Restore of the backup `backup_name`, with its remote and identity.
+async fn restorer(mut config: Config, backup_name: &str) -> Result<Restore, i32> {
+    let backup_config = match config.backup.get(backup_name) {
+        Some(backup_config) => backup_config.clone(),
+        None => {
+            error!(
+                "Invalid backup: {}, not available in the configured backups: {:?}",
+                backup_name,
+                config.backup.keys()
+            );
             return Err(-1);
         }
     };
 
-    let mut remotes: HashMap<String, Box<dyn Remote + Send + Sync>> = HashMap::new();
+    let remotes = remote_names(&config);
+    if !remotes.contains(&backup_config.r#where) {
+        error!(
+            "Backup {}. Invalid where: {}, not available in the configured remotes: {:?}",
+            backup_name, backup_config.r#where, remotes
+        );
+        return Err(-1);
+    }
+    let remote = remote(&mut config, &backup_config.r#where).await?;
 
-    match config.aws {
-        Some(aws) => {
-            for (bucket_name, bucket_config) in aws {
-                remotes.insert(
-                    format!("aws.{}", bucket_name),
-                    Box::new(AwsBucket::new(bucket_config, &bucket_name).await.unwrap()),
-                );
-                info!("Remote aws.{} configured", bucket_name);
-            }
+    let mut restore = Restore::new(backup_name, remote, &backup_config);
+    restore.identity = keys(&config).await?.and_then(|keys| keys.identity);
+    Ok(restore)
+}
+
+async fn restore(
+    config: Config,
+    backup_name: &str,
+    destination: &Path,
+    archive: Option<&str>,
+) -> Result<(), i32> {
+    let restore = restorer(config, backup_name).await?;
+    match restore.restore(archive, destination).await {
+        Ok(restored) => {
+            info!("[{}] Restored into {}", backup_name, restored.display());
+            Ok(())
+        }
+        Err(error) => {
+            error!("[{}] Restore failed: {}", backup_name, error);
+            Err(-1)
         }
-        None => warn!("No AWS cloud configured."),
     }
+}
 
-    match config.ssh {
-        Some(host) => {
-            for (hostname, config) in host {
-                remotes.insert(
-                    format!("ssh.{}", hostname),
-                    Box::new(Ssh::new(config, &hostname).await.unwrap()),
-                );
-                info!("Remote ssh.{} configured", hostname);
+async fn list(config: Config, backup_name: &str) -> Result<(), i32> {
+    let restore = restorer(config, backup_name).await?;
+    match restore.archives().await {
+        Ok(archives) => {
+            for archive in archives {
+                println!("{}", archive);
             }
+            Ok(())
+        }
+        Err(error) => {
+            error!("[{}] Unable to list the archives: {}", backup_name, error);
+            Err(-1)
         }
-        None => warn!("No Ssh remotes configured."),
     }
+}
 
-    match config.localhost {
-        Some(host) => {
-            for (name, config) in host {
-                remotes.insert(
-                    format!("localhost.{}", name),
-                    Box::new(Localhost::new(config, &name).unwrap()),
-                );
-                info!("Remote localhost.{} configured", name);
-            }
-        }
-        None => warn!("No localhost remotes configured."),
+async fn load(mut config: Config, service_name: &str, dump: &Path) -> Result<(), i32> {
+    if !dump.exists() {
+        error!("The dump {} doesn't exist.", dump.display());
+        return Err(-1);
     }
 
-    match config.git {
-        Some(host) => {
-            for (name, config) in host {
-                remotes.insert(
-                    format!("git.{}", name),
-                    Box::new(Git::new(config, &name).await.unwrap()),
-                );
-                info!("Remote git.{} configured", name);
-            }
-        }
-        None => warn!("No Git remotes configured."),
+    let services = service_names(&config);
+    if !services.iter().any(|name| name == service_name) {
+        error!(
+            "Invalid service: {}, not available in the configured services: {:?}",
+            service_name, services
+        );
+        return Err(-1);
     }
+    let service = service(&mut config, service_name).await?;
 
-    let mut services: HashMap<String, Box<dyn Service + Send + Sync>> = HashMap::new();
-    match config.folders {
-        Some(folders) => {
-            for (location_name, folder) in folders {
-                let key = format!("folders.{}", location_name);
-                services.insert(key, Box::new(Folder::new(&folder.pattern).await.unwrap()));
-            }
+    info!("[{}] Loading {}...", service_name, dump.display());
+    match service.restore(dump).await {
+        Ok(()) => {
+            info!("[{}] Loaded {}", service_name, dump.display());
+            Ok(())
         }
-        None => warn!("No folders to backup."),
-    }
-    match config.postgres {
-        Some(postgres) => {
-            for (service_name, instance_config) in postgres {
-                let key = format!("postgres.{}", service_name);
-                services.insert(
-                    key,
-                    Box::new(
-                        PostgreSql::new(instance_config, &service_name)
-                            .await
-                            .unwrap(),
-                    ),
-                );
-            }
+        Err(error) => {
+            error!("[{}] Load failed: {}", service_name, error);
+            Err(-1)
         }
-        None => warn!("No PostgreSql to backup."),
     }
-    match config.docker {
-        Some(docker) => {
-            for (service_name, instance_config) in docker {
-                let key = format!("docker.{}", service_name);
-                services.insert(
-                    key,
-                    Box::new(Docker::new(instance_config, &service_name).await.unwrap()),
-                );
+}
+
+async fn keys(config: &Config) -> Result<Option<Keys>, i32> {
+    match &config.encryption {
+        Some(encryption) => match Keys::new(encryption).await {
+            Ok(keys) => Ok(Some(keys)),
+            Err(error) => {
+                error!("Encryption: {}", error);
+                Err(-1)
             }
+        },
+        None => Ok(None),
+    }
+}
+
+/// The catalog is stored in the state folder, inside the working directory.
+fn catalog() -> Catalog {
+    Catalog::new(
+        &env::current_dir()
+            .unwrap()
+            .join("state")
+            .join("catalog.toml"),
+    )
+}
+
+/// Creates the remote and the service of the backup `backup_name` only, and drops the
+/// other backups from `config`: the unrelated remotes and services are never contacted.
+async fn backup_only(
+    config: &mut Config,
+    backup_name: &str,
+) -> Result<
+    (
+        HashMap<String, Box<dyn Remote + Send + Sync>>,
+        HashMap<String, Box<dyn Service + Send + Sync>>,
+    ),
+    i32,
+> {
+    let backup_config = match config.backup.get(backup_name) {
+        Some(backup_config) => backup_config.clone(),
+        None => {
+            error!(
+                "Invalid backup: {}, not available in the configured backups: {:?}",
+                backup_name,
+                config.backup.keys()
+            );
+            return Err(-1);
         }
-        None => warn!("No Docker to backup."),
+    };
+    config.backup.retain(|name, _| name == backup_name);
+
+    let services = service_names(config);
+    if !services.contains(&backup_config.what) {
+        error!(
+            "Backup {}. Invalid what: {}, not available in the configured services: {:?}",
+            backup_name, backup_config.what, services
+        );
+        return Err(-1);
+    }
+    let remotes = remote_names(config);
+    if !remotes.contains(&backup_config.r#where) {
+        error!(
+            "Backup {}. Invalid where: {}, not available in the configured remotes: {:?}",
+            backup_name, backup_config.r#where, remotes
+        );
+        return Err(-1);
     }
 
+    let service = service(config, &backup_config.what).await?;
+    let remote = remote(config, &backup_config.r#where).await?;
+    Ok((
+        HashMap::from([(backup_config.r#where, remote)]),
+        HashMap::from([(backup_config.what, service)]),
+    ))
+}
+
+/// Creates the configured backups. With `backup_name`, only that backup is created,
+/// together with its remote and service.
+async fn backups(
+    mut config: Config,
+    backup_name: Option<&str>,
+) -> Result<HashMap<String, Arc<Backup>>, i32> {
+    let keys = keys(&config).await?;
+    let notifiers = match &config.notify {
+        Some(notify) => match notify::notifiers(notify) {
+            Ok(notifiers) => notifiers,
+            Err(error) => {
+                error!("Notify: {}", error);
+                return Err(-1);
+            }
+        },
+        None => vec![],
+    };
+    let (remotes, services) = match backup_name {
+        Some(backup_name) => backup_only(&mut config, backup_name).await?,
+        None => (remotes(&mut config).await?, services(&mut config).await?),
+    };
+    let catalog = Arc::new(catalog());
+
     let mut backup: HashMap<String, Arc<Backup>> = HashMap::new();
     for (backup_name, config) in config.backup {
         if !services.contains_key(&config.what) {
@@ -196,22 +506,174 @@ async fn main() -> Result<(), i32> {
             return Err(-1);
         }
 
-        backup.insert(
-            backup_name.clone(),
-            Arc::new(
-                Backup::new(
-                    &backup_name,
-                    dyn_clone::clone_box(&*remotes[&config.r#where]),
-                    dyn_clone::clone_box(&*services[&config.what]),
-                    &config,
-                )
-                .await
-                .unwrap(),
-            ),
-        );
+        let mut job = match Backup::new(
+            &backup_name,
+            dyn_clone::clone_box(&*remotes[&config.r#where]),
+            dyn_clone::clone_box(&*services[&config.what]),
+            &config,
+        )
+        .await
+        {
+            Ok(job) => job,
+            Err(error) => {
+                error!("Backup {}. {}", backup_name, error);
+                return Err(-1);
+            }
+        };
+        job.catalog = Some(catalog.clone());
+        job.notifiers = notifiers.clone();
+
+        if config.encrypt.unwrap_or(false) {
+            match &keys {
+                Some(keys) => job.archive.recipient = Some(keys.recipient.clone()),
+                None => {
+                    error!(
+                        "Backup {}. encrypt = true requires the [encryption] section",
+                        backup_name
+                    );
+                    return Err(-1);
+                }
+            }
+        }
+
+        backup.insert(backup_name.clone(), Arc::new(job));
         info!("Backup {} -> {} configured", config.what, config.r#where);
     }
 
+    Ok(backup)
+}
+
+async fn run(config: Config, backup_name: Option<String>) -> Result<(), i32> {
+    let backup = backups(config, backup_name.as_deref()).await?;
+
+    let mut failed = false;
+    for job in backup.values() {
+        let report = job.run().await;
+        if report.success() {
+            info!("[{}] Backup {}", job.name, report);
+        } else {
+            error!("[{}] Backup {}", job.name, report);
+            failed = true;
+        }
+        job.notify(&report).await;
+    }
+
+    if failed {
+        return Err(-1);
+    }
+    Ok(())
+}
+
+async fn history(backup_name: Option<&str>) -> Result<(), i32> {
+    let catalog = catalog();
+    let entries = match catalog.entries(backup_name).await {
+        Ok(entries) => entries,
+        Err(error) => {
+            error!("Catalog {}: {}", catalog.path().display(), error);
+            return Err(-1);
+        }
+    };
+
+    for entry in entries {
+        println!(
+            "{}  {}  {}  {}  {} objects  {} bytes",
+            entry.end.format("%Y-%m-%d %H:%M:%S"),
+            entry.backup,
+            entry.remote,
+            entry.status,
+            entry.objects.len(),
+            entry.size(),
+        );
+        for object in &entry.objects {
+            println!(
+                "    {}  {} bytes  {}",
+                object.path,
+                object.size,
+                object.checksum.as_deref().unwrap_or("-"),
+            );
+        }
+        for deleted in &entry.deleted {
+            println!("    deleted: {}", deleted);
+        }
+        for error in &entry.errors {
+            println!("    error: {}", error);
+        }
+    }
+    Ok(())
+}
+
+async fn decrypt(config: Config, file: &Path, output: Option<&Path>) -> Result<(), i32> {
+    let identity = match keys(&config).await?.and_then(|keys| keys.identity) {
+        Some(identity) => identity,
+        None => {
+            error!("{}", encryption::Error::MissingIdentity);
+            return Err(-1);
+        }
+    };
+
+    let output = match output {
+        Some(output) => output.to_path_buf(),
+        None => match file.to_str().unwrap().strip_suffix(encryption::SUFFIX) {
+            Some(output) => PathBuf::from(output),
+            None => {
+                error!(
+                    "{} has no {} suffix, specify the destination with --output",
+                    file.display(),
+                    encryption::SUFFIX
+                );
+                return Err(-1);
+            }
+        },
+    };
+
+    match encryption::decrypt_file(file, &output, &identity).await {
+        Ok(()) => {
+            info!("Decrypted {} into {}", file.display(), output.display());
+            Ok(())
+        }
+        Err(error) => {
+            error!("Decryption of {} failed: {}", file.display(), error);
+            Err(-1)
+        }
+    }
+}
+
+async fn daemon(mut config: Config) -> Result<(), i32> {
+    let metrics = config.metrics.take();
+    let backup = backups(config, None).await?;
+
+    let jobs: Vec<Arc<Backup>> = backup.values().cloned().collect();
+    for job in &jobs {
+        if let Err(error) = job.load_last_success().await {
+            warn!("[{}] Unable to load the last success: {}", job.name, error);
+        }
+    }
+    if let Some(metrics) = metrics {
+        if let Some(listen) = metrics.listen {
+            let listener = match tokio::net::TcpListener::bind(&listen).await {
+                Ok(listener) => listener,
+                Err(error) => {
+                    error!("Metrics: unable to listen on {}: {}", listen, error);
+                    return Err(-1);
+                }
+            };
+            info!("Metrics served on http://{}/metrics", listen);
+            let jobs = jobs.clone();
+            tokio::spawn(async move {
+                if let Err(error) = metrics::serve(listener, jobs).await {
+                    error!("Metrics: listener stopped: {}", error);
+                }
+            });
+        }
+        if let Some(textfile) = metrics.textfile {
+            info!("Metrics written into {}", textfile);
+            let jobs = jobs.clone();
+            tokio::spawn(
+                async move { metrics::textfile_exporter(Path::new(&textfile), jobs).await },
+            );
+        }
+    }
+
     let mut scheduler = JobScheduler::new().await.unwrap();
     // scheduler.shutdown_on_ctrl_c();
 
@@ -246,3 +708,49 @@ async fn main() -> Result<(), i32> {
         tokio::time::sleep(Duration::from_millis(50)).await;
     }
 }
+
+#[tokio::main]
+async fn main() -> Result<(), i32> {
+    let opt = Opt::from_args();
+    stderrlog::new()
+        //.modules(vec![module_path!(), "bacup"])
+        .quiet(opt.quiet)
+        .verbosity(opt.verbose)
+        .timestamp(stderrlog::Timestamp::Second)
+        .init()
+        .unwrap();
+
+    let path = match env::var("CONF_FILE") {
+        Ok(x) => x,
+        Err(_) => "config.toml".to_string(),
+    };
+
+    let path = Path::new(&path);
+
+    if !path.exists() {
+        error!("The configuration file {:?} doesn't exist.", path);
+        return Err(-1);
+    }
+
+    let config = match Config::new(path).await {
+        Ok(config) => config,
+        Err(error) => {
+            error!("Config error: {}", error);
+            return Err(-1);
+        }
+    };
+
+    match opt.cmd {
+        Some(Command::Restore {
+            backup,
+            destination,
+            archive,
+        }) => restore(config, &backup, &destination, archive.as_deref()).await,
+        Some(Command::List { backup }) => list(config, &backup).await,
+        Some(Command::Load { service, dump }) => load(config, &service, &dump).await,
+        Some(Command::Run { backup, all }) => run(config, if all { None } else { backup }).await,
+        Some(Command::History { backup }) => history(backup.as_deref()).await,
+        Some(Command::Decrypt { file, output }) => decrypt(config, &file, output.as_deref()).await,
+        None => daemon(config).await,
+    }
+}
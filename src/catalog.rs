@@ -0,0 +1,376 @@
+// Copyright 2022 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::backup::RunReport;
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Minimum number of executions of every backup kept in the catalog: the oldest entries
+/// are pruned, so that the catalog doesn't grow without bound.
+pub const HISTORY_RUNS: usize = 100;
+
+#[derive(Debug)]
+pub enum Error {
+    RuntimeError(io::Error),
+    Parse(toml::de::Error),
+    Serialize(toml::ser::Error),
+}
+
+impl std::error::Error for Error {}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::RuntimeError(error) => write!(f, "Could not open/write catalog: {}", error),
+            Error::Parse(error) => write!(f, "Failed to parse catalog: {}", error),
+            Error::Serialize(error) => write!(f, "Failed to serialize catalog entry: {}", error),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::RuntimeError(error)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(error: toml::de::Error) -> Self {
+        Error::Parse(error)
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(error: toml::ser::Error) -> Self {
+        Error::Serialize(error)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Success,
+    Failure,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Success => write!(f, "success"),
+            Status::Failure => write!(f, "failure"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Object {
+    pub path: String,
+    pub size: u64,
+    pub checksum: Option<String>,
+}
+
+/// The outcome of a backup execution, as stored in the catalog.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+    pub backup: String,
+    pub remote: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub status: Status,
+    #[serde(default)]
+    pub errors: Vec<String>,
+    #[serde(default)]
+    pub deleted: Vec<String>,
+    #[serde(default)]
+    pub objects: Vec<Object>,
+}
+
+impl Entry {
+    pub fn new(remote: &str, report: &RunReport) -> Entry {
+        Entry {
+            backup: report.name.clone(),
+            remote: String::from(remote),
+            start: report.start,
+            end: report.end,
+            status: report.status(),
+            errors: report.errors.clone(),
+            deleted: report.deleted.clone(),
+            objects: report
+                .uploads
+                .iter()
+                .map(|upload| Object {
+                    path: upload.remote_path.to_str().unwrap().to_string(),
+                    size: upload.size,
+                    checksum: upload.checksum.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.objects.iter().map(|object| object.size).sum()
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Runs {
+    #[serde(default)]
+    run: Vec<Entry>,
+}
+
+impl Runs {
+    /// Removes the oldest executions of `backup` on `remote`, keeping the last `keep`.
+    fn prune(&mut self, backup: &str, remote: &str, keep: usize) {
+        let count = self
+            .run
+            .iter()
+            .filter(|entry| entry.backup == backup && entry.remote == remote)
+            .count();
+        let mut to_remove = count.saturating_sub(keep);
+        self.run.retain(|entry| {
+            if to_remove > 0 && entry.backup == backup && entry.remote == remote {
+                to_remove -= 1;
+                return false;
+            }
+            true
+        });
+    }
+}
+
+/// Local history of the backup executions.
+///
+/// The catalog is a TOML file containing an array of [[run]] tables. Every execution
+/// adds a new table: the updated catalog is written into a temporary file that replaces
+/// the previous one, thus an interrupted write never leaves a truncated catalog.
+///
+/// Only the last executions of every backup are kept: at least HISTORY_RUNS, and at least
+/// keep_last, so that the archives still subject to the retention remain tracked. The
+/// archives of the pruned executions that are still on the remote are older than the
+/// tracked ones, thus the retention considers them anyway.
+pub struct Catalog {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl Catalog {
+    pub fn new(path: &Path) -> Catalog {
+        Catalog {
+            path: path.to_path_buf(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Adds `entry` to the catalog and prunes the oldest executions of the same backup,
+    /// keeping the last HISTORY_RUNS (or `keep_last`, when bigger).
+    pub async fn append(&self, entry: &Entry, keep_last: Option<u32>) -> Result<(), Error> {
+        let _guard = self.lock.lock().await;
+        if let Some(parent) = self.path.parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                fs::create_dir_all(parent).await?;
+            }
+        }
+
+        let mut runs = self.read().await?;
+        runs.run.push(entry.clone());
+        runs.prune(
+            &entry.backup,
+            &entry.remote,
+            HISTORY_RUNS.max(keep_last.unwrap_or_default() as usize),
+        );
+        let content = toml::to_string(&runs)?;
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file = fs::File::create(&tmp).await?;
+        file.write_all(content.as_bytes()).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+
+    async fn read(&self) -> Result<Runs, Error> {
+        if !self.path.exists() {
+            return Ok(Runs::default());
+        }
+        let txt = fs::read_to_string(&self.path).await?;
+        Ok(toml::from_str(&txt)?)
+    }
+
+    /// Returns the entries of the catalog, from the oldest to the most recent.
+    /// When `backup` is specified, only the entries of this backup are returned.
+    pub async fn entries(&self, backup: Option<&str>) -> Result<Vec<Entry>, Error> {
+        let _guard = self.lock.lock().await;
+        Ok(self
+            .read()
+            .await?
+            .run
+            .into_iter()
+            .filter(|entry| backup.is_none_or(|backup| entry.backup == backup))
+            .collect())
+    }
+
+    /// Returns the objects written by `backup` on `remote` that have not been deleted yet.
+    /// The entries are replayed in order, thus an object deleted and then written again
+    /// by a later execution is returned.
+    ///
+    /// The objects are written with their remote path, and deleted with the path listed
+    /// by the remote, with or without the leading /: the returned paths have no leading /.
+    pub async fn objects(&self, backup: &str, remote: &str) -> Result<Vec<String>, Error> {
+        let normalize = |object: &str| object.trim_start_matches('/').to_string();
+        let mut objects: Vec<String> = vec![];
+        for entry in self.entries(Some(backup)).await? {
+            if entry.remote != remote {
+                continue;
+            }
+            for object in &entry.objects {
+                let object = normalize(&object.path);
+                if !objects.contains(&object) {
+                    objects.push(object);
+                }
+            }
+            let deleted: Vec<String> = entry
+                .deleted
+                .iter()
+                .map(|object| normalize(object))
+                .collect();
+            objects.retain(|object| !deleted.contains(object));
+        }
+        Ok(objects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remotes::remote::Upload;
+
+    fn report(name: &str, uploads: &[&str], deleted: &[&str]) -> RunReport {
+        let now = Utc::now();
+        RunReport {
+            name: String::from(name),
+            start: now,
+            end: now,
+            dump_duration: chrono::TimeDelta::zero(),
+            uploads: uploads
+                .iter()
+                .map(|path| Upload {
+                    remote_path: PathBuf::from(path),
+                    size: 10,
+                    checksum: Some(String::from("abc")),
+                })
+                .collect(),
+            deleted: deleted.iter().map(|path| path.to_string()).collect(),
+            errors: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_append_and_entries() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let catalog = Catalog::new(&tmp_dir.path().join("state").join("catalog.toml"));
+        assert!(catalog.entries(None).await.unwrap().is_empty());
+
+        let first = Entry::new("localhost.disk", &report("db", &["/a/1.gz"], &[]));
+        let second = Entry::new("localhost.disk", &report("files", &[], &[]));
+        catalog.append(&first, None).await.unwrap();
+        catalog.append(&second, None).await.unwrap();
+
+        let entries = catalog.entries(None).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].backup, "db");
+        assert_eq!(entries[0].status, Status::Success);
+        assert_eq!(entries[0].size(), 10);
+        assert_eq!(entries[0].objects[0].checksum, Some(String::from("abc")));
+
+        let entries = catalog.entries(Some("files")).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].objects.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_objects() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let catalog = Catalog::new(&tmp_dir.path().join("catalog.toml"));
+
+        // The deletions are recorded with the paths listed by the remote, without the /
+        let runs = [
+            report("db", &["/a/1.gz"], &[]),
+            report("db", &["/a/2.gz"], &[]),
+            report("db", &["/a/3.gz"], &["a/1.gz"]),
+            report("db", &["/a/5.gz"], &["a/2.gz"]),
+            // Written again after its deletion
+            report("db", &["/a/1.gz"], &[]),
+        ];
+        for run in runs.iter() {
+            catalog
+                .append(&Entry::new("localhost.disk", run), None)
+                .await
+                .unwrap();
+        }
+        catalog
+            .append(
+                &Entry::new("localhost.other", &report("db", &["/a/4.gz"], &[])),
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            catalog.objects("db", "localhost.disk").await.unwrap(),
+            vec!["a/3.gz", "a/5.gz", "a/1.gz"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prune() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let catalog = Catalog::new(&tmp_dir.path().join("catalog.toml"));
+
+        let other = Entry::new("localhost.disk", &report("files", &[], &[]));
+        catalog.append(&other, None).await.unwrap();
+        for i in 0..HISTORY_RUNS + 2 {
+            let path = format!("/a/{}.gz", i);
+            let entry = Entry::new("localhost.disk", &report("db", &[&path], &[]));
+            catalog.append(&entry, Some(1)).await.unwrap();
+        }
+
+        let entries = catalog.entries(Some("db")).await.unwrap();
+        assert_eq!(entries.len(), HISTORY_RUNS);
+        assert_eq!(entries[0].objects[0].path, "/a/2.gz");
+        assert_eq!(catalog.entries(Some("files")).await.unwrap().len(), 1);
+
+        // keep_last bigger than HISTORY_RUNS keeps the entries of the retained archives
+        let entry = Entry::new("localhost.disk", &report("db", &["/b.gz"], &[]));
+        catalog
+            .append(&entry, Some(HISTORY_RUNS as u32 + 5))
+            .await
+            .unwrap();
+        assert_eq!(
+            catalog.entries(Some("db")).await.unwrap().len(),
+            HISTORY_RUNS + 1
+        );
+    }
+}
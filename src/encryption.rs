@@ -0,0 +1,290 @@
+// Copyright 2022 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::EncryptionConfig;
+
+use std::fmt;
+use std::io;
+use std::iter;
+use std::path::Path;
+use std::str::FromStr;
+
+use age::stream::StreamWriter;
+use age::x25519::{Identity, Recipient};
+use tempfile::NamedTempFile;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio_util::compat::{
+    Compat, FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt, TokioAsyncReadCompatExt,
+    TokioAsyncWriteCompatExt,
+};
+
+use log::info;
+
+/// Suffix appended to the name of the encrypted archives.
+pub const SUFFIX: &str = ".age";
+
+#[derive(Debug)]
+pub enum Error {
+    RuntimeError(io::Error),
+    InvalidKey(String),
+    MissingIdentity,
+    EncryptError(age::EncryptError),
+    DecryptError(age::DecryptError),
+}
+
+impl std::error::Error for Error {}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::RuntimeError(error) => write!(f, "Runtime error: {}", error),
+            Error::InvalidKey(error) => write!(f, "Invalid age key: {}", error),
+            Error::MissingIdentity => write!(
+                f,
+                "An age identity is required: set identity in the [encryption] section"
+            ),
+            Error::EncryptError(error) => write!(f, "Encryption error: {}", error),
+            Error::DecryptError(error) => write!(f, "Decryption error: {}", error),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::RuntimeError(error)
+    }
+}
+
+impl From<age::EncryptError> for Error {
+    fn from(error: age::EncryptError) -> Self {
+        Error::EncryptError(error)
+    }
+}
+
+impl From<age::DecryptError> for Error {
+    fn from(error: age::DecryptError) -> Self {
+        Error::DecryptError(error)
+    }
+}
+
+/// The age keys loaded from the [encryption] section of the configuration.
+///
+/// Encrypting only requires the recipient (public key), thus a host that only
+/// creates backups can be configured without the identity (private key).
+#[derive(Clone)]
+pub struct Keys {
+    pub recipient: Recipient,
+    pub identity: Option<Identity>,
+}
+
+impl Keys {
+    pub async fn new(config: &EncryptionConfig) -> Result<Keys, Error> {
+        let identity = match &config.identity {
+            Some(path) => Some(load_identity(Path::new(path)).await?),
+            None => None,
+        };
+        let recipient = match (&config.recipient, &identity) {
+            (Some(recipient), _) => Recipient::from_str(recipient)
+                .map_err(|error| Error::InvalidKey(error.to_string()))?,
+            (None, Some(identity)) => identity.to_public(),
+            (None, None) => return Err(Error::MissingIdentity),
+        };
+        Ok(Keys {
+            recipient,
+            identity,
+        })
+    }
+}
+
+/// Reads the first identity (AGE-SECRET-KEY-1...) of the age identity file `path`,
+/// as generated by age-keygen.
+pub async fn load_identity(path: &Path) -> Result<Identity, Error> {
+    let content = fs::read_to_string(path).await?;
+    match content
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        Some(line) => {
+            Identity::from_str(line).map_err(|error| Error::InvalidKey(error.to_string()))
+        }
+        None => Err(Error::InvalidKey(format!(
+            "no identity found in {}",
+            path.display()
+        ))),
+    }
+}
+
+/// Wraps `output` into a writer that encrypts everything written for `recipient`.
+/// The age file is completed when the writer is shut down.
+pub async fn writer<W>(
+    output: W,
+    recipient: &Recipient,
+) -> Result<Compat<StreamWriter<Compat<W>>>, Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let encryptor = age::Encryptor::with_recipients(iter::once(recipient as &dyn age::Recipient))?;
+    let writer = encryptor.wrap_async_output(output.compat_write()).await?;
+    Ok(writer.compat_write())
+}
+
+/// Decrypts the age file read from `input` with `identity`, writing the plain content into `output`.
+pub async fn decrypt<R, W>(input: R, output: &mut W, identity: &Identity) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let decryptor = age::Decryptor::new_async_buffered(BufReader::new(input).compat()).await?;
+    let reader = decryptor.decrypt_async(iter::once(identity as &dyn age::Identity))?;
+    tokio::io::copy(&mut reader.compat(), output).await?;
+    output.flush().await?;
+    Ok(())
+}
+
+/// Decrypts the file `path` into `dest`.
+///
+/// The plain content is written into a temporary file in the folder of `dest`, that
+/// replaces `dest` only when the decryption succeeds: a wrong identity or a truncated
+/// file never leave a partial `dest`.
+pub async fn decrypt_file(path: &Path, dest: &Path, identity: &Identity) -> Result<(), Error> {
+    info!("Decrypting file {}...", path.display());
+    let input = fs::File::open(path).await?;
+    let folder = match dest.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let decrypted = NamedTempFile::new_in(folder)?;
+    let mut output = fs::File::from_std(decrypted.reopen()?);
+    decrypt(input, &mut output, identity).await?;
+    decrypted.persist(dest).map_err(|error| error.error)?;
+    info!("Decryption of file {} done.", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+
+    /// Encrypts the content of `input` for `recipient`, writing the age file into `output`.
+    async fn encrypt<R, W>(input: &mut R, output: W, recipient: &Recipient) -> Result<(), Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut writer = writer(output, recipient).await?;
+        tokio::io::copy(input, &mut writer).await?;
+        writer.shutdown().await?;
+        Ok(())
+    }
+
+    /// Encrypts the file `path` into `dest`.
+    async fn encrypt_file(path: &Path, dest: &Path, recipient: &Recipient) -> Result<(), Error> {
+        info!("Encrypting file {}...", path.display());
+        let mut input = fs::File::open(path).await?;
+        let output = fs::File::create(dest).await?;
+        encrypt(&mut input, output, recipient).await?;
+        info!("Encryption of file {} done.", path.display());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_decrypt_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let identity = Identity::generate();
+        let key_path = tmp_dir.path().join("key.txt");
+        fs::write(
+            &key_path,
+            format!(
+                "# public key: {}\n{}\n",
+                identity.to_public(),
+                identity.to_string().expose_secret()
+            ),
+        )
+        .await
+        .unwrap();
+
+        let keys = Keys::new(&EncryptionConfig {
+            identity: Some(String::from(key_path.to_str().unwrap())),
+            recipient: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(keys.recipient.to_string(), identity.to_public().to_string());
+
+        let encrypted = tmp_dir.path().join("Cargo.toml.age");
+        let decrypted = tmp_dir.path().join("Cargo.toml");
+        encrypt_file(Path::new("Cargo.toml"), &encrypted, &keys.recipient)
+            .await
+            .unwrap();
+        assert_ne!(
+            std::fs::read("Cargo.toml").unwrap(),
+            std::fs::read(&encrypted).unwrap()
+        );
+
+        decrypt_file(&encrypted, &decrypted, keys.identity.as_ref().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read("Cargo.toml").unwrap(),
+            std::fs::read(&decrypted).unwrap()
+        );
+
+        let other = Identity::generate();
+        let missing = tmp_dir.path().join("missing");
+        assert!(matches!(
+            decrypt_file(&encrypted, &missing, &other).await,
+            Err(Error::DecryptError(_))
+        ));
+        // Neither the destination nor the temporary file are left around
+        assert!(!missing.exists());
+        assert_eq!(std::fs::read_dir(tmp_dir.path()).unwrap().count(), 3);
+
+        // A truncated file doesn't replace the existing destination
+        let content = std::fs::read(&encrypted).unwrap();
+        std::fs::write(&encrypted, &content[..content.len() - 10]).unwrap();
+        assert!(
+            decrypt_file(&encrypted, &decrypted, keys.identity.as_ref().unwrap())
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            std::fs::read("Cargo.toml").unwrap(),
+            std::fs::read(&decrypted).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keys_without_identity() {
+        assert!(matches!(
+            Keys::new(&EncryptionConfig {
+                identity: None,
+                recipient: None,
+            })
+            .await,
+            Err(Error::MissingIdentity)
+        ));
+
+        let recipient = Identity::generate().to_public().to_string();
+        let keys = Keys::new(&EncryptionConfig {
+            identity: None,
+            recipient: Some(recipient.clone()),
+        })
+        .await
+        .unwrap();
+        assert_eq!(keys.recipient.to_string(), recipient);
+        assert!(keys.identity.is_none());
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright 2022 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use tokio::{fs, io};
+
+/// File in the root of a folder containing the excluded paths, in the gitignore syntax.
+pub const IGNORE_FILE: &str = ".bacupignore";
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidPattern(ignore::Error),
+}
+
+impl From<ignore::Error> for Error {
+    fn from(error: ignore::Error) -> Self {
+        Error::InvalidPattern(error)
+    }
+}
+
+impl std::error::Error for Error {}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidPattern(error) => write!(f, "Invalid exclude pattern: {}", error),
+        }
+    }
+}
+
+/// The paths of one or more folders excluded from the backups.
+#[derive(Clone)]
+pub struct Filter {
+    /// A matcher for every folder, with the patterns relative to it
+    matchers: Vec<Gitignore>,
+}
+
+/// Content of a folder, split by a Filter.
+#[derive(Debug, Default)]
+pub struct Walk {
+    /// Files and folders to back up, parents first
+    pub included: Vec<PathBuf>,
+    /// Excluded files and folders. The content of an excluded folder is not listed.
+    pub excluded: Vec<PathBuf>,
+}
+
+impl Filter {
+    /// Creates the filter of the `root` folder, from the `exclude` patterns and, when
+    /// `ignore_file` is true, from the IGNORE_FILE in root (if present).
+    /// The patterns are in the gitignore syntax, relative to root.
+    pub fn new(root: &Path, exclude: &[String], ignore_file: bool) -> Result<Filter, Error> {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in exclude {
+            builder.add_line(None, pattern)?;
+        }
+        let path = root.join(IGNORE_FILE);
+        if ignore_file && path.is_file() {
+            if let Some(error) = builder.add(path) {
+                return Err(error.into());
+            }
+        }
+        Ok(Filter {
+            matchers: vec![builder.build()?],
+        })
+    }
+
+    /// Adds the patterns of the folders of `other` to the filter.
+    pub fn extend(&mut self, other: Filter) {
+        self.matchers.extend(other.matchers);
+    }
+
+    /// Returns true if `path`, or one of its parents, is excluded.
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        self.matchers.iter().any(|matcher| {
+            path.starts_with(matcher.path())
+                && matcher
+                    .matched_path_or_any_parents(path, is_dir)
+                    .is_ignore()
+        })
+    }
+
+    /// Lists the content of the folder `path`, without descending into the excluded
+    /// folders. Sockets, pipes and devices are always excluded.
+    pub async fn walk(&self, path: &Path) -> Result<Walk, io::Error> {
+        let mut walk = Walk::default();
+        let mut folders = vec![path.to_path_buf()];
+        while let Some(folder) = folders.pop() {
+            let mut entries = vec![];
+            let mut read_dir = fs::read_dir(&folder).await?;
+            while let Some(entry) = read_dir.next_entry().await? {
+                entries.push((entry.path(), entry.file_type().await?));
+            }
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+            for (entry, file_type) in entries {
+                let is_dir = file_type.is_dir();
+                if !(is_dir || file_type.is_file() || file_type.is_symlink())
+                    || self.is_excluded(&entry, is_dir)
+                {
+                    walk.excluded.push(entry);
+                    continue;
+                }
+                if is_dir {
+                    folders.push(entry.clone());
+                }
+                walk.included.push(entry);
+            }
+        }
+        Ok(walk)
+    }
+}
+
+/// Returns true if `path` is a regular file, a folder or a symlink.
+pub fn is_regular(path: &Path) -> bool {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) => {
+            let file_type = metadata.file_type();
+            file_type.is_file() || file_type.is_dir() || file_type.is_symlink()
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(root: &Path, paths: &[&str]) {
+        for path in paths {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "bacup").unwrap();
+        }
+    }
+
+    #[test]
+    fn test_is_excluded() {
+        let root = Path::new("/srv/app");
+        let filter = Filter::new(
+            root,
+            &[String::from("node_modules/"), String::from("*.log")],
+            false,
+        )
+        .unwrap();
+        assert!(filter.is_excluded(&root.join("node_modules"), true));
+        assert!(filter.is_excluded(&root.join("web/node_modules/a/index.js"), false));
+        assert!(filter.is_excluded(&root.join("logs/today.log"), false));
+        assert!(!filter.is_excluded(&root.join("src/main.rs"), false));
+        // Paths outside the root are never excluded
+        assert!(!filter.is_excluded(Path::new("/srv/other/a.log"), false));
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut filter =
+            Filter::new(Path::new("/srv/app"), &[String::from("/cache")], false).unwrap();
+        filter
+            .extend(Filter::new(Path::new("/etc/nginx"), &[String::from("*.bak")], false).unwrap());
+        assert!(filter.is_excluded(Path::new("/srv/app/cache/a"), false));
+        assert!(filter.is_excluded(Path::new("/etc/nginx/nginx.conf.bak"), false));
+        // The patterns are relative to their own folder
+        assert!(!filter.is_excluded(Path::new("/etc/nginx/cache"), true));
+        assert!(!filter.is_excluded(Path::new("/srv/app/a.bak"), false));
+    }
+
+    #[tokio::test]
+    async fn test_ignore_file() {
+        let root = tempfile::tempdir().unwrap();
+        create(
+            root.path(),
+            &["target/debug/bacup", "src/lib.rs", "keep.log", "a.log"],
+        );
+        std::fs::write(
+            root.path().join(IGNORE_FILE),
+            "# build\n/target\n*.log\n!keep.log\n",
+        )
+        .unwrap();
+
+        let filter = Filter::new(root.path(), &[], true).unwrap();
+        let walk = filter.walk(root.path()).await.unwrap();
+        let mut excluded = walk.excluded.clone();
+        excluded.sort();
+        assert_eq!(
+            excluded,
+            vec![root.path().join("a.log"), root.path().join("target")]
+        );
+        assert!(walk.included.contains(&root.path().join("src/lib.rs")));
+        assert!(walk.included.contains(&root.path().join("keep.log")));
+
+        // The ignore file is honored only when enabled
+        let filter = Filter::new(root.path(), &[], false).unwrap();
+        assert!(filter.walk(root.path()).await.unwrap().excluded.is_empty());
+    }
+}
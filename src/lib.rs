@@ -13,6 +13,13 @@
 // limitations under the License.
 
 pub mod backup;
+pub mod catalog;
 pub mod config;
+pub mod encryption;
+pub mod filter;
+pub mod metrics;
+pub mod notify;
 pub mod remotes;
+pub mod repository;
+pub mod restore;
 pub mod services;
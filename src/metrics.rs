@@ -0,0 +1,336 @@
+// Copyright 2022 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::backup::Backup;
+
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use tokio::fs;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Interval between two updates of the textfile.
+pub const TEXTFILE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time given to the clients for sending the request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum size of the request line and the headers.
+const MAX_REQUEST_SIZE: u64 = 8 * 1024;
+
+/// Escapes a label value as required by the Prometheus text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn timestamp(time: DateTime<Utc>) -> f64 {
+    time.timestamp_millis() as f64 / 1000.0
+}
+
+/// A metric and its value for every backup.
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    samples: Vec<(String, f64)>,
+}
+
+impl Family {
+    fn new(name: &'static str, kind: &'static str, help: &'static str) -> Family {
+        Family {
+            name,
+            kind,
+            help,
+            samples: vec![],
+        }
+    }
+}
+
+/// Renders the metrics of `backups` in the Prometheus text exposition format.
+pub fn render(backups: &[Arc<Backup>]) -> String {
+    let mut backups: Vec<&Arc<Backup>> = backups.iter().collect();
+    backups.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut last_success = Family::new(
+        "bacup_last_success_timestamp_seconds",
+        "gauge",
+        "Unix time of the end of the last successful execution.",
+    );
+    let mut duration = Family::new(
+        "bacup_last_run_duration_seconds",
+        "gauge",
+        "Duration of the last execution.",
+    );
+    let mut uploaded = Family::new(
+        "bacup_last_run_uploaded_bytes",
+        "gauge",
+        "Bytes uploaded by the last execution.",
+    );
+    let mut failures = Family::new(
+        "bacup_failures_total",
+        "counter",
+        "Failed executions since the start of the daemon.",
+    );
+    let mut next_run = Family::new(
+        "bacup_next_run_timestamp_seconds",
+        "gauge",
+        "Unix time of the next scheduled execution.",
+    );
+
+    for backup in backups {
+        let name = escape(&backup.name);
+        if let Some(end) = *backup.last_success.lock().unwrap() {
+            last_success.samples.push((name.clone(), timestamp(end)));
+        }
+        if let Some(report) = &*backup.last_report.lock().unwrap() {
+            duration.samples.push((
+                name.clone(),
+                report.duration().num_milliseconds() as f64 / 1000.0,
+            ));
+            uploaded
+                .samples
+                .push((name.clone(), report.bytes_sent() as f64));
+        }
+        failures
+            .samples
+            .push((name.clone(), backup.failures.load(Ordering::Relaxed) as f64));
+        if let Some(next) = backup.schedule.upcoming(Utc).next() {
+            next_run.samples.push((name, timestamp(next)));
+        }
+    }
+
+    let mut output = String::new();
+    for family in [last_success, duration, uploaded, failures, next_run] {
+        writeln!(output, "# HELP {} {}", family.name, family.help).unwrap();
+        writeln!(output, "# TYPE {} {}", family.name, family.kind).unwrap();
+        for (backup, value) in family.samples {
+            writeln!(output, "{}{{backup=\"{}\"}} {}", family.name, backup, value).unwrap();
+        }
+    }
+    output
+}
+
+/// Reads the request line and the headers (that are not used) of an HTTP request,
+/// returning the request line.
+async fn read_request<R>(stream: R) -> io::Result<String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut stream = stream.take(MAX_REQUEST_SIZE);
+    let mut request = String::new();
+    stream.read_line(&mut request).await?;
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            break;
+        }
+        if line == "\r\n" {
+            return Ok(request);
+        }
+    }
+    if stream.limit() == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("request bigger than {} bytes", MAX_REQUEST_SIZE),
+        ));
+    }
+    Ok(request)
+}
+
+/// Answers to a single HTTP request: GET /metrics returns the metrics, everything else 404.
+async fn respond(stream: TcpStream, backups: &[Arc<Backup>]) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(request) => request?,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "request not received in time",
+            ))
+        }
+    };
+
+    let (status, body) = match request.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", render(backups)),
+        _ => ("404 Not Found", String::from("Not found\n")),
+    };
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.shutdown().await
+}
+
+/// Serves the metrics of `backups` on `listener`, at the /metrics path.
+pub async fn serve(listener: TcpListener, backups: Vec<Arc<Backup>>) -> io::Result<()> {
+    let backups = Arc::new(backups);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let backups = backups.clone();
+        tokio::spawn(async move {
+            if let Err(error) = respond(stream, &backups).await {
+                warn!("Metrics: unable to answer {}: {}", peer, error);
+            }
+        });
+    }
+}
+
+/// Writes the metrics of `backups` into `path`, for the node exporter textfile collector.
+/// The file is replaced atomically, thus the collector never reads a partial file.
+pub async fn write_textfile(path: &Path, backups: &[Arc<Backup>]) -> io::Result<()> {
+    let tmp = path.with_extension("prom.tmp");
+    fs::write(&tmp, render(backups)).await?;
+    fs::rename(&tmp, path).await
+}
+
+/// Rewrites the textfile every TEXTFILE_INTERVAL.
+pub async fn textfile_exporter(path: &Path, backups: Vec<Arc<Backup>>) {
+    loop {
+        if let Err(error) = write_textfile(path, &backups).await {
+            warn!("Metrics: unable to write {}: {}", path.display(), error);
+        }
+        tokio::time::sleep(TEXTFILE_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::RunReport;
+    use crate::config::{BackupConfig, LocalhostConfig};
+    use crate::remotes::localhost::Localhost;
+    use crate::services::folders::Folder;
+    use tokio::io::AsyncReadExt;
+
+    async fn backup(name: &str, path: &Path) -> Arc<Backup> {
+        let remote = Localhost::new(
+            LocalhostConfig {
+                path: String::from(path.to_str().unwrap()),
+            },
+            "test_remote",
+        )
+        .unwrap();
+        let service = Folder::new("Cargo.toml").await.unwrap();
+        let config = BackupConfig {
+            what: String::from("folders.test"),
+            r#where: String::from("localhost.test"),
+            when: String::from("daily 00:00"),
+            remote_path: String::from("/backup"),
+            ..Default::default()
+        };
+        Arc::new(
+            Backup::new(name, Box::new(remote), Box::new(service), &config)
+                .await
+                .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_render() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let first = backup("first", tmp_dir.path()).await;
+        let second = backup("second\"", tmp_dir.path()).await;
+
+        let mut report = RunReport::new("first");
+        report.end = DateTime::from_timestamp(1_600_000_010, 0).unwrap();
+        report.start = DateTime::from_timestamp(1_600_000_000, 0).unwrap();
+        first.record(report.clone());
+        report.errors.push(String::from("Upload failed"));
+        first.record(report);
+
+        let metrics = render(&[second, first]);
+        assert!(metrics.contains("# TYPE bacup_failures_total counter\n"));
+        assert!(
+            metrics.contains("bacup_last_success_timestamp_seconds{backup=\"first\"} 1600000010\n")
+        );
+        assert!(metrics.contains("bacup_last_run_duration_seconds{backup=\"first\"} 10\n"));
+        assert!(metrics.contains("bacup_last_run_uploaded_bytes{backup=\"first\"} 0\n"));
+        assert!(metrics.contains("bacup_failures_total{backup=\"first\"} 1\n"));
+        assert!(metrics.contains("bacup_failures_total{backup=\"second\\\"\"} 0\n"));
+        assert!(!metrics.contains("bacup_last_success_timestamp_seconds{backup=\"second"));
+        assert!(metrics.contains("bacup_next_run_timestamp_seconds{backup=\"first\"} "));
+        // Sorted by backup name
+        assert!(
+            metrics.find("failures_total{backup=\"first\"}")
+                < metrics.find("failures_total{backup=\"second")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, vec![backup("test", tmp_dir.path()).await]));
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream
+                .write_all(format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).as_bytes())
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("bacup_failures_total{backup=\"test\"} 0\n"));
+
+        assert!(get("/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let request = "GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n";
+        assert_eq!(
+            read_request(request.as_bytes()).await.unwrap(),
+            "GET /metrics HTTP/1.1\r\n"
+        );
+
+        // Endless headers
+        let request = format!("GET /metrics HTTP/1.1\r\nX: {}", "a".repeat(10 * 1024));
+        let error = read_request(request.as_bytes()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_write_textfile() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("bacup.prom");
+        let backups = vec![backup("test", tmp_dir.path()).await];
+        write_textfile(&path, &backups).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), render(&backups));
+        assert!(!tmp_dir.path().join("bacup.prom.tmp").exists());
+    }
+}
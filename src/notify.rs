@@ -0,0 +1,488 @@
+// Copyright 2022 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::backup::RunReport;
+use crate::catalog::Status;
+use crate::config::{CommandConfig, NotifyConfig, SmtpConfig, WebhookConfig};
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::Serialize;
+use tokio::process::Command;
+
+/// Timeout of the requests sent to the webhooks and to the SMTP servers.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum Error {
+    RuntimeError(io::Error),
+    InvalidConfiguration(String),
+    WebhookError(reqwest::Error),
+    SmtpError(lettre::transport::smtp::Error),
+    MessageError(lettre::error::Error),
+    CommandFailed(String),
+}
+
+impl std::error::Error for Error {}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::RuntimeError(error) => write!(f, "Runtime error: {}", error),
+            Error::InvalidConfiguration(msg) => write!(f, "Invalid configuration: {}", msg),
+            Error::WebhookError(error) => write!(f, "Webhook error: {}", error),
+            Error::SmtpError(error) => write!(f, "SMTP error: {}", error),
+            Error::MessageError(error) => write!(f, "Unable to create the email: {}", error),
+            Error::CommandFailed(msg) => write!(f, "Command failed: {}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::RuntimeError(error)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Error::WebhookError(error)
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for Error {
+    fn from(error: lettre::transport::smtp::Error) -> Self {
+        Error::SmtpError(error)
+    }
+}
+
+impl From<lettre::error::Error> for Error {
+    fn from(error: lettre::error::Error) -> Self {
+        Error::MessageError(error)
+    }
+}
+
+/// The outcome of a backup execution, sent on the notification channels.
+#[derive(Serialize, Debug, Clone)]
+pub struct Notification {
+    pub backup: String,
+    pub remote: String,
+    pub status: Status,
+    /// The report of the execution, as logged
+    pub summary: String,
+    pub errors: Vec<String>,
+    pub next_run: Option<DateTime<Utc>>,
+}
+
+impl Notification {
+    pub fn new(remote: &str, report: &RunReport, next_run: Option<DateTime<Utc>>) -> Notification {
+        Notification {
+            backup: report.name.clone(),
+            remote: String::from(remote),
+            status: report.status(),
+            summary: report.to_string(),
+            errors: report.errors.clone(),
+            next_run,
+        }
+    }
+
+    pub fn subject(&self) -> String {
+        format!("[bacup] {}: {}", self.backup, self.status)
+    }
+
+    pub fn text(&self) -> String {
+        format!(
+            "Backup {} -> {} {}\nNext run: {}",
+            self.backup,
+            self.remote,
+            self.summary,
+            self.next_run
+                .map_or_else(|| String::from("-"), |next_run| next_run.to_string())
+        )
+    }
+}
+
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    /// The channel as named in the configuration (e.g. webhook.slack)
+    fn name(&self) -> String;
+    async fn notify(&self, notification: &Notification) -> Result<(), Error>;
+}
+
+/// Posts the notification as JSON to a Slack/Mattermost compatible incoming webhook.
+pub struct Webhook {
+    name: String,
+    url: String,
+    client: reqwest::Client,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    /// Message shown by Slack and Mattermost
+    text: String,
+    #[serde(flatten)]
+    notification: &'a Notification,
+}
+
+impl Webhook {
+    pub fn new(config: &WebhookConfig, name: &str) -> Result<Webhook, Error> {
+        reqwest::Url::parse(&config.url).map_err(|error| {
+            Error::InvalidConfiguration(format!("invalid url {}: {}", config.url, error))
+        })?;
+        Ok(Webhook {
+            name: format!("webhook.{}", name),
+            url: config.url.clone(),
+            client: reqwest::Client::builder().timeout(TIMEOUT).build()?,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for Webhook {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    async fn notify(&self, notification: &Notification) -> Result<(), Error> {
+        self.client
+            .post(&self.url)
+            .json(&WebhookPayload {
+                text: notification.text(),
+                notification,
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Sends the notification by email.
+pub struct Smtp {
+    name: String,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl Smtp {
+    pub fn new(config: &SmtpConfig, name: &str) -> Result<Smtp, Error> {
+        let mailbox = |address: &str| {
+            Mailbox::from_str(address).map_err(|error| {
+                Error::InvalidConfiguration(format!("invalid address {}: {}", address, error))
+            })
+        };
+        let from = mailbox(&config.from)?;
+        let to = config
+            .to
+            .iter()
+            .map(|address| mailbox(address))
+            .collect::<Result<Vec<Mailbox>, Error>>()?;
+        if to.is_empty() {
+            return Err(Error::InvalidConfiguration(String::from(
+                "at least a recipient is required in to",
+            )));
+        }
+
+        let mut builder = match config.security.as_deref().unwrap_or("starttls") {
+            "tls" => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+            "starttls" => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?,
+            "none" => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+            security => {
+                return Err(Error::InvalidConfiguration(format!(
+                    "invalid security {}, expected one of tls, starttls, none",
+                    security
+                )))
+            }
+        };
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(Smtp {
+            name: format!("smtp.{}", name),
+            from,
+            to,
+            transport: builder.timeout(Some(TIMEOUT)).build(),
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for Smtp {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    async fn notify(&self, notification: &Notification) -> Result<(), Error> {
+        let mut message = Message::builder()
+            .from(self.from.clone())
+            .subject(notification.subject())
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        self.transport
+            .send(message.body(notification.text())?)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Executes a shell command, passing the notification in the BACUP_* environment variables.
+pub struct ShellCommand {
+    name: String,
+    command: String,
+}
+
+impl ShellCommand {
+    pub fn new(config: &CommandConfig, name: &str) -> ShellCommand {
+        ShellCommand {
+            name: format!("command.{}", name),
+            command: config.command.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for ShellCommand {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    async fn notify(&self, notification: &Notification) -> Result<(), Error> {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .env("BACUP_BACKUP", &notification.backup)
+            .env("BACUP_REMOTE", &notification.remote)
+            .env("BACUP_STATUS", notification.status.to_string())
+            .env("BACUP_ERRORS", notification.errors.join("\n"))
+            .env(
+                "BACUP_NEXT_RUN",
+                notification
+                    .next_run
+                    .map_or_else(String::new, |next_run| next_run.to_rfc3339()),
+            )
+            .env("BACUP_MESSAGE", notification.text())
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(Error::CommandFailed(format!(
+                "{} exited with {}: {}",
+                self.command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Creates the notification channels defined in the [notify] section.
+pub fn notifiers(config: &NotifyConfig) -> Result<Vec<Arc<dyn Notifier>>, Error> {
+    let mut notifiers: Vec<Arc<dyn Notifier>> = vec![];
+    for (name, webhook) in config.webhook.iter().flatten() {
+        notifiers.push(Arc::new(Webhook::new(webhook, name)?));
+    }
+    for (name, smtp) in config.smtp.iter().flatten() {
+        notifiers.push(Arc::new(Smtp::new(smtp, name)?));
+    }
+    for (name, command) in config.command.iter().flatten() {
+        notifiers.push(Arc::new(ShellCommand::new(command, name)));
+    }
+    Ok(notifiers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    fn notification() -> Notification {
+        let mut report = RunReport::new("test");
+        report.errors.push(String::from("Upload failed: timeout"));
+        Notification::new("localhost.test", &report, Some(Utc::now()))
+    }
+
+    /// Accepts a single HTTP request, answers with `status` and returns the request body.
+    async fn http_stub(status: u16) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((key, value)) = line.split_once(':') {
+                    if key.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await.unwrap();
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 {} Stub\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            String::from_utf8(body).unwrap()
+        });
+        (url, handle)
+    }
+
+    /// Accepts a single SMTP session and returns the received message.
+    async fn smtp_stub() -> (u16, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            stream.write_all(b"220 stub ESMTP\r\n").await.unwrap();
+            let mut data = String::new();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        stream.write_all(b"250 queued\r\n").await.unwrap();
+                    } else {
+                        data.push_str(&line);
+                    }
+                    continue;
+                }
+                let reply: &[u8] = match line.get(..4).unwrap_or("").to_uppercase().as_str() {
+                    "DATA" => {
+                        in_data = true;
+                        b"354 go ahead\r\n"
+                    }
+                    "QUIT" => {
+                        stream.write_all(b"221 bye\r\n").await.unwrap();
+                        break;
+                    }
+                    _ => b"250 ok\r\n",
+                };
+                stream.write_all(reply).await.unwrap();
+            }
+            data
+        });
+        (port, handle)
+    }
+
+    #[tokio::test]
+    async fn test_webhook() {
+        let (url, handle) = http_stub(200).await;
+        let webhook = Webhook::new(&WebhookConfig { url }, "stub").unwrap();
+        assert_eq!(webhook.name(), "webhook.stub");
+        webhook.notify(&notification()).await.unwrap();
+
+        let body = handle.await.unwrap();
+        assert!(body.contains("\"text\":\"Backup test -> localhost.test failed"));
+        assert!(body.contains("\"status\":\"failure\""));
+        assert!(body.contains("\"errors\":[\"Upload failed: timeout\"]"));
+        assert!(body.contains("\"next_run\":\""));
+
+        let (url, handle) = http_stub(500).await;
+        let webhook = Webhook::new(&WebhookConfig { url }, "stub").unwrap();
+        assert!(matches!(
+            webhook.notify(&notification()).await,
+            Err(Error::WebhookError(_))
+        ));
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_smtp() {
+        let (port, handle) = smtp_stub().await;
+        let smtp = Smtp::new(
+            &SmtpConfig {
+                host: String::from("127.0.0.1"),
+                port: Some(port),
+                username: None,
+                password: None,
+                from: String::from("bacup@example.com"),
+                to: vec![String::from("admin@example.com")],
+                security: Some(String::from("none")),
+            },
+            "stub",
+        )
+        .unwrap();
+        smtp.notify(&notification()).await.unwrap();
+
+        let data = handle.await.unwrap();
+        assert!(data.contains("Subject: [bacup] test: failure"));
+        assert!(data.contains("To: admin@example.com"));
+        assert!(data.contains("Upload failed: timeout"));
+    }
+
+    #[tokio::test]
+    async fn test_command() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let output = tmp_dir.path().join("notification");
+        let command = ShellCommand::new(
+            &CommandConfig {
+                command: format!(
+                    "echo \"$BACUP_BACKUP $BACUP_STATUS $BACUP_ERRORS\" > {}",
+                    output.display()
+                ),
+            },
+            "stub",
+        );
+        command.notify(&notification()).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(output).unwrap(),
+            "test failure Upload failed: timeout\n"
+        );
+
+        let command = ShellCommand::new(
+            &CommandConfig {
+                command: String::from("exit 1"),
+            },
+            "stub",
+        );
+        assert!(matches!(
+            command.notify(&notification()).await,
+            Err(Error::CommandFailed(_))
+        ));
+    }
+}
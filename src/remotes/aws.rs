@@ -14,47 +14,282 @@
 
 use aws_credential_types::provider::SharedCredentialsProvider;
 use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
 pub use aws_sdk_s3::{Client, Error};
 use aws_types::region::Region;
 
 use crate::config::AwsConfig;
 use crate::remotes::remote;
 
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use bytes::Bytes;
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{info, warn};
+
+use tokio::fs;
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream};
 
 use async_trait::async_trait;
 
+const MIB: usize = 1024 * 1024;
+/// Default size of the parts of the multipart uploads. Objects smaller than a part
+/// are uploaded with a single request.
+const DEFAULT_PART_SIZE: usize = 8 * MIB;
+/// Minimum part size accepted by S3 (except for the last part).
+const MIN_PART_SIZE: usize = 5 * MIB;
+/// Maximum part size accepted by S3.
+const MAX_PART_SIZE: usize = 5 * 1024 * MIB;
+/// Maximum number of parts of a multipart upload accepted by S3.
+const MAX_PARTS: usize = 10_000;
+/// The parts of the streams of unknown length double in size every GROWTH_PARTS parts.
+const GROWTH_PARTS: usize = MAX_PARTS / 10;
+const DEFAULT_PARALLEL_PARTS: usize = 4;
+const DEFAULT_PART_ATTEMPTS: u32 = 3;
+const DEFAULT_PARALLEL_FILES: usize = 4;
+
+/// Capacity of the pipe between the compression and the upload of the archives.
+const PIPE_SIZE: usize = 64 * 1024;
+
 #[derive(Clone)]
 pub struct AwsBucket {
     name: String,
     bucket: Bucket,
+    parallel_files: usize,
+}
+
+/// An object of the bucket, as listed by list_objects.
+struct Object {
+    key: String,
+    size: u64,
+    modified: SystemTime,
 }
 
 #[derive(Clone)]
 struct Bucket {
     client: Client,
     bucket_name: String,
+    part_size: usize,
+    parallel_parts: usize,
+    part_attempts: u32,
 }
 
 impl Bucket {
     pub async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
-        let response = self
+        Ok(self
+            .list_objects(prefix)
+            .await?
+            .into_iter()
+            .map(|object| object.key)
+            .collect())
+    }
+
+    /// Lists the objects whose key starts with `prefix`, following the pagination.
+    pub async fn list_objects(&self, prefix: &str) -> Result<Vec<Object>, Error> {
+        let mut objects = vec![];
+        let mut continuation_token = None;
+        loop {
+            let response = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket_name)
+                .prefix(prefix.trim_start_matches('/'))
+                .set_continuation_token(continuation_token)
+                .send()
+                .await?;
+            for object in response.contents() {
+                let modified = object.last_modified().map_or(UNIX_EPOCH, |modified| {
+                    UNIX_EPOCH
+                        + Duration::new(modified.secs().max(0) as u64, modified.subsec_nanos())
+                });
+                objects.push(Object {
+                    key: object.key().unwrap_or_default().to_owned(),
+                    size: object.size().unwrap_or_default().max(0) as u64,
+                    modified,
+                });
+            }
+            continuation_token = response.next_continuation_token().map(String::from);
+            if continuation_token.is_none() {
+                return Ok(objects);
+            }
+        }
+    }
+
+    /// Uploads everything read from `reader` into the object `remote_path`. `length`, when
+    /// known, is the number of bytes that will be read.
+    ///
+    /// Objects bigger than a part are sent with a multipart upload: up to parallel_parts
+    /// parts are uploaded concurrently, thus at most part_size * parallel_parts bytes
+    /// are kept in memory. When the length is unknown the parts grow while the upload
+    /// goes (see streamed_part_size). On failure, the multipart upload is aborted.
+    pub async fn upload<R>(
+        &self,
+        remote_path: &str,
+        reader: &mut R,
+        length: Option<u64>,
+    ) -> Result<(), remote::Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        // S3 accepts at most MAX_PARTS parts: bigger parts are used for the bigger files
+        let part_size = match length {
+            Some(length) => self
+                .part_size
+                .max(length.div_ceil(MAX_PARTS as u64) as usize),
+            None => self.part_size,
+        };
+        let part = read_part(reader, part_size, length).await?;
+        if part.len() < part_size {
+            self.put_object(remote_path, part).await?;
+            return Ok(());
+        }
+
+        let key = remote_path.trim_start_matches('/');
+        let upload_id = self
             .client
-            .list_objects_v2()
+            .create_multipart_upload()
             .bucket(&self.bucket_name)
-            .prefix(prefix.trim_start_matches('/'))
+            .key(key)
             .send()
-            .await?;
-        let mut ret: Vec<String> = vec![];
-        for res in response.contents.iter() {
-            for object in res {
-                ret.push(object.key.as_ref().unwrap().to_owned());
+            .await
+            .map_err(Error::from)?
+            .upload_id
+            .unwrap_or_default();
+
+        let remaining = length.map(|length| length - part.len() as u64);
+        match self
+            .upload_parts(key, &upload_id, part_size, part, reader, remaining)
+            .await
+        {
+            Ok(parts) => {
+                self.client
+                    .complete_multipart_upload()
+                    .bucket(&self.bucket_name)
+                    .key(key)
+                    .upload_id(&upload_id)
+                    .multipart_upload(
+                        CompletedMultipartUpload::builder()
+                            .set_parts(Some(parts))
+                            .build(),
+                    )
+                    .send()
+                    .await
+                    .map_err(Error::from)?;
+                Ok(())
+            }
+            Err(error) => {
+                // Don't leave the uploaded parts (that are billed) around
+                if let Err(abort_error) = self
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket_name)
+                    .key(key)
+                    .upload_id(&upload_id)
+                    .send()
+                    .await
+                {
+                    warn!(
+                        "Unable to abort the multipart upload {} of {}: {}",
+                        upload_id,
+                        key,
+                        Error::from(abort_error)
+                    );
+                }
+                Err(error)
+            }
+        }
+    }
+
+    async fn upload_parts<R>(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_size: usize,
+        first: Vec<u8>,
+        reader: &mut R,
+        mut remaining: Option<u64>,
+    ) -> Result<Vec<CompletedPart>, remote::Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut parts = vec![];
+        let mut uploading = FuturesUnordered::new();
+        let mut part = first;
+        let mut part_number = 0;
+        loop {
+            while !part.is_empty() && uploading.len() < self.parallel_parts {
+                if part_number == MAX_PARTS as i32 {
+                    return Err(remote::Error::LocalError(io::Error::other(format!(
+                        "{} is bigger than {} parts of {} MiB, increase part_size",
+                        key,
+                        MAX_PARTS,
+                        part_size / MIB
+                    ))));
+                }
+                part_number += 1;
+                uploading.push(self.upload_part(key, upload_id, part_number, Bytes::from(part)));
+                let next_size = match remaining {
+                    Some(_) => part_size,
+                    None => streamed_part_size(part_size, part_number as usize + 1),
+                };
+                part = read_part(reader, next_size, remaining).await?;
+                remaining = remaining.map(|remaining| remaining.saturating_sub(part.len() as u64));
+            }
+            match uploading.next().await {
+                Some(completed) => parts.push(completed?),
+                None => break,
+            }
+        }
+        // The parts must be listed in ascending order
+        parts.sort_by_key(|part: &CompletedPart| part.part_number);
+        Ok(parts)
+    }
+
+    /// Uploads a part, retrying up to part_attempts times with an exponential backoff
+    /// when the failure is transient.
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        content: Bytes,
+    ) -> Result<CompletedPart, remote::Error> {
+        let mut attempt = 1;
+        loop {
+            let result = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket_name)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(content.clone()))
+                .send()
+                .await;
+            let error: remote::Error = match result {
+                Ok(response) => {
+                    return Ok(CompletedPart::builder()
+                        .set_e_tag(response.e_tag)
+                        .part_number(part_number)
+                        .build())
+                }
+                Err(error) => Error::from(error).into(),
+            };
+            if attempt >= self.part_attempts || !error.is_transient() {
+                return Err(error);
             }
+            warn!(
+                "Upload of part {} of {} failed (attempt {}/{}): {}",
+                part_number, key, attempt, self.part_attempts, error
+            );
+            tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+            attempt += 1;
         }
-        Ok(ret)
     }
 
     pub async fn put_object(&self, remote_path: &str, content: Vec<u8>) -> Result<(), Error> {
@@ -68,11 +303,22 @@ impl Bucket {
         Ok(())
     }
 
+    pub async fn get_object(&self, remote_path: &str) -> Result<ByteStream, Error> {
+        let response = self
+            .client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(remote_path.trim_start_matches('/'))
+            .send()
+            .await?;
+        Ok(response.body)
+    }
+
     pub async fn delete(&self, remote_path: &str) -> Result<(), Error> {
         self.client
             .delete_object()
             .bucket(&self.bucket_name)
-            .key(remote_path)
+            .key(remote_path.trim_start_matches('/'))
             .send()
             .await?;
 
@@ -80,7 +326,102 @@ impl Bucket {
     }
 }
 
+/// Returns the size of the part `part_number` (starting from 1) of a stream of unknown
+/// length, whose first part is `part_size` bytes: the size doubles every GROWTH_PARTS
+/// parts, up to MAX_PART_SIZE. In this way, the 10,000 parts hold
+/// part_size * GROWTH_PARTS * 1023 bytes (almost 5 TiB, the maximum object size of S3,
+/// with parts of 5 MiB), while the smaller streams keep using small parts.
+fn streamed_part_size(part_size: usize, part_number: usize) -> usize {
+    let doublings = (part_number.saturating_sub(1) / GROWTH_PARTS) as u32;
+    part_size
+        .saturating_mul(1 << doublings)
+        .min(MAX_PART_SIZE.max(part_size))
+}
+
+/// Reads from `reader` up to `size` bytes. Returns less bytes only at the end of the stream.
+/// `remaining`, when known, is the number of bytes left in the stream: the buffer is not
+/// bigger than that.
+async fn read_part<R>(
+    reader: &mut R,
+    size: usize,
+    remaining: Option<u64>,
+) -> Result<Vec<u8>, io::Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let capacity = remaining.map_or(size, |remaining| remaining.min(size as u64) as usize);
+    // Filled in place: read_to_end would grow a full buffer while probing for the end
+    let mut part = vec![0; capacity];
+    let mut filled = 0;
+    while filled < capacity {
+        match reader.read(&mut part[filled..]).await? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    part.truncate(filled);
+    Ok(part)
+}
+
+/// Returns the prefix of the keys of the objects inside the folder `remote_path`: the
+/// path without the leading / and with a trailing /, or empty for the root of the bucket.
+fn folder_prefix(remote_path: &Path) -> String {
+    let prefix = remote_path.to_str().unwrap().trim_matches('/');
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", prefix)
+    }
+}
+
+/// Keeps the `keys` of the objects directly inside the folder `prefix` (as returned by
+/// folder_prefix), dropping the ones in its subfolders.
+fn direct_children(keys: Vec<String>, prefix: &str) -> Vec<String> {
+    keys.into_iter()
+        .filter(|key| {
+            key.strip_prefix(prefix)
+                .is_some_and(|name| !name.is_empty() && !name.contains('/'))
+        })
+        .collect()
+}
+
 impl AwsBucket {
+    /// Uploads into `remote_path` the content written into the pipe by `writing` (e.g.
+    /// an archive, while it's being created). `writing` returns the size and the checksum
+    /// of the content, and `length` is its size when known in advance.
+    async fn upload_stream<F>(
+        &self,
+        remote_path: PathBuf,
+        pipe: DuplexStream,
+        length: Option<u64>,
+        writing: F,
+    ) -> Result<remote::Upload, remote::Error>
+    where
+        F: Future<Output = Result<(u64, String), remote::Error>>,
+    {
+        let key = remote_path.to_str().unwrap();
+        // The pipe is moved into the upload future, so it's closed as soon as the upload
+        // ends (even on failure), making the writes on the other end fail.
+        let upload = async {
+            let mut pipe = pipe;
+            self.bucket.upload(key, &mut pipe, length).await
+        };
+
+        match tokio::join!(writing, upload) {
+            (Ok((size, checksum)), Ok(())) => Ok(remote::Upload {
+                remote_path: remote_path.clone(),
+                size,
+                checksum: Some(checksum),
+            }),
+            (Err(error), Ok(())) => {
+                // The truncated archive has been uploaded
+                self.bucket.delete(key).await.ok();
+                Err(error)
+            }
+            (_, Err(error)) => Err(error),
+        }
+    }
+
     pub async fn new(config: AwsConfig, bucket_name: &str) -> Result<AwsBucket, Error> {
         let region = Region::new(config.region);
         let mut builder =
@@ -102,9 +443,24 @@ impl AwsBucket {
         let mut conf_builder = aws_sdk_s3::config::Builder::from(&sdk_config);
         conf_builder.set_force_path_style(config.force_path_style);
         let client = Client::from_conf(conf_builder.build());
+        let part_size = config
+            .part_size
+            .map_or(DEFAULT_PART_SIZE, |size| size * MIB);
+        if part_size < MIN_PART_SIZE {
+            warn!(
+                "aws.{}: part_size must be at least 5 MiB, using 5 MiB",
+                bucket_name
+            );
+        }
         let bucket = Bucket {
             client,
             bucket_name: bucket_name.to_owned(),
+            part_size: part_size.max(MIN_PART_SIZE),
+            parallel_parts: config
+                .parallel_parts
+                .unwrap_or(DEFAULT_PARALLEL_PARTS)
+                .max(1),
+            part_attempts: config.part_attempts.unwrap_or(DEFAULT_PART_ATTEMPTS).max(1),
         };
 
         // Perform a listing request to check if the configuration is ok
@@ -112,6 +468,10 @@ impl AwsBucket {
         Ok(AwsBucket {
             name: String::from(bucket_name),
             bucket,
+            parallel_files: config
+                .parallel_files
+                .unwrap_or(DEFAULT_PARALLEL_FILES)
+                .max(1),
         })
     }
 }
@@ -123,8 +483,11 @@ impl remote::Remote for AwsBucket {
     }
 
     async fn enumerate(&self, remote_path: &Path) -> Result<Vec<String>, remote::Error> {
-        let ret = self.bucket.list(remote_path.to_str().unwrap()).await?;
-        Ok(ret)
+        // Like the listing of a folder: the siblings sharing the name as prefix
+        // (src-old for src) and the content of the nested folders are not listed
+        let prefix = folder_prefix(remote_path);
+        let keys = self.bucket.list(&prefix).await?;
+        Ok(direct_children(keys, &prefix))
     }
 
     async fn delete(&self, remote_path: &Path) -> Result<(), remote::Error> {
@@ -132,34 +495,81 @@ impl remote::Remote for AwsBucket {
         Ok(())
     }
 
-    async fn upload_file(&self, path: &Path, remote_path: &Path) -> Result<(), remote::Error> {
-        let mut content: Vec<u8> = vec![];
-        let mut file = File::open(path).await?;
-        file.read_to_end(&mut content).await?;
+    async fn download_file(&self, remote_path: &Path, path: &Path) -> Result<(), remote::Error> {
+        let body = self
+            .bucket
+            .get_object(remote_path.to_str().unwrap())
+            .await?;
+
+        let mut file = File::create(path).await?;
+        tokio::io::copy(&mut body.into_async_read(), &mut file).await?;
+        file.flush().await?;
+        Ok(())
+    }
 
-        let remote_path = remote_path.to_str().unwrap();
-        self.bucket.put_object(remote_path, content).await?;
+    async fn download_folder(&self, remote_path: &Path, path: &Path) -> Result<(), remote::Error> {
+        // The trailing / excludes the siblings sharing the name as prefix (src-old for src)
+        let prefix = folder_prefix(remote_path);
+        for key in self.bucket.list(&prefix).await? {
+            // Keys ending with / are the "folders" created by the web console
+            if key.ends_with('/') {
+                continue;
+            }
+            let relative = match key.strip_prefix(&prefix) {
+                Some(relative) => relative,
+                None => continue,
+            };
+            let dest = path.join(relative);
+            let parent = dest.parent().unwrap();
+            if !parent.exists() {
+                fs::create_dir_all(parent).await?;
+            }
+            self.download_file(&PathBuf::from(&key), &dest).await?;
+        }
         Ok(())
     }
 
+    async fn upload_file(
+        &self,
+        path: &Path,
+        remote_path: &Path,
+    ) -> Result<remote::Upload, remote::Error> {
+        // The file is hashed while it's uploaded, read only once
+        let size = fs::metadata(path).await?.len();
+        let mut file = File::open(path).await?;
+        let (writer, reader) = tokio::io::duplex(PIPE_SIZE);
+        let writing = async {
+            let mut writer = remote::HashWriter::new(writer);
+            tokio::io::copy(&mut file, &mut writer).await?;
+            writer.shutdown().await?;
+            Ok((writer.size(), writer.checksum()))
+        };
+        self.upload_stream(remote_path.to_path_buf(), reader, Some(size), writing)
+            .await
+    }
+
     async fn upload_file_compressed(
         &self,
         path: &Path,
         remote_path: &Path,
-    ) -> Result<(), remote::Error> {
-        let compressed_bytes = self.compress_file(path).await?;
-        let remote_path = self.remote_compressed_file_path(remote_path);
-        self.bucket
-            .put_object(remote_path.to_str().unwrap(), compressed_bytes)
-            .await?;
-        Ok(())
+        options: &remote::ArchiveOptions,
+    ) -> Result<remote::Upload, remote::Error> {
+        let remote_path = self.remote_compressed_file_path(remote_path, options);
+        let (writer, reader) = tokio::io::duplex(PIPE_SIZE);
+        let writing = async {
+            let mut writer = remote::HashWriter::new(writer);
+            self.compress_file(path, &mut writer, options).await?;
+            Ok((writer.size(), writer.checksum()))
+        };
+        self.upload_stream(remote_path, reader, None, writing).await
     }
 
     async fn upload_folder(
         &self,
         paths: &[PathBuf],
         remote_path: &Path,
-    ) -> Result<(), remote::Error> {
+        options: &remote::ArchiveOptions,
+    ) -> Result<Vec<remote::Upload>, remote::Error> {
         let tot = paths.len();
 
         let mut local_prefix = paths.iter().min_by(|a, b| a.cmp(b)).unwrap();
@@ -179,32 +589,275 @@ impl remote::Remote for AwsBucket {
             remote_paths.push(remote_path.join(path.strip_prefix(local_prefix).unwrap()));
         }
 
-        // Upload all the files one by one
+        // The objects already in the remote folder, for uploading only the changed files
+        let prefix = folder_prefix(remote_path);
+        let remote_objects: HashMap<String, Object> = self
+            .bucket
+            .list_objects(&prefix)
+            .await?
+            .into_iter()
+            .map(|object| (object.key.clone(), object))
+            .collect();
+
+        // Upload the changed files, parallel_files at a time
         let mut futures = vec![];
         // Add only files - paths are automatically created remotely from the full file path
         for i in 0..tot {
             if paths[i].is_file() {
+                let key = remote_paths[i].to_str().unwrap().trim_start_matches('/');
+                if let Some(object) = remote_objects.get(key) {
+                    // The objects are newer than the local files they were uploaded from
+                    let metadata = fs::metadata(&paths[i]).await?;
+                    if !remote::is_changed(
+                        &metadata,
+                        object.size,
+                        object.modified,
+                        remote::RemoteModified::Uploaded,
+                    ) {
+                        continue;
+                    }
+                }
                 futures.push(self.upload_file(&paths[i], &remote_paths[i]));
             }
         }
 
-        futures::future::join_all(futures).await;
-        Ok(())
+        let uploads = futures::stream::iter(futures)
+            .buffer_unordered(self.parallel_files)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<remote::Upload>, remote::Error>>()?;
+
+        if options.mirror && !single_location {
+            let synced: HashSet<PathBuf> = paths
+                .iter()
+                .map(|path| path.strip_prefix(local_prefix).unwrap().to_path_buf())
+                .collect();
+            for key in remote_objects.keys() {
+                // Keys ending with / are the "folders" created by the web console
+                let relative = match key.strip_prefix(&prefix) {
+                    Some(relative) if !key.ends_with('/') => Path::new(relative),
+                    _ => continue,
+                };
+                if !remote::is_stale(relative, false, local_prefix, &synced, options) {
+                    continue;
+                }
+                self.bucket.delete(key).await?;
+                info!("Deleted {}, not in {}", key, local_prefix.display());
+            }
+        }
+        Ok(uploads)
     }
 
     async fn upload_folder_compressed(
         &self,
-        path: &Path,
+        paths: &[PathBuf],
         remote_path: &Path,
-    ) -> Result<(), remote::Error> {
-        if !path.is_dir() {
+        options: &remote::ArchiveOptions,
+    ) -> Result<remote::Upload, remote::Error> {
+        if !paths.iter().all(|path| path.is_dir()) {
             return Err(remote::Error::NotADirectory);
         }
 
-        let remote_path = self.remote_archive_path(remote_path);
-        let compressed_folder = self.compress_folder(path).await?;
-        self.upload_file(compressed_folder.path(), &remote_path)
-            .await?;
-        Ok(())
+        let remote_path = self.remote_archive_path(remote_path, options);
+        let (writer, reader) = tokio::io::duplex(PIPE_SIZE);
+        let writing = async {
+            let mut writer = remote::HashWriter::new(writer);
+            self.compress_folder(paths, &mut writer, options).await?;
+            Ok((writer.size(), writer.checksum()))
+        };
+        self.upload_stream(remote_path, reader, None, writing).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remotes::remote::Remote;
+
+    // MinIO started with:
+    // docker run -p 9000:9000 minio/minio server /data
+    // and a bucket named bacup created
+    const ENDPOINT: &str = "http://localhost:9000";
+    const ACCESS_KEY: &str = "minioadmin";
+    const SECRET_KEY: &str = "minioadmin";
+    const BUCKET: &str = "bacup";
+
+    fn config() -> AwsConfig {
+        AwsConfig {
+            region: String::from("us-east-1"),
+            endpoint: Some(String::from(ENDPOINT)),
+            access_key: String::from(ACCESS_KEY),
+            secret_key: String::from(SECRET_KEY),
+            force_path_style: Some(true),
+            part_size: Some(5),
+            parallel_parts: Some(2),
+            part_attempts: None,
+            parallel_files: None,
+        }
+    }
+
+    #[test]
+    fn test_streamed_part_size() {
+        assert_eq!(streamed_part_size(8 * MIB, 1), 8 * MIB);
+        assert_eq!(streamed_part_size(8 * MIB, GROWTH_PARTS), 8 * MIB);
+        assert_eq!(streamed_part_size(8 * MIB, GROWTH_PARTS + 1), 16 * MIB);
+        assert_eq!(streamed_part_size(8 * MIB, MAX_PARTS), 4096 * MIB);
+        assert_eq!(streamed_part_size(16 * MIB, MAX_PARTS), MAX_PART_SIZE);
+
+        // The 10,000 parts hold (almost) the biggest object accepted by S3
+        let total = |part_size| -> usize {
+            (1..=MAX_PARTS)
+                .map(|part_number| streamed_part_size(part_size, part_number))
+                .sum()
+        };
+        assert!(total(MIN_PART_SIZE) > 4900 * 1024 * MIB);
+        assert!(total(DEFAULT_PART_SIZE) > 5 * 1024 * 1024 * MIB);
+    }
+
+    #[tokio::test]
+    async fn test_read_part() {
+        let content: Vec<u8> = (0..25u8).collect();
+        let mut reader = content.as_slice();
+        assert_eq!(
+            read_part(&mut reader, 10, None).await.unwrap(),
+            content[..10]
+        );
+        assert_eq!(
+            read_part(&mut reader, 10, None).await.unwrap(),
+            content[10..20]
+        );
+        let last = read_part(&mut reader, 10, Some(5)).await.unwrap();
+        assert_eq!(last, content[20..]);
+        assert_eq!(last.capacity(), 5);
+        assert!(read_part(&mut reader, 10, None).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_folder_prefix() {
+        assert_eq!(folder_prefix(Path::new("/backup/src")), "backup/src/");
+        assert_eq!(folder_prefix(Path::new("backup/src/")), "backup/src/");
+        assert_eq!(folder_prefix(Path::new("/")), "");
+        assert_eq!(folder_prefix(Path::new("")), "");
+    }
+
+    #[test]
+    fn test_direct_children() {
+        let keys = [
+            "backup/src/2024-01-01-00.00-src.tar.gz",
+            "backup/src/nested/2023-01-01-00.00-src.tar.gz",
+            "backup/src/",
+            "backup/src-old/2023-01-01-00.00-src.tar.gz",
+        ]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+        assert_eq!(
+            direct_children(keys, &folder_prefix(Path::new("/backup/src"))),
+            vec!["backup/src/2024-01-01-00.00-src.tar.gz"]
+        );
+        assert_eq!(
+            direct_children(vec![String::from("a"), String::from("b/c")], ""),
+            vec!["a"]
+        );
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_enumerate_siblings() {
+        let bucket = AwsBucket::new(config(), BUCKET).await.unwrap();
+        for key in ["/test/src/a", "/test/src/nested/b", "/test/src-old/c"] {
+            bucket
+                .bucket
+                .put_object(key, b"bacup".to_vec())
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            bucket.enumerate(Path::new("/test/src")).await.unwrap(),
+            vec!["test/src/a"]
+        );
+        for key in ["/test/src/a", "/test/src/nested/b", "/test/src-old/c"] {
+            bucket.delete(Path::new(key)).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_multipart_upload() {
+        let bucket = AwsBucket::new(config(), BUCKET).await.unwrap();
+        let tmp_dir = tempfile::tempdir().unwrap();
+
+        // 12 MiB: 3 parts of (at most) 5 MiB
+        let path = tmp_dir.path().join("big");
+        let content: Vec<u8> = (0..12 * MIB).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+
+        let remote_path = PathBuf::from("/test/big");
+        let upload = bucket.upload_file(&path, &remote_path).await.unwrap();
+        assert_eq!(upload.size, content.len() as u64);
+        assert_eq!(
+            upload.checksum,
+            Some(remote::checksum(&path).await.unwrap())
+        );
+
+        let downloaded = tmp_dir.path().join("downloaded");
+        bucket
+            .download_file(&remote_path, &downloaded)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&downloaded).unwrap(), content);
+
+        let upload = bucket
+            .upload_file_compressed(&path, &remote_path, &remote::ArchiveOptions::default())
+            .await
+            .unwrap();
+        bucket
+            .download_file(&upload.remote_path, &downloaded)
+            .await
+            .unwrap();
+        assert_eq!(
+            upload.checksum,
+            Some(remote::checksum(&downloaded).await.unwrap())
+        );
+
+        bucket.delete(&remote_path).await.unwrap();
+        bucket.delete(&upload.remote_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_upload_folder_mirror_root() {
+        let bucket = AwsBucket::new(config(), BUCKET).await.unwrap();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let paths: Vec<PathBuf> = ["a", "b"]
+            .iter()
+            .map(|name| tmp_dir.path().join(name))
+            .collect();
+        for path in &paths {
+            std::fs::write(path, "bacup").unwrap();
+        }
+        bucket
+            .bucket
+            .put_object("/stale/object", b"stale".to_vec())
+            .await
+            .unwrap();
+
+        // The keys of the bucket root have no leading /
+        let options = remote::ArchiveOptions {
+            mirror: true,
+            ..Default::default()
+        };
+        let uploads = bucket
+            .upload_folder(&paths, Path::new("/"), &options)
+            .await
+            .unwrap();
+        assert_eq!(uploads.len(), 2);
+        let keys = bucket.bucket.list("").await.unwrap();
+        assert!(keys.contains(&String::from("a")));
+        assert!(!keys.contains(&String::from("stale/object")));
+
+        bucket.delete(Path::new("/a")).await.unwrap();
+        bucket.delete(Path::new("/b")).await.unwrap();
     }
 }
@@ -18,12 +18,13 @@ use crate::remotes::ssh;
 
 use tokio::fs;
 use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
 
 use std::io;
 
 use std::path::{Path, PathBuf};
 
+use glob::glob;
+
 use std::fmt;
 use std::string::String;
 
@@ -31,8 +32,6 @@ use which::which;
 
 use async_trait::async_trait;
 
-use scopeguard::defer;
-
 use std::process::Command;
 
 #[derive(Debug)]
@@ -41,6 +40,7 @@ pub enum Error {
     CommandNotFound(which::Error),
     RuntimeError(io::Error),
     DoesNotExist(PathBuf),
+    CommandFailed(String),
 }
 
 impl From<which::Error> for Error {
@@ -74,6 +74,7 @@ impl fmt::Display for Error {
             Error::InvalidPrivateKey(ref msg) => write!(f, "Invalid private key: {}", msg),
             Error::RuntimeError(ref error) => write!(f, "Error while reading/writing: {}", error),
             Error::DoesNotExist(ref path) => write!(f, "Path {} does not exist", path.display()),
+            Error::CommandFailed(ref msg) => write!(f, "Command failed: {}", msg),
         }
     }
 }
@@ -89,6 +90,7 @@ impl From<Error> for remote::Error {
             Error::DoesNotExist(path) => {
                 remote::Error::LocalError(std::io::Error::other(path.to_str().unwrap()))
             }
+            Error::CommandFailed(msg) => remote::Error::CommandFailed(msg),
         }
     }
 }
@@ -150,115 +152,232 @@ impl Git {
         }
         Ok(dest)
     }
-}
-
-#[async_trait]
-impl remote::Remote for Git {
-    fn name(&self) -> String {
-        self.remote_name.clone()
-    }
 
-    async fn enumerate(&self, _remote_path: &Path) -> Result<Vec<String>, remote::Error> {
-        Err(remote::Error::LocalError(io::Error::other(
-            "enumerate is not possibile on Git remote!",
-        )))
-    }
-
-    async fn delete(&self, _remote_path: &Path) -> Result<(), remote::Error> {
-        Err(remote::Error::LocalError(io::Error::other(
-            "delete makes no sense on git remote. Change the repo, and upload the new repo status.",
-        )))
-    }
-
-    async fn upload_file(&self, path: &Path, remote_path: &Path) -> Result<(), remote::Error> {
+    /// Clones (or reuses the already cloned) repository and updates it
+    /// to the latest status of the configured branch.
+    fn updated_repository(&self) -> Result<PathBuf, Error> {
         let repo = self.clone_repository()?;
 
-        // cp file <repo_location>/[<subdir>]
-        let dest = repo.join(remote_path.strip_prefix("/").unwrap());
-        if !dest.exists() {
-            fs::create_dir_all(&dest).await.unwrap();
+        // git checkout branch
+        let status = Command::new(&self.git_cmd)
+            .current_dir(&repo)
+            .args(["checkout", &self.config.branch])
+            .status()?;
+        if !status.success() {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "Unable to execute git checkout {} into {}",
+                &self.config.branch,
+                repo.display()
+            ))));
         }
-        fs::copy(path, dest.join(path.file_name().unwrap())).await?;
 
-        // cd <repo path>
-        let cwd = std::env::current_dir()?;
-        defer! {
-            #[allow(unused_must_use)] {
-            std::env::set_current_dir(cwd);
-            }
+        // git pull origin branch
+        let status = Command::new(&self.git_cmd)
+            .current_dir(&repo)
+            .args(["pull", "origin", &self.config.branch])
+            .status()?;
+        if !status.success() {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "Unable to execute git pull origin {} into {}",
+                &self.config.branch,
+                repo.display()
+            ))));
         }
-        std::env::set_current_dir(&dest)?;
+        Ok(repo)
+    }
 
+    /// Commits the changes in the folder `dir` of the repository and pushes them
+    /// to the configured branch.
+    fn commit(&self, dir: &Path, message: &str) -> Result<(), Error> {
         // git switch -c branch (ignore failures - we might be in the branch already)
         Command::new(&self.git_cmd)
+            .current_dir(dir)
             .args(["switch", "-c", &self.config.branch])
             .status()?;
 
         // git pull origin branch (ignore failures)
         Command::new(&self.git_cmd)
+            .current_dir(dir)
             .args(["pull", "origin", &self.config.branch])
             .status()?;
 
         // git add . -A
         let status = Command::new(&self.git_cmd)
+            .current_dir(dir)
             .args(["add", ".", "-A"])
             .status()?;
         if !status.success() {
-            return Err(remote::Error::LocalError(io::Error::other(format!(
+            return Err(Error::RuntimeError(io::Error::other(format!(
                 "Unable to execute git add . -A into {}",
-                dest.display()
+                dir.display()
             ))));
         }
-        // git commit -m '[bacup] snapshot'
+        // git commit -m message
         let status = Command::new(&self.git_cmd)
-            .args(["commit", "-m", "[bacup] snapshot"])
+            .current_dir(dir)
+            .args(["commit", "-m", message])
             .status()?;
         if !status.success() {
-            return Err(remote::Error::LocalError(io::Error::other(format!(
-                "Unable to execute git commit -m [bacup] snapshot into {}",
-                dest.display()
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "Unable to execute git commit -m {} into {}",
+                message,
+                dir.display()
             ))));
         }
         // git push origin <branch>
         let status = Command::new(&self.git_cmd)
+            .current_dir(dir)
             .args(["push", "origin", &self.config.branch])
             .status()?;
+        if !status.success() {
+            return Err(Error::CommandFailed(format!(
+                "Unable to execute git push origin {} into {}",
+                &self.config.branch,
+                dir.display()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl remote::Remote for Git {
+    fn name(&self) -> String {
+        self.remote_name.clone()
+    }
+
+    async fn enumerate(&self, remote_path: &Path) -> Result<Vec<String>, remote::Error> {
+        let repo = self.updated_repository()?;
+
+        let folder = repo.join(remote_path.strip_prefix("/").unwrap_or(remote_path));
+        if !folder.is_dir() {
+            return Err(remote::Error::NotADirectory);
+        }
+        // The files of the working tree, named as the remote paths passed to upload_file
+        let mut ret = vec![];
+        let mut entries = fs::read_dir(&folder).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            if name == ".git" {
+                continue;
+            }
+            ret.push(remote_path.join(name).to_str().unwrap().to_string());
+        }
+        Ok(ret)
+    }
+
+    async fn delete(&self, remote_path: &Path) -> Result<(), remote::Error> {
+        let repo = self.updated_repository()?;
+
+        let relative = remote_path.strip_prefix("/").unwrap_or(remote_path);
+        if !repo.join(relative).exists() {
+            return Err(Error::DoesNotExist(repo.join(relative)).into());
+        }
+        // git rm -r -q <path>
+        let status = Command::new(&self.git_cmd)
+            .current_dir(&repo)
+            .args(["rm", "-r", "-q", "--"])
+            .arg(relative)
+            .status()?;
         if !status.success() {
             return Err(remote::Error::LocalError(io::Error::other(format!(
-                "Unable to execute git add . -A into {}",
-                dest.display()
+                "Unable to execute git rm -r {} into {}",
+                relative.display(),
+                repo.display()
             ))));
         }
+        self.commit(&repo, &format!("[bacup] delete {}", relative.display()))?;
         Ok(())
     }
 
-    async fn upload_file_compressed(
+    async fn download_file(&self, remote_path: &Path, path: &Path) -> Result<(), remote::Error> {
+        let repo = self.updated_repository()?;
+
+        let mut src = repo.join(remote_path.strip_prefix("/").unwrap_or(remote_path));
+        // upload_file copies the file inside a folder named remote_path
+        if src.is_dir() {
+            src = src.join(remote_path.file_name().unwrap());
+        }
+        if !src.is_file() {
+            return Err(Error::DoesNotExist(src).into());
+        }
+        fs::copy(src, path).await?;
+        Ok(())
+    }
+
+    async fn download_folder(&self, remote_path: &Path, path: &Path) -> Result<(), remote::Error> {
+        let repo = self.updated_repository()?;
+
+        let src = repo.join(remote_path.strip_prefix("/").unwrap_or(remote_path));
+        if !src.is_dir() {
+            return Err(remote::Error::NotADirectory);
+        }
+
+        let git_folder = std::path::Component::Normal(".git".as_ref());
+        let pattern = src.join("**").join("*");
+        for entry in glob(pattern.to_str().unwrap()).unwrap() {
+            let entry = entry.map_err(|error| remote::Error::LocalError(error.into()))?;
+            let relative = entry.strip_prefix(&src).unwrap();
+            // Skip .git and content of this folder
+            if relative.components().any(|x| x == git_folder) {
+                continue;
+            }
+            if entry.is_file() {
+                let dest = path.join(relative);
+                let parent = dest.parent().unwrap();
+                if !parent.exists() {
+                    fs::create_dir_all(parent).await?;
+                }
+                fs::copy(&entry, dest).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn upload_file(
         &self,
         path: &Path,
         remote_path: &Path,
-    ) -> Result<(), remote::Error> {
-        // Read and compress
-        let compressed_bytes = self.compress_file(path).await?;
-        let remote_path = self.remote_compressed_file_path(remote_path);
-
-        let mut buffer = File::create(&remote_path).await?;
-        buffer.write_all(&compressed_bytes).await?;
-
-        defer! {
-            #[allow(unused_must_use)]
-            {
-                fs::remove_file(&remote_path);
-            }
+    ) -> Result<remote::Upload, remote::Error> {
+        let repo = self.clone_repository()?;
+
+        // cp file <repo_location>/[<subdir>]
+        let dest = repo.join(remote_path.strip_prefix("/").unwrap());
+        if !dest.exists() {
+            fs::create_dir_all(&dest).await.unwrap();
         }
-        self.upload_file(&remote_path, &remote_path).await?;
-        Ok(())
+        let size = fs::copy(path, dest.join(path.file_name().unwrap())).await?;
+
+        self.commit(&dest, "[bacup] snapshot")?;
+        Ok(remote::Upload {
+            remote_path: remote_path.join(path.file_name().unwrap()),
+            size,
+            checksum: Some(remote::checksum(path).await?),
+        })
+    }
+
+    async fn upload_file_compressed(
+        &self,
+        path: &Path,
+        remote_path: &Path,
+        options: &remote::ArchiveOptions,
+    ) -> Result<remote::Upload, remote::Error> {
+        let remote_path = self.remote_compressed_file_path(remote_path, options);
+
+        // Compress into a local file with the archive name, then commit it
+        let tmp_dir = tempfile::tempdir()?;
+        let archive = tmp_dir.path().join(remote_path.file_name().unwrap());
+        self.compress_file(path, File::create(&archive).await?, options)
+            .await?;
+        self.upload_file(&archive, &remote_path).await
     }
 
     async fn upload_folder(
         &self,
         paths: &[PathBuf],
         remote_path: &Path,
-    ) -> Result<(), remote::Error> {
+        _options: &remote::ArchiveOptions,
+    ) -> Result<Vec<remote::Upload>, remote::Error> {
         let repo = self.clone_repository()?;
 
         // cp file <repo_location>/[<subdir>]
@@ -267,6 +386,7 @@ impl remote::Remote for Git {
             fs::create_dir_all(&dest).await.unwrap();
         }
         let git_folder = std::path::Component::Normal(".git".as_ref());
+        let mut uploads = vec![];
         for path in paths.iter() {
             // Skip .git and content of this folder
             if path.components().any(|x| x == git_folder) {
@@ -275,75 +395,35 @@ impl remote::Remote for Git {
             if path.is_dir() {
                 fs::create_dir_all(dest.join(path.file_name().unwrap())).await?;
             } else {
-                fs::copy(path, dest.join(path.file_name().unwrap())).await?;
-            }
-        }
-
-        // cd <repo path>
-        let cwd = std::env::current_dir()?;
-        defer! {
-            #[allow(unused_must_use)] {
-            std::env::set_current_dir(cwd);
+                let size = fs::copy(path, dest.join(path.file_name().unwrap())).await?;
+                uploads.push(remote::Upload {
+                    remote_path: remote_path.join(path.file_name().unwrap()),
+                    size,
+                    checksum: None,
+                });
             }
         }
-        std::env::set_current_dir(&dest)?;
 
-        // git switch -c branch (ignore failures - we might be in the branch already)
-        Command::new(&self.git_cmd)
-            .args(["switch", "-c", &self.config.branch])
-            .status()?;
-
-        // git pull origin branch (ignore failures)
-        Command::new(&self.git_cmd)
-            .args(["pull", "origin", &self.config.branch])
-            .status()?;
-
-        // git add . -A
-        let status = Command::new(&self.git_cmd)
-            .args(["add", ".", "-A"])
-            .status()?;
-        if !status.success() {
-            return Err(remote::Error::LocalError(io::Error::other(format!(
-                "Unable to execute git add . -A into {}",
-                dest.display()
-            ))));
-        }
-        // git commit -m '[bacup] snapshot'
-        let status = Command::new(&self.git_cmd)
-            .args(["commit", "-m", "[bacup] snapshot"])
-            .status()?;
-        if !status.success() {
-            return Err(remote::Error::LocalError(io::Error::other(format!(
-                "Unable to execute git commit -m [bacup] snapshot into {}",
-                dest.display()
-            ))));
-        }
-        // git push origin <branch>
-        let status = Command::new(&self.git_cmd)
-            .args(["push", "origin", &self.config.branch])
-            .status()?;
-        if !status.success() {
-            return Err(remote::Error::LocalError(io::Error::other(format!(
-                "Unable to execute git add . -A into {}",
-                dest.display()
-            ))));
-        }
-        Ok(())
+        self.commit(&dest, "[bacup] snapshot")?;
+        Ok(uploads)
     }
 
     async fn upload_folder_compressed(
         &self,
-        path: &Path,
+        paths: &[PathBuf],
         remote_path: &Path,
-    ) -> Result<(), remote::Error> {
-        if !path.is_dir() {
+        options: &remote::ArchiveOptions,
+    ) -> Result<remote::Upload, remote::Error> {
+        if !paths.iter().all(|path| path.is_dir()) {
             return Err(remote::Error::NotADirectory);
         }
 
-        let remote_path = self.remote_archive_path(remote_path);
-        let compressed_folder = self.compress_folder(path).await?;
+        let remote_path = self.remote_archive_path(remote_path, options);
 
-        self.upload_file(compressed_folder.path(), &remote_path)
-            .await
+        let tmp_dir = tempfile::tempdir()?;
+        let archive = tmp_dir.path().join(remote_path.file_name().unwrap());
+        self.compress_folder(paths, File::create(&archive).await?, options)
+            .await?;
+        self.upload_file(&archive, &remote_path).await
     }
 }
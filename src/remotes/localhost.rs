@@ -15,9 +15,13 @@
 use crate::config::LocalhostConfig;
 use crate::remotes::remote;
 
+use std::collections::HashSet;
 use std::io;
 use std::path::{Path, PathBuf};
 
+use glob::glob;
+use log::info;
+
 use async_trait::async_trait;
 
 use std::fmt;
@@ -65,6 +69,28 @@ impl Localhost {
             path,
         })
     }
+
+    /// Creates the file `remote_path` inside the localhost path, together with its
+    /// missing parent folders. Returns the local path of the file and the file.
+    async fn create_file(
+        &self,
+        remote_path: &Path,
+    ) -> Result<(PathBuf, tokio::fs::File), io::Error> {
+        use tokio::fs;
+
+        let remote_path = if remote_path.is_absolute() {
+            remote_path.strip_prefix("/").unwrap()
+        } else {
+            remote_path
+        };
+        let dest = self.path.join(remote_path);
+        let parent = dest.parent().unwrap();
+        if !parent.exists() {
+            fs::create_dir_all(parent).await?;
+        }
+        let file = fs::File::create(&dest).await?;
+        Ok((dest, file))
+    }
 }
 
 #[async_trait]
@@ -117,62 +143,123 @@ impl remote::Remote for Localhost {
         Ok(())
     }
 
-    async fn upload_file(&self, path: &Path, remote_path: &Path) -> Result<(), remote::Error> {
+    async fn download_file(&self, remote_path: &Path, path: &Path) -> Result<(), remote::Error> {
         use tokio::fs;
 
-        if !path.exists() {
+        let remote_path = if remote_path.is_absolute() {
+            remote_path.strip_prefix("/").unwrap()
+        } else {
+            remote_path
+        };
+
+        let src = self.path.join(remote_path);
+        if !src.is_file() {
             return Err(remote::Error::LocalError(io::Error::other(format!(
-                "{} does not exist",
-                path.display()
+                "{} does not exist or is not a file",
+                src.display()
             ))));
         }
 
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                fs::create_dir_all(parent).await?;
+            }
+        }
+        fs::copy(src, path).await?;
+        Ok(())
+    }
+
+    async fn download_folder(&self, remote_path: &Path, path: &Path) -> Result<(), remote::Error> {
+        use tokio::fs;
+
         let remote_path = if remote_path.is_absolute() {
             remote_path.strip_prefix("/").unwrap()
         } else {
             remote_path
         };
 
-        let dest = self.path.join(remote_path.parent().unwrap());
-        if !dest.exists() {
-            fs::create_dir_all(&dest).await?;
+        let src = self.path.join(remote_path);
+        if !src.is_dir() {
+            return Err(remote::Error::NotADirectory);
+        }
+
+        // Same approach of the folders service: list everything inside src/**/*
+        // and copy the files preserving the relative layout.
+        let pattern = src.join("**").join("*");
+        for entry in glob(pattern.to_str().unwrap()).unwrap() {
+            let entry = entry.map_err(|error| remote::Error::LocalError(error.into()))?;
+            if entry.is_file() {
+                let dest = path.join(entry.strip_prefix(&src).unwrap());
+                let parent = dest.parent().unwrap();
+                if !parent.exists() {
+                    fs::create_dir_all(parent).await?;
+                }
+                fs::copy(&entry, dest).await?;
+            }
         }
-        fs::copy(path, dest.join(remote_path.file_name().unwrap())).await?;
         Ok(())
     }
 
-    async fn upload_file_compressed(
+    async fn upload_file(
         &self,
         path: &Path,
         remote_path: &Path,
-    ) -> Result<(), remote::Error> {
+    ) -> Result<remote::Upload, remote::Error> {
         use tokio::fs;
-        use tokio::io::AsyncWriteExt;
 
-        let compressed_bytes = self.compress_file(path).await?;
+        if !path.exists() {
+            return Err(remote::Error::LocalError(io::Error::other(format!(
+                "{} does not exist",
+                path.display()
+            ))));
+        }
+
+        let upload_path = remote_path.to_path_buf();
         let remote_path = if remote_path.is_absolute() {
             remote_path.strip_prefix("/").unwrap()
         } else {
             remote_path
         };
-        let parent = self.path.join(remote_path.parent().unwrap());
-        if !parent.exists() {
-            fs::create_dir_all(&parent).await?;
+
+        let dest = self.path.join(remote_path.parent().unwrap());
+        if !dest.exists() {
+            fs::create_dir_all(&dest).await?;
         }
-        let remote_path = parent.join(
-            self.remote_compressed_file_path(&PathBuf::from(remote_path.file_name().unwrap())),
-        );
+        let size = fs::copy(path, dest.join(remote_path.file_name().unwrap())).await?;
+        Ok(remote::Upload {
+            remote_path: upload_path,
+            size,
+            checksum: Some(remote::checksum(path).await?),
+        })
+    }
 
-        let mut buffer = fs::File::create(remote_path).await?;
-        buffer.write_all(&compressed_bytes).await?;
-        Ok(())
+    async fn upload_file_compressed(
+        &self,
+        path: &Path,
+        remote_path: &Path,
+        options: &remote::ArchiveOptions,
+    ) -> Result<remote::Upload, remote::Error> {
+        let remote_path = self.remote_compressed_file_path(remote_path, options);
+        let (dest, file) = self.create_file(&remote_path).await?;
+
+        let mut writer = remote::HashWriter::new(file);
+        if let Err(error) = self.compress_file(path, &mut writer, options).await {
+            tokio::fs::remove_file(dest).await.ok();
+            return Err(error);
+        }
+        Ok(remote::Upload {
+            remote_path,
+            size: writer.size(),
+            checksum: Some(writer.checksum()),
+        })
     }
 
     async fn upload_folder(
         &self,
         paths: &[PathBuf],
         remote_path: &Path,
-    ) -> Result<(), remote::Error> {
+        options: &remote::ArchiveOptions,
+    ) -> Result<Vec<remote::Upload>, remote::Error> {
         use tokio::fs;
 
         let mut local_prefix = paths.iter().min_by(|a, b| a.cmp(b)).unwrap();
@@ -197,46 +284,211 @@ impl remote::Remote for Localhost {
             PathBuf::from(remote_path)
         };
 
+        let mut uploads = vec![];
+        let mut synced = HashSet::new();
         for path in paths.iter() {
+            let relative = path.strip_prefix(local_prefix).unwrap();
+            synced.insert(relative.to_path_buf());
             if path.is_file() {
-                let dest = self
-                    .path
-                    .join(remote_prefix.join(path.strip_prefix(local_prefix).unwrap()));
+                let dest = self.path.join(remote_prefix.join(relative));
+                // The copies get the modification time of the original files, thus
+                // the unchanged files are skipped
+                let metadata = fs::metadata(path).await?;
+                if let Ok(current) = fs::metadata(&dest).await {
+                    if !remote::is_changed(
+                        &metadata,
+                        current.len(),
+                        current.modified()?,
+                        remote::RemoteModified::Copied,
+                    ) {
+                        continue;
+                    }
+                }
                 let parent = dest.parent().unwrap();
                 if !parent.exists() {
                     fs::create_dir_all(parent).await?;
                 }
-                fs::copy(path, dest).await?;
+                let size = fs::copy(path, &dest).await?;
+                std::fs::File::options()
+                    .write(true)
+                    .open(&dest)?
+                    .set_modified(metadata.modified()?)?;
+                uploads.push(remote::Upload {
+                    remote_path: remote_path.join(relative),
+                    size,
+                    checksum: None,
+                });
             }
         }
 
-        Ok(())
+        let folder = self.path.join(&remote_prefix);
+        if options.mirror && !single_location && folder.is_dir() {
+            let pattern = folder.join("**").join("*");
+            let entries: Vec<PathBuf> = glob(pattern.to_str().unwrap())
+                .unwrap()
+                .filter_map(Result::ok)
+                .collect();
+            for entry in entries {
+                // The content of a deleted folder is already gone
+                let metadata = match fs::symlink_metadata(&entry).await {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                };
+                let relative = entry.strip_prefix(&folder).unwrap();
+                if remote::is_stale(relative, metadata.is_dir(), local_prefix, &synced, options) {
+                    if metadata.is_dir() {
+                        fs::remove_dir_all(&entry).await?;
+                    } else {
+                        fs::remove_file(&entry).await?;
+                    }
+                    info!(
+                        "Deleted {}, not in {}",
+                        entry.display(),
+                        local_prefix.display()
+                    );
+                }
+            }
+        }
+
+        Ok(uploads)
     }
 
     async fn upload_folder_compressed(
         &self,
-        path: &Path,
+        paths: &[PathBuf],
         remote_path: &Path,
-    ) -> Result<(), remote::Error> {
-        if !path.is_dir() {
+        options: &remote::ArchiveOptions,
+    ) -> Result<remote::Upload, remote::Error> {
+        if !paths.iter().all(|path| path.is_dir()) {
             return Err(remote::Error::NotADirectory);
         }
-        let remote_path = self.remote_archive_path(remote_path);
-        let compressed_folder = self.compress_folder(path).await?;
-        self.upload_file(compressed_folder.path(), &remote_path)
-            .await?;
-        Ok(())
+        let remote_path = self.remote_archive_path(remote_path, options);
+        let (dest, file) = self.create_file(&remote_path).await?;
+
+        let mut writer = remote::HashWriter::new(file);
+        if let Err(error) = self.compress_folder(paths, &mut writer, options).await {
+            tokio::fs::remove_file(dest).await.ok();
+            return Err(error);
+        }
+        Ok(remote::Upload {
+            remote_path,
+            size: writer.size(),
+            checksum: Some(writer.checksum()),
+        })
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use crate::config::Compression;
     use crate::remotes::remote::Remote;
 
     use crate::services::folders::Folder;
     use crate::services::service::Service;
 
+    /// A localhost remote whose enumerate lists like the S3 prefix listing or the SSH
+    /// find: every file whose path starts with the listed path, including the siblings
+    /// sharing the name as prefix and the content of the subfolders.
+    #[derive(Clone)]
+    pub(crate) struct PrefixListing {
+        localhost: Localhost,
+    }
+
+    impl PrefixListing {
+        pub(crate) fn new(path: &Path) -> PrefixListing {
+            let config = LocalhostConfig {
+                path: String::from(path.to_str().unwrap()),
+            };
+            PrefixListing {
+                localhost: Localhost::new(config, "test").unwrap(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl remote::Remote for PrefixListing {
+        fn name(&self) -> String {
+            self.localhost.name()
+        }
+
+        async fn enumerate(&self, remote_path: &Path) -> Result<Vec<String>, remote::Error> {
+            let prefix = remote_path.to_str().unwrap().trim_start_matches('/');
+            let pattern = self.localhost.path.join("**").join("*");
+            Ok(glob(pattern.to_str().unwrap())
+                .unwrap()
+                .map(|path| path.unwrap())
+                .filter(|path| path.is_file())
+                .map(|path| {
+                    let relative = path.strip_prefix(&self.localhost.path).unwrap();
+                    relative.to_str().unwrap().to_string()
+                })
+                .filter(|path| path.starts_with(prefix))
+                .collect())
+        }
+
+        async fn delete(&self, remote_path: &Path) -> Result<(), remote::Error> {
+            self.localhost.delete(remote_path).await
+        }
+
+        async fn download_file(
+            &self,
+            remote_path: &Path,
+            path: &Path,
+        ) -> Result<(), remote::Error> {
+            self.localhost.download_file(remote_path, path).await
+        }
+
+        async fn download_folder(
+            &self,
+            remote_path: &Path,
+            path: &Path,
+        ) -> Result<(), remote::Error> {
+            self.localhost.download_folder(remote_path, path).await
+        }
+
+        async fn upload_file(
+            &self,
+            path: &Path,
+            remote_path: &Path,
+        ) -> Result<remote::Upload, remote::Error> {
+            self.localhost.upload_file(path, remote_path).await
+        }
+
+        async fn upload_file_compressed(
+            &self,
+            path: &Path,
+            remote_path: &Path,
+            options: &remote::ArchiveOptions,
+        ) -> Result<remote::Upload, remote::Error> {
+            self.localhost
+                .upload_file_compressed(path, remote_path, options)
+                .await
+        }
+
+        async fn upload_folder(
+            &self,
+            paths: &[PathBuf],
+            remote_path: &Path,
+            options: &remote::ArchiveOptions,
+        ) -> Result<Vec<remote::Upload>, remote::Error> {
+            self.localhost
+                .upload_folder(paths, remote_path, options)
+                .await
+        }
+
+        async fn upload_folder_compressed(
+            &self,
+            paths: &[PathBuf],
+            remote_path: &Path,
+            options: &remote::ArchiveOptions,
+        ) -> Result<remote::Upload, remote::Error> {
+            self.localhost
+                .upload_folder_compressed(paths, remote_path, options)
+                .await
+        }
+    }
+
     #[tokio::test]
     async fn test_upload_file() {
         let tmp_dir = tempfile::tempdir().unwrap();
@@ -266,7 +518,11 @@ mod tests {
         assert_eq!(localhost.name(), "test_service");
 
         localhost
-            .upload_file_compressed(&PathBuf::from("Cargo.toml"), &PathBuf::from("Cargo.toml"))
+            .upload_file_compressed(
+                &PathBuf::from("Cargo.toml"),
+                &PathBuf::from("Cargo.toml"),
+                &remote::ArchiveOptions::default(),
+            )
             .await
             .unwrap();
 
@@ -278,6 +534,43 @@ mod tests {
         assert!(dest.exists());
     }
 
+    #[tokio::test]
+    async fn test_upload_file_compressed_streamed() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = LocalhostConfig {
+            path: String::from(tmp_dir.path().to_str().unwrap()),
+        };
+        let localhost = Localhost::new(config, "test_service").unwrap();
+
+        let upload = localhost
+            .upload_file_compressed(
+                &PathBuf::from("Cargo.toml"),
+                &PathBuf::from("/backup/Cargo.toml"),
+                &remote::ArchiveOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        // The size and the checksum are computed while streaming the archive
+        let dest = tmp_dir
+            .path()
+            .join(upload.remote_path.strip_prefix("/").unwrap());
+        assert_eq!(upload.size, std::fs::metadata(&dest).unwrap().len());
+        assert_eq!(
+            upload.checksum,
+            Some(remote::checksum(&dest).await.unwrap())
+        );
+
+        let decompressed = tmp_dir.path().join("Cargo.toml");
+        remote::decompress_file(&dest, &decompressed, Compression::Gzip)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read("Cargo.toml").unwrap(),
+            std::fs::read(decompressed).unwrap()
+        );
+    }
+
     #[tokio::test]
     async fn test_upload_folder() {
         let tmp_dir = tempfile::tempdir().unwrap();
@@ -304,7 +597,11 @@ mod tests {
         let files = folder.list().await;
 
         localhost
-            .upload_folder(&files, &PathBuf::from("/"))
+            .upload_folder(
+                &files,
+                &PathBuf::from("/"),
+                &remote::ArchiveOptions::default(),
+            )
             .await
             .unwrap();
 
@@ -313,6 +610,169 @@ mod tests {
         assert!(tmp_dir.path().join("lib.rs").exists());
     }
 
+    #[tokio::test]
+    async fn test_upload_folder_incremental_mirror() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let local_dir = tempfile::tempdir().unwrap();
+        let localhost = Localhost::new(
+            LocalhostConfig {
+                path: String::from(remote_dir.path().to_str().unwrap()),
+            },
+            "test_service",
+        )
+        .unwrap();
+        for path in ["a", "b/c", "b/d", "e.log", "f"] {
+            let path = local_dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "bacup").unwrap();
+        }
+        let folder = Folder::new(local_dir.path().to_str().unwrap())
+            .await
+            .unwrap();
+        let mut options = remote::ArchiveOptions::default();
+        let remote_path = PathBuf::from("/backup");
+
+        let uploads = localhost
+            .upload_folder(&folder.list().await, &remote_path, &options)
+            .await
+            .unwrap();
+        assert_eq!(uploads.len(), 5);
+
+        // Only the changed files are copied
+        std::fs::write(local_dir.path().join("a"), "changed").unwrap();
+        let uploads = localhost
+            .upload_folder(&folder.list().await, &remote_path, &options)
+            .await
+            .unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].remote_path, remote_path.join("a"));
+        let backup = remote_dir.path().join("backup");
+        assert_eq!(
+            std::fs::read_to_string(backup.join("a")).unwrap(),
+            "changed"
+        );
+
+        // A file replaced with an older version of the same size is copied again
+        let older = std::fs::metadata(local_dir.path().join("a"))
+            .unwrap()
+            .modified()
+            .unwrap()
+            - std::time::Duration::from_secs(3600);
+        std::fs::write(local_dir.path().join("a"), "restore").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(local_dir.path().join("a"))
+            .unwrap()
+            .set_modified(older)
+            .unwrap();
+        let uploads = localhost
+            .upload_folder(&folder.list().await, &remote_path, &options)
+            .await
+            .unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(
+            std::fs::read_to_string(backup.join("a")).unwrap(),
+            "restore"
+        );
+
+        // The deleted files are kept, unless mirroring. The excluded ones are always kept
+        std::fs::remove_dir_all(local_dir.path().join("b")).unwrap();
+        std::fs::write(local_dir.path().join("e.log"), "excluded").unwrap();
+        localhost
+            .upload_folder(&folder.list().await, &remote_path, &options)
+            .await
+            .unwrap();
+        assert!(backup.join("b").join("c").exists());
+
+        let folder = folder
+            .with_exclude(&[String::from("*.log")], false)
+            .unwrap();
+        options.mirror = true;
+        options.filter = folder.filter();
+        let uploads = localhost
+            .upload_folder(&folder.list().await, &remote_path, &options)
+            .await
+            .unwrap();
+        assert!(uploads.is_empty());
+        assert!(!backup.join("b").exists());
+        assert_eq!(
+            std::fs::read_to_string(backup.join("e.log")).unwrap(),
+            "excluded"
+        );
+        assert!(backup.join("a").exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_file() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let local_dir = tempfile::tempdir().unwrap();
+        let config = LocalhostConfig {
+            path: String::from(remote_dir.path().to_str().unwrap()),
+        };
+        let localhost = Localhost::new(config, "test_service").unwrap();
+
+        localhost
+            .upload_file(
+                &PathBuf::from("Cargo.toml"),
+                &PathBuf::from("/remote/Cargo.toml"),
+            )
+            .await
+            .unwrap();
+
+        let dest = local_dir.path().join("restored").join("Cargo.toml");
+        localhost
+            .download_file(&PathBuf::from("/remote/Cargo.toml"), &dest)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read("Cargo.toml").unwrap(),
+            std::fs::read(dest).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_folder() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let local_dir = tempfile::tempdir().unwrap();
+        let config = LocalhostConfig {
+            path: String::from(remote_dir.path().to_str().unwrap()),
+        };
+        let localhost = Localhost::new(config, "test_service").unwrap();
+
+        let folder = Folder::new(
+            std::env::current_dir()
+                .unwrap()
+                .join("src")
+                .to_str()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        let files = folder.list().await;
+
+        localhost
+            .upload_folder(
+                &files,
+                &PathBuf::from("/src"),
+                &remote::ArchiveOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        localhost
+            .download_folder(&PathBuf::from("/src"), local_dir.path())
+            .await
+            .unwrap();
+
+        assert!(local_dir
+            .path()
+            .join("remotes")
+            .join("localhost.rs")
+            .exists());
+        assert!(local_dir.path().join("lib.rs").exists());
+    }
+
     #[tokio::test]
     async fn test_upload_folder_compressed() {
         let tmp_dir = tempfile::tempdir().unwrap();
@@ -324,8 +784,9 @@ mod tests {
         let remote_filename = "remote_archive_name";
         localhost
             .upload_folder_compressed(
-                &std::env::current_dir().unwrap().join("src"),
+                &[std::env::current_dir().unwrap().join("src")],
                 &PathBuf::from(remote_filename),
+                &remote::ArchiveOptions::default(),
             )
             .await
             .unwrap();
@@ -339,4 +800,42 @@ mod tests {
 
         assert!(dest.exists());
     }
+
+    #[tokio::test]
+    async fn test_upload_folder_compressed_filter() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = LocalhostConfig {
+            path: String::from(tmp_dir.path().to_str().unwrap()),
+        };
+        let localhost = Localhost::new(config, "test_service").unwrap();
+
+        let src = std::env::current_dir().unwrap().join("src");
+        let options = remote::ArchiveOptions {
+            filter: Some(
+                crate::filter::Filter::new(&src, &[String::from("remotes/")], false).unwrap(),
+            ),
+            ..Default::default()
+        };
+        let upload = localhost
+            .upload_folder_compressed(std::slice::from_ref(&src), &PathBuf::from("src"), &options)
+            .await
+            .unwrap();
+
+        let out_dir = tempfile::tempdir().unwrap();
+        remote::decompress_folder(
+            &tmp_dir.path().join(upload.remote_path.file_name().unwrap()),
+            out_dir.path(),
+            Compression::Gzip,
+        )
+        .await
+        .unwrap();
+        assert!(out_dir.path().join("src").join("lib.rs").exists());
+        assert!(out_dir
+            .path()
+            .join("src")
+            .join("services")
+            .join("folders.rs")
+            .exists());
+        assert!(!out_dir.path().join("src").join("remotes").exists());
+    }
 }
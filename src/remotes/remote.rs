@@ -14,23 +14,32 @@
 
 use async_trait::async_trait;
 
+use std::collections::HashSet;
 use std::fmt;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::string::String;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
 
 use chrono::DateTime;
 use chrono::Utc;
 
-use async_compression::tokio::write::GzipEncoder;
+use async_compression::tokio::bufread::{GzipDecoder, XzDecoder, ZstdDecoder};
+use async_compression::tokio::write::{GzipEncoder, XzEncoder, ZstdEncoder};
+use async_compression::Level;
 
 use dyn_clone::DynClone;
 
+use crate::config::Compression;
+use crate::encryption;
+use crate::filter::Filter;
 use crate::remotes::aws::Error as AWSError;
 
-use tempfile::NamedTempFile;
+use sha2::{Digest, Sha256};
 
 use tokio::fs;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 
 use log::info;
 
@@ -38,7 +47,12 @@ use log::info;
 pub enum Error {
     LocalError(std::io::Error),
     RemoteError(AWSError),
+    /// An external command (ssh, rsync, git) failed
+    CommandFailed(String),
+    /// An external command (ssh, rsync) was unable to reach the remote host
+    ConnectionFailed(String),
     CompressionError,
+    EncryptionError(Box<encryption::Error>),
     NotADirectory,
 }
 
@@ -54,74 +68,262 @@ impl From<AWSError> for Error {
     }
 }
 
+impl From<encryption::Error> for Error {
+    fn from(error: encryption::Error) -> Self {
+        Error::EncryptionError(Box::new(error))
+    }
+}
+
 impl std::error::Error for Error {}
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::LocalError(error) => write!(f, "Local (IO) error: {}", error),
             Error::CompressionError => write!(f, "Unable to compress the file/folder"),
+            Error::EncryptionError(error) => write!(f, "{}", error),
             Error::NotADirectory => write!(f, "The specified file is not a directory"),
             Error::RemoteError(error) => write!(f, "Remote error: {}", error),
+            Error::CommandFailed(msg) => write!(f, "Command failed: {}", msg),
+            Error::ConnectionFailed(msg) => write!(f, "Connection failed: {}", msg),
+        }
+    }
+}
+
+impl Error {
+    /// Returns true when the error is likely to be temporary (network failures, timeouts,
+    /// throttling) and thus the operation that caused it can be retried.
+    pub fn is_transient(&self) -> bool {
+        use aws_sdk_s3::error::ProvideErrorMetadata;
+        use std::io::ErrorKind;
+
+        match self {
+            Error::LocalError(error) => matches!(
+                error.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::NotConnected
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::TimedOut
+                    | ErrorKind::Interrupted
+                    | ErrorKind::UnexpectedEof
+            ),
+            // Errors without a code are raised before getting a response
+            // (e.g. connection errors, timeouts)
+            Error::RemoteError(error) => match error.code() {
+                None => true,
+                Some(code) => matches!(
+                    code,
+                    "InternalError"
+                        | "ServiceUnavailable"
+                        | "SlowDown"
+                        | "RequestTimeout"
+                        | "Throttling"
+                        | "ThrottlingException"
+                ),
+            },
+            Error::ConnectionFailed(_) => true,
+            Error::CommandFailed(_)
+            | Error::CompressionError
+            | Error::EncryptionError(_)
+            | Error::NotADirectory => false,
+        }
+    }
+}
+
+/// An object written on the remote by one of the upload methods.
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub remote_path: PathBuf,
+    pub size: u64,
+    /// SHA-256 of the uploaded content. Not computed for the files synced by upload_folder.
+    pub checksum: Option<String>,
+}
+
+/// How the compressed archives are created by the upload_*_compressed methods,
+/// and which files of a folder are uploaded.
+#[derive(Clone, Default)]
+pub struct ArchiveOptions {
+    /// When set, the archives are encrypted with age for this recipient after the
+    /// compression, and their name gets the .age suffix.
+    pub recipient: Option<age::x25519::Recipient>,
+    /// When set, the excluded paths are skipped by compress_folder and upload_folder.
+    pub filter: Option<Filter>,
+    /// When set, compress_folder archives only these paths (as listed by the service)
+    /// of the folders, instead of their whole content.
+    pub files: Option<Vec<PathBuf>>,
+    /// Codec of the archives.
+    pub compression: Compression,
+    /// Level of the codec. When None, the default level of the codec is used.
+    pub level: Option<i32>,
+    /// When true, upload_folder deletes from the remote folder the files and folders
+    /// that are not in the uploaded paths (but the excluded ones), like rsync --delete.
+    pub mirror: bool,
+}
+
+impl ArchiveOptions {
+    /// Returns the file extension of the archives of a folder (.tar followed by the
+    /// codec extension) or of a file (the codec extension only), followed by the
+    /// encryption suffix, if any.
+    pub fn extension(&self, folder: bool) -> String {
+        let mut extension = String::new();
+        if folder {
+            extension.push_str(".tar");
+        }
+        extension.push_str(self.compression.extension());
+        if self.recipient.is_some() {
+            extension.push_str(encryption::SUFFIX);
+        }
+        extension
+    }
+
+    /// Wraps `output` in the encoder of the configured codec.
+    fn encoder<'a, W>(&self, output: W) -> Box<dyn AsyncWrite + Unpin + Send + 'a>
+    where
+        W: AsyncWrite + Unpin + Send + 'a,
+    {
+        let level = match self.level {
+            Some(level) => Level::Precise(level),
+            None => Level::Default,
+        };
+        match self.compression {
+            Compression::Gzip => Box::new(GzipEncoder::with_quality(output, level)),
+            Compression::Zstd => Box::new(ZstdEncoder::with_quality(output, level)),
+            Compression::Xz => Box::new(XzEncoder::with_quality(output, level)),
+            Compression::None => Box::new(output),
+        }
+    }
+}
+
+impl Compression {
+    /// Extension of the files compressed with the codec, empty for None.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+            Compression::Xz => ".xz",
+            Compression::None => "",
+        }
+    }
+
+    /// Returns the codec of the file `name` (without the encryption suffix),
+    /// from its extension. None when the extension is not a codec one.
+    pub fn from_name(name: &str) -> Compression {
+        [Compression::Gzip, Compression::Zstd, Compression::Xz]
+            .iter()
+            .copied()
+            .find(|compression| name.ends_with(compression.extension()))
+            .unwrap_or(Compression::None)
+    }
+
+    /// Valid levels of the codec. None when the codec has no levels.
+    pub fn levels(&self) -> Option<std::ops::RangeInclusive<i32>> {
+        match self {
+            Compression::Gzip | Compression::Xz => Some(0..=9),
+            Compression::Zstd => Some(1..=22),
+            Compression::None => None,
+        }
+    }
+
+    /// Wraps `input` in the decoder of the codec.
+    fn decoder<'a, R>(&self, input: R) -> Box<dyn AsyncRead + Unpin + Send + 'a>
+    where
+        R: AsyncBufRead + Unpin + Send + 'a,
+    {
+        match self {
+            Compression::Gzip => Box::new(GzipDecoder::new(input)),
+            Compression::Zstd => Box::new(ZstdDecoder::new(input)),
+            Compression::Xz => Box::new(XzDecoder::new(input)),
+            Compression::None => Box::new(input),
         }
     }
 }
 
 #[async_trait]
 pub trait Remote: DynClone + Send + Sync {
-    async fn upload_file(&self, path: &Path, remote_path: &Path) -> Result<(), Error>;
-    async fn upload_folder(&self, paths: &[PathBuf], remote_path: &Path) -> Result<(), Error>;
-    async fn upload_file_compressed(&self, path: &Path, remote_path: &Path) -> Result<(), Error>;
-    async fn upload_folder_compressed(&self, path: &Path, remote_path: &Path) -> Result<(), Error>;
+    async fn upload_file(&self, path: &Path, remote_path: &Path) -> Result<Upload, Error>;
+    async fn upload_folder(
+        &self,
+        paths: &[PathBuf],
+        remote_path: &Path,
+        options: &ArchiveOptions,
+    ) -> Result<Vec<Upload>, Error>;
+    async fn upload_file_compressed(
+        &self,
+        path: &Path,
+        remote_path: &Path,
+        options: &ArchiveOptions,
+    ) -> Result<Upload, Error>;
+    /// Uploads a single archive of the folders `paths`. Each folder is a top-level
+    /// folder of the archive, named after it.
+    async fn upload_folder_compressed(
+        &self,
+        paths: &[PathBuf],
+        remote_path: &Path,
+        options: &ArchiveOptions,
+    ) -> Result<Upload, Error>;
     async fn enumerate(&self, remote_path: &Path) -> Result<Vec<String>, Error>;
     async fn delete(&self, remote_path: &Path) -> Result<(), Error>;
+    async fn download_file(&self, remote_path: &Path, path: &Path) -> Result<(), Error>;
+    async fn download_folder(&self, remote_path: &Path, path: &Path) -> Result<(), Error>;
 
     fn name(&self) -> String;
 
-    async fn compress_folder(&self, path: &Path) -> Result<NamedTempFile, Error>
+    /// Writes the compressed tar archive of the folders `paths` into `output`, without the paths
+    /// excluded by the filter and encrypting it when required by `options`.
+    /// The archive is streamed, never kept in memory.
+    async fn compress_folder<W>(
+        &self,
+        paths: &[PathBuf],
+        output: W,
+        options: &ArchiveOptions,
+    ) -> Result<(), Error>
     where
         Self: Sized,
+        W: AsyncWrite + Unpin + Send,
     {
-        info!("Compressing folder {}", path.display());
-        let archive_path = NamedTempFile::new()?;
-
-        let file = fs::File::create(&archive_path).await?;
-        let encoder = GzipEncoder::new(file);
-
-        let mut builder = tokio_tar::Builder::new(encoder);
-        builder
-            .append_dir_all(path.file_name().unwrap(), path)
-            .await?;
-
-        let mut encoder = builder.into_inner().await?;
-        encoder.flush().await?;
-        encoder.shutdown().await?;
-        info!("Compression of folder {} done.", path.display());
-        Ok(archive_path)
+        let display = paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        info!("Compressing folder {}", display);
+        match &options.recipient {
+            Some(recipient) => {
+                let output = encryption::writer(output, recipient).await?;
+                tar(paths, options.encoder(output), options).await?
+            }
+            None => tar(paths, options.encoder(output), options).await?,
+        }
+        info!("Compression of folder {} done.", display);
+        Ok(())
     }
 
-    async fn compress_file(&self, path: &Path) -> Result<Vec<u8>, Error>
+    /// Writes the compressed file `path` into `output`, encrypting it when required
+    /// by `options`. The file is streamed, never kept in memory.
+    async fn compress_file<W>(
+        &self,
+        path: &Path,
+        output: W,
+        options: &ArchiveOptions,
+    ) -> Result<(), Error>
     where
         Self: Sized,
+        W: AsyncWrite + Unpin + Send,
     {
         info!("Compressing file {}...", path.display());
-        let mut content: Vec<u8> = vec![];
-        let mut file = match fs::File::open(path).await {
-            Ok(file) => file,
-            Err(error) => return Err(Error::LocalError(error)),
-        };
-
-        file.read_to_end(&mut content).await?;
-
-        let mut e = GzipEncoder::new(Vec::new());
-        e.write_all(&content).await?;
-        e.flush().await?;
-        e.shutdown().await?;
-
+        match &options.recipient {
+            Some(recipient) => {
+                let output = encryption::writer(output, recipient).await?;
+                copy(path, options.encoder(output)).await?
+            }
+            None => copy(path, options.encoder(output)).await?,
+        }
         info!("Compression of file {} done.", path.display());
-        Ok(e.into_inner())
+        Ok(())
     }
 
-    fn remote_archive_path(&self, remote_path: &Path) -> PathBuf {
+    fn remote_archive_path(&self, remote_path: &Path, options: &ArchiveOptions) -> PathBuf {
         let now: DateTime<Utc> = Utc::now();
         let parent = match remote_path.parent() {
             Some(path) => path.to_path_buf(),
@@ -129,13 +331,14 @@ pub trait Remote: DynClone + Send + Sync {
         };
 
         parent.join(format!(
-            "{}-{}.tar.gz",
+            "{}-{}{}",
             now.format("%Y-%m-%d-%H.%M"),
-            remote_path.file_name().unwrap().to_str().unwrap()
+            remote_path.file_name().unwrap().to_str().unwrap(),
+            options.extension(true)
         ))
     }
 
-    fn remote_compressed_file_path(&self, remote_path: &Path) -> PathBuf {
+    fn remote_compressed_file_path(&self, remote_path: &Path, options: &ArchiveOptions) -> PathBuf {
         let now: DateTime<Utc> = Utc::now();
         let parent = match remote_path.parent() {
             Some(path) => path.to_path_buf(),
@@ -143,9 +346,223 @@ pub trait Remote: DynClone + Send + Sync {
         };
 
         parent.join(format!(
-            "{}-{}.gz",
+            "{}-{}{}",
             now.format("%Y-%m-%d-%H.%M"),
-            remote_path.file_name().unwrap().to_str().unwrap()
+            remote_path.file_name().unwrap().to_str().unwrap(),
+            options.extension(false)
         ))
     }
 }
+
+/// Writes the tar archive of the folders `paths` into `output` (the encoder), with only
+/// the files of `options` when set, or without the paths excluded by its filter.
+async fn tar<W>(
+    paths: &[PathBuf],
+    output: W,
+    options: &ArchiveOptions,
+) -> Result<(), std::io::Error>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    // into_inner terminates the archive: new would require a 'static writer
+    let mut builder = tokio_tar::Builder::new_non_terminated(output);
+    for path in paths {
+        let name = Path::new(path.file_name().unwrap());
+        let included = match (&options.files, &options.filter) {
+            (Some(files), _) => Some(
+                files
+                    .iter()
+                    .filter(|file| file.starts_with(path) && *file != path)
+                    .cloned()
+                    .collect::<Vec<PathBuf>>(),
+            ),
+            (None, Some(filter)) => Some(filter.walk(path).await?.included),
+            (None, None) => None,
+        };
+        match included {
+            Some(included) => {
+                builder.append_dir(name, path).await?;
+                for entry in included {
+                    let entry_name = name.join(entry.strip_prefix(path).unwrap());
+                    if entry.is_dir() {
+                        builder.append_dir(entry_name, &entry).await?;
+                    } else {
+                        builder.append_path_with_name(&entry, entry_name).await?;
+                    }
+                }
+            }
+            None => builder.append_dir_all(name, path).await?,
+        }
+    }
+
+    let mut encoder = builder.into_inner().await?;
+    encoder.flush().await?;
+    encoder.shutdown().await
+}
+
+/// Copies the file `path` into `output` (the encoder).
+async fn copy<W>(path: &Path, mut encoder: W) -> Result<(), std::io::Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut file = fs::File::open(path).await?;
+    tokio::io::copy(&mut file, &mut encoder).await?;
+    encoder.flush().await?;
+    encoder.shutdown().await
+}
+
+/// Writer forwarding everything to the inner writer, while counting the written
+/// bytes and computing their SHA-256. Used to describe the streamed uploads.
+pub struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: AsyncWrite + Unpin> HashWriter<W> {
+    pub fn new(inner: W) -> HashWriter<W> {
+        HashWriter {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn checksum(&self) -> String {
+        format!("{:x}", self.hasher.clone().finalize())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            self.hasher.update(&buf[..written]);
+            self.size += written as u64;
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// What the modification time of a remote file is, for comparing it with the local one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RemoteModified {
+    /// The time of the upload (e.g. the S3 LastModified): the file changed if the local
+    /// one has been modified after it.
+    Uploaded,
+    /// The modification time of the local file, copied on the remote one: the file
+    /// changed if the times differ, like the rsync quick check. A file replaced with
+    /// an older version is uploaded again.
+    Copied,
+}
+
+/// Returns true if the local file, described by `local`, differs from the remote file
+/// with size `remote_size` and modification time `remote_modified`: upload_folder
+/// uploads only the changed files.
+pub fn is_changed(
+    local: &std::fs::Metadata,
+    remote_size: u64,
+    remote_modified: SystemTime,
+    mode: RemoteModified,
+) -> bool {
+    match local.modified() {
+        Ok(modified) => {
+            local.len() != remote_size
+                || match mode {
+                    RemoteModified::Uploaded => modified > remote_modified,
+                    RemoteModified::Copied => modified != remote_modified,
+                }
+        }
+        Err(_) => true,
+    }
+}
+
+/// Returns true if the object `relative` (to the remote folder) has to be deleted by a
+/// mirroring upload_folder: it's not among the `synced` paths (relative to `local_prefix`),
+/// nor excluded by the filter of `options`.
+pub fn is_stale(
+    relative: &Path,
+    is_dir: bool,
+    local_prefix: &Path,
+    synced: &HashSet<PathBuf>,
+    options: &ArchiveOptions,
+) -> bool {
+    if synced.contains(relative) {
+        return false;
+    }
+    match &options.filter {
+        Some(filter) => !filter.is_excluded(&local_prefix.join(relative), is_dir),
+        None => true,
+    }
+}
+
+/// Computes the SHA-256 of the file content, reading it in chunks.
+pub async fn checksum(path: &Path) -> Result<String, Error> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Decompresses the file `path` (created by `Remote::compress_file` with `compression`)
+/// into `dest`.
+pub async fn decompress_file(
+    path: &Path,
+    dest: &Path,
+    compression: Compression,
+) -> Result<(), Error> {
+    info!("Decompressing file {}...", path.display());
+    let file = fs::File::open(path).await?;
+    let mut decoder = compression.decoder(BufReader::new(file));
+
+    let mut out = fs::File::create(dest).await?;
+    tokio::io::copy(&mut decoder, &mut out).await?;
+    out.flush().await?;
+
+    info!("Decompression of file {} done.", path.display());
+    Ok(())
+}
+
+/// Extracts the archive `path` (created by `Remote::compress_folder` with `compression`)
+/// into the `dest` folder.
+pub async fn decompress_folder(
+    path: &Path,
+    dest: &Path,
+    compression: Compression,
+) -> Result<(), Error> {
+    info!("Decompressing archive {}...", path.display());
+    if !dest.is_dir() {
+        return Err(Error::NotADirectory);
+    }
+    let file = fs::File::open(path).await?;
+    let decoder = compression.decoder(BufReader::new(file));
+
+    let mut archive = tokio_tar::Archive::new(decoder);
+    archive.unpack(dest).await?;
+
+    info!("Decompression of archive {} done.", path.display());
+    Ok(())
+}
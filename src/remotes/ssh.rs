@@ -16,8 +16,6 @@ use crate::config::SshConfig;
 use crate::remotes::remote;
 
 use std::io;
-use std::io::prelude::*;
-use std::io::Write;
 
 use std::iter::once;
 use std::path::{Path, PathBuf};
@@ -29,11 +27,11 @@ use log::warn;
 
 use tokio::fs;
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
 
 use async_trait::async_trait;
 
-use std::process::{Command, Stdio};
+use std::process::{Command, ExitStatus, Stdio};
 use which::which;
 
 #[derive(Debug)]
@@ -168,6 +166,58 @@ impl Ssh {
             ssh_args,
         })
     }
+
+    /// Spawns ssh -Pxxx user@host "cat > 'remote_path'": everything written into the
+    /// stdin of the returned process is streamed into the remote file.
+    fn cat_into(&self, remote_path: &Path) -> io::Result<tokio::process::Child> {
+        tokio::process::Command::new(&self.ssh_cmd)
+            .args(self.ssh_args.iter().chain(once(&format!(
+                "mkdir -p {} && cat > {}",
+                shell_quote(remote_path.parent().unwrap_or(Path::new("."))),
+                shell_quote(remote_path)
+            ))))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+    }
+
+    /// Closes the stdin of the process spawned by cat_into and waits for its termination.
+    /// `written` is the outcome of the writes into the stdin: when they failed, the
+    /// partially written remote file is removed.
+    async fn wait_upload(
+        &self,
+        ssh: tokio::process::Child,
+        stdin: remote::HashWriter<tokio::process::ChildStdin>,
+        written: Result<(), remote::Error>,
+        remote_path: &Path,
+    ) -> Result<remote::Upload, remote::Error> {
+        let size = stdin.size();
+        let checksum = stdin.checksum();
+        // Close stdin for being 100% sure that the process read all the file
+        drop(stdin);
+
+        let output = ssh.wait_with_output().await?;
+        if !output.status.success() {
+            let message = format!(
+                "Failure while executing ssh command.\n\
+                Stderr: {}\nStdout: {}",
+                String::from_utf8_lossy(&output.stderr),
+                String::from_utf8_lossy(&output.stdout)
+            );
+            return Err(ssh_error(output.status, message));
+        }
+        if let Err(error) = written {
+            remote::Remote::delete(self, remote_path).await.ok();
+            return Err(error);
+        }
+
+        Ok(remote::Upload {
+            remote_path: remote_path.to_path_buf(),
+            size,
+            checksum: Some(checksum),
+        })
+    }
 }
 
 #[async_trait]
@@ -177,46 +227,41 @@ impl remote::Remote for Ssh {
     }
 
     async fn enumerate(&self, remote_path: &Path) -> Result<Vec<String>, remote::Error> {
-        let remote_path = remote_path.to_str().unwrap();
-        // ssh -Pxxx user@host "find remote_path/*"
+        // ssh -Pxxx user@host "find 'remote_path'/*"
         // use find path/* instead of ls path
         // because find returns the fullpath
         // the /* is needed to return the content
         // and not the path itself
-        let mut ssh = Command::new(&self.ssh_cmd)
+        let output = Command::new(&self.ssh_cmd)
             .args(
                 self.ssh_args
                     .iter()
-                    .chain(once(&format!("find {}/*", remote_path))),
+                    .chain(once(&format!("find {}/*", shell_quote(remote_path)))),
             )
             .stdin(Stdio::null())
-            .stdout(Stdio::piped())
             .stderr(Stdio::null())
-            .spawn()?;
-
-        let status = ssh.wait()?;
+            .output()?;
 
-        if status.success() {
-            let stdout = ssh.stdout.as_mut().unwrap();
-            let mut output = String::new();
-            stdout.read_to_string(&mut output).unwrap();
-            return Ok(output.split_whitespace().map(|s| s.to_string()).collect());
+        if output.status.success() {
+            return Ok(String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(|s| s.to_string())
+                .collect());
         }
 
-        Err(remote::Error::LocalError(io::Error::other(format!(
-            "Error during ls {} on remote host",
-            remote_path
-        ))))
+        Err(ssh_error(
+            output.status,
+            format!("Error during ls {} on remote host", remote_path.display()),
+        ))
     }
 
     async fn delete(&self, remote_path: &Path) -> Result<(), remote::Error> {
-        let remote_path = remote_path.to_str().unwrap();
-        // ssh -Pxxx user@host "rm -r remote_path"
+        // ssh -Pxxx user@host "rm -r 'remote_path'"
         let mut ssh = Command::new(&self.ssh_cmd)
             .args(
                 self.ssh_args
                     .iter()
-                    .chain(once(&format!("rm -r {}", remote_path))),
+                    .chain(once(&format!("rm -r {}", shell_quote(remote_path)))),
             )
             .stdin(Stdio::null())
             .stdout(Stdio::null())
@@ -229,93 +274,115 @@ impl remote::Remote for Ssh {
             return Ok(());
         }
 
-        Err(remote::Error::LocalError(io::Error::other(format!(
-            "Error during rm -r {} on remote host",
-            remote_path
-        ))))
+        Err(ssh_error(
+            status,
+            format!(
+                "Error during rm -r {} on remote host",
+                remote_path.display()
+            ),
+        ))
     }
 
-    async fn upload_file(&self, path: &Path, remote_path: &Path) -> Result<(), remote::Error> {
-        // Read file
-        let mut content: Vec<u8> = vec![];
-        let mut file = File::open(path).await?;
-        file.read_to_end(&mut content).await?;
-        let remote_path = remote_path.to_str().unwrap();
+    async fn download_file(&self, remote_path: &Path, path: &Path) -> Result<(), remote::Error> {
+        let file = std::fs::File::create(path)?;
 
-        // cat file | ssh -Pxxx user@host "cat > file"
-        let mut ssh = Command::new(&self.ssh_cmd)
+        // ssh -Pxxx user@host "cat 'remote_path'" > file
+        let ssh = Command::new(&self.ssh_cmd)
             .args(
                 self.ssh_args
                     .iter()
-                    .chain(once(&format!("cat > {}", remote_path))),
+                    .chain(once(&format!("cat {}", shell_quote(remote_path)))),
             )
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
+            .stdin(Stdio::null())
+            .stdout(Stdio::from(file))
             .stderr(Stdio::piped())
             .spawn()?;
 
-        {
-            let stdin = ssh.stdin.as_mut().unwrap();
-            // This is the "cat file" on localhost piped into ssh
-            // when stdin is dropped
-            stdin.write_all(&content)?;
-        }
-        // Close stdin for being 100% sure that the process read all the file
-
-        let status = ssh.wait()?;
-
-        if !status.success() {
-            let stdout = ssh.stdout.as_mut().unwrap();
-            let stderr = ssh.stderr.as_mut().unwrap();
-            let mut errlog = String::new();
-            stderr.read_to_string(&mut errlog).unwrap();
-            let mut outlog = String::new();
-            stdout.read_to_string(&mut outlog).unwrap();
+        // stderr is read while waiting, a full pipe would block the remote command
+        let output = ssh.wait_with_output()?;
 
+        if !output.status.success() {
             let message = format!(
                 "Failure while executing ssh command.\n\
-                Stderr: {}\nStdout: {}",
-                errlog, outlog
+                Stderr: {}",
+                String::from_utf8_lossy(&output.stderr)
             );
-            return Err(remote::Error::LocalError(io::Error::other(message)));
+            return Err(ssh_error(output.status, message));
         }
         Ok(())
     }
 
-    async fn upload_file_compressed(
-        &self,
-        path: &Path,
-        remote_path: &Path,
-    ) -> Result<(), remote::Error> {
-        // Read and compress
-        let compressed_bytes = self.compress_file(path).await?;
-        let remote_path = self.remote_compressed_file_path(remote_path);
+    async fn download_folder(&self, remote_path: &Path, path: &Path) -> Result<(), remote::Error> {
+        // The trailing / makes rsync copy the content of the remote folder
+        // and not the folder itself
+        let src = format!(
+            "{}@{}:{}/",
+            self.config.username,
+            self.config.host,
+            remote_path.to_str().unwrap().trim_end_matches('/')
+        );
+        let dest = path.to_str().unwrap();
+        let ssh_port_opt = format!(r#"ssh -p {}"#, self.config.port);
+        // rsync -az -e "ssh -p port" user@host:remote_path/ /local/folder
+        let args = vec!["-az", "-e", &ssh_port_opt, &src, dest];
 
-        // cat file | ssh -Pxxx user@host "cat > file"
-        let mut ssh = Command::new(&self.ssh_cmd)
-            .stdin(Stdio::piped())
+        let status = Command::new(&self.rsync_cmd)
+            .stderr(Stdio::null())
             .stdout(Stdio::null())
-            .args(
-                self.ssh_args
-                    .iter()
-                    .chain(once(&format!("cat > {} ", remote_path.display()))),
-            )
-            .spawn()?;
-        ssh.stdin.as_mut().unwrap().write_all(&compressed_bytes)?;
-        let status = ssh.wait()?;
+            .args(&args)
+            .status()?;
+
         if !status.success() {
-            return Err(remote::Error::LocalError(io::Error::other(
-                "Failure while executing ssh command",
-            )));
+            return Err(rsync_error(
+                status,
+                String::from("Failed to execute rsync trought ssh command"),
+            ));
         }
+
         Ok(())
     }
 
+    async fn upload_file(
+        &self,
+        path: &Path,
+        remote_path: &Path,
+    ) -> Result<remote::Upload, remote::Error> {
+        // cat file | ssh -Pxxx user@host "cat > file"
+        let mut ssh = self.cat_into(remote_path)?;
+        let mut stdin = remote::HashWriter::new(ssh.stdin.take().unwrap());
+        let written = async {
+            let mut file = File::open(path).await?;
+            tokio::io::copy(&mut file, &mut stdin).await?;
+            stdin.shutdown().await
+        }
+        .await
+        .map_err(remote::Error::from);
+
+        self.wait_upload(ssh, stdin, written, remote_path).await
+    }
+
+    async fn upload_file_compressed(
+        &self,
+        path: &Path,
+        remote_path: &Path,
+        options: &remote::ArchiveOptions,
+    ) -> Result<remote::Upload, remote::Error> {
+        let remote_path = self.remote_compressed_file_path(remote_path, options);
+
+        // compress file | ssh -Pxxx user@host "cat > file"
+        let mut ssh = self.cat_into(&remote_path)?;
+        let mut stdin = remote::HashWriter::new(ssh.stdin.take().unwrap());
+        let written = self.compress_file(path, &mut stdin, options).await;
+
+        self.wait_upload(ssh, stdin, written, &remote_path).await
+    }
+
     async fn upload_folder(
         &self,
         paths: &[PathBuf],
         remote_path: &Path,
-    ) -> Result<(), remote::Error> {
+        options: &remote::ArchiveOptions,
+    ) -> Result<Vec<remote::Upload>, remote::Error> {
         let mut local_prefix = paths.iter().min_by(|a, b| a.cmp(b)).unwrap();
         // The local_prefix found is:
         // In case of a folder: the shortest path inside the folder we want to backup.
@@ -328,6 +395,7 @@ impl remote::Remote for Ssh {
             local_prefix = &parent;
         }
 
+        let remote_prefix = remote_path;
         let remote_path = remote_path.to_str().unwrap();
         let dest = format!(
             "{}@{}:{}",
@@ -337,7 +405,29 @@ impl remote::Remote for Ssh {
         let ssh_port_opt = format!(r#"ssh -p {}"#, self.config.port);
         // rsync -az -e "ssh -p port" /local/folder user@host:remote_path --delete
         // delete is used to remove from remote and keep it in sync with local
-        let args = vec!["-az", "-e", &ssh_port_opt, src, &dest, "--delete"];
+        let mut args: Vec<String> = vec![
+            String::from("-az"),
+            String::from("-e"),
+            ssh_port_opt,
+            String::from(src),
+            dest,
+            String::from("--delete"),
+        ];
+
+        // rsync copies the local_prefix itself (not only its content) inside the remote path
+        let rsync_root = local_prefix.parent().unwrap_or(local_prefix);
+
+        // The excluded paths are anchored to the rsync root, thus only them are skipped
+        if let Some(filter) = &options.filter {
+            if local_prefix.is_dir() {
+                for path in filter.walk(local_prefix).await?.excluded {
+                    args.push(format!(
+                        "--exclude=/{}",
+                        rsync_pattern(path.strip_prefix(rsync_root).unwrap())
+                    ));
+                }
+            }
+        }
 
         let status = Command::new(&self.rsync_cmd)
             .stderr(Stdio::null())
@@ -346,27 +436,130 @@ impl remote::Remote for Ssh {
             .status()?;
 
         if !status.success() {
-            return Err(remote::Error::LocalError(io::Error::other(
-                "Failed to execute rsync trought ssh command",
-            )));
+            return Err(rsync_error(
+                status,
+                String::from("Failed to execute rsync trought ssh command"),
+            ));
         }
 
-        Ok(())
+        let mut uploads = vec![];
+        for path in paths.iter() {
+            if path.is_file() {
+                uploads.push(remote::Upload {
+                    remote_path: remote_prefix.join(path.strip_prefix(rsync_root).unwrap()),
+                    size: fs::metadata(path).await?.len(),
+                    checksum: None,
+                });
+            }
+        }
+        Ok(uploads)
     }
 
     async fn upload_folder_compressed(
         &self,
-        path: &Path,
+        paths: &[PathBuf],
         remote_path: &Path,
-    ) -> Result<(), remote::Error> {
-        if !path.is_dir() {
+        options: &remote::ArchiveOptions,
+    ) -> Result<remote::Upload, remote::Error> {
+        if !paths.iter().all(|path| path.is_dir()) {
             return Err(remote::Error::NotADirectory);
         }
 
-        let remote_path = self.remote_archive_path(remote_path);
-        let compressed_folder = self.compress_folder(path).await?;
+        let remote_path = self.remote_archive_path(remote_path, options);
+
+        // tar -cz folder | ssh -Pxxx user@host "cat > file"
+        let mut ssh = self.cat_into(&remote_path)?;
+        let mut stdin = remote::HashWriter::new(ssh.stdin.take().unwrap());
+        let written = self.compress_folder(paths, &mut stdin, options).await;
+
+        self.wait_upload(ssh, stdin, written, &remote_path).await
+    }
+}
+
+/// Exit code of ssh when the connection to the remote host fails.
+const SSH_CONNECTION_ERROR: i32 = 255;
+/// Exit codes of rsync for socket and protocol errors, timeouts and ssh failures.
+const RSYNC_CONNECTION_ERRORS: [i32; 5] = [10, 12, 30, 35, 255];
+
+/// The error of a failed ssh command: only the connection failures are transient.
+fn ssh_error(status: ExitStatus, message: String) -> remote::Error {
+    if status.code() == Some(SSH_CONNECTION_ERROR) {
+        remote::Error::ConnectionFailed(message)
+    } else {
+        remote::Error::CommandFailed(message)
+    }
+}
+
+/// The error of a failed rsync command: only the connection failures are transient.
+fn rsync_error(status: ExitStatus, message: String) -> remote::Error {
+    match status.code() {
+        Some(code) if RSYNC_CONNECTION_ERRORS.contains(&code) => {
+            remote::Error::ConnectionFailed(message)
+        }
+        _ => remote::Error::CommandFailed(message),
+    }
+}
+
+/// Quotes `path` for the remote shell. A leading ~/ is left outside the quotes, thus
+/// it's still expanded to the home folder.
+fn shell_quote(path: &Path) -> String {
+    let path = path.to_str().unwrap();
+    let (home, path) = match path.strip_prefix("~/") {
+        Some(rest) => ("~/", rest),
+        None => ("", path),
+    };
+    format!("{}'{}'", home, path.replace('\'', "'\\''"))
+}
+
+/// Escapes the wildcards in `path`, used as an rsync filter pattern.
+fn rsync_pattern(path: &Path) -> String {
+    let path = path.to_str().unwrap();
+    // The backslash is an escape character only in the patterns with wildcards
+    if !path.contains(['*', '?', '[']) {
+        return String::from(path);
+    }
+    let mut pattern = String::with_capacity(path.len());
+    for c in path.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        self.upload_file(compressed_folder.path(), &remote_path)
-            .await
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote(Path::new("/backup/a b")), "'/backup/a b'");
+        assert_eq!(shell_quote(Path::new("~/backup/$(id)")), "~/'backup/$(id)'");
+        assert_eq!(shell_quote(Path::new("/it's")), "'/it'\\''s'");
+    }
+
+    #[test]
+    fn test_connection_errors() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let exited = |code: i32| ExitStatus::from_raw(code << 8);
+        assert!(ssh_error(exited(255), String::new()).is_transient());
+        assert!(!ssh_error(exited(1), String::new()).is_transient());
+        assert!(rsync_error(exited(30), String::new()).is_transient());
+        assert!(!rsync_error(exited(23), String::new()).is_transient());
+    }
+
+    #[test]
+    fn test_rsync_pattern() {
+        assert_eq!(
+            rsync_pattern(Path::new("app/node_modules")),
+            "app/node_modules"
+        );
+        assert_eq!(rsync_pattern(Path::new("app/a\\b")), "app/a\\b");
+        assert_eq!(
+            rsync_pattern(Path::new("app/[x]\\*.log")),
+            "app/\\[x]\\\\\\*.log"
+        );
     }
 }
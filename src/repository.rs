@@ -0,0 +1,753 @@
+// Copyright 2022 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::backup::RetryPolicy;
+use crate::remotes::remote;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use async_compression::tokio::bufread::ZstdDecoder;
+use async_compression::tokio::write::ZstdEncoder;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Folder of the repository containing the chunks, named after their SHA-256.
+pub const CHUNKS: &str = "chunks";
+/// Folder of the repository containing the snapshot manifests.
+pub const SNAPSHOTS: &str = "snapshots";
+
+/// Chunks are never smaller than this, but the last chunk of a file.
+const MIN_CHUNK: usize = 512 * 1024;
+/// Chunks are cut when this size is reached, even without a boundary.
+const MAX_CHUNK: usize = 8 * 1024 * 1024;
+/// A boundary is found when the top 20 bits of the hash are zero: on average,
+/// a chunk is MIN_CHUNK + 1 MiB long.
+const BOUNDARY_MASK: u64 = 0xFFFF_F000_0000_0000;
+
+#[derive(Debug)]
+pub enum Error {
+    RemoteError(remote::Error),
+    RuntimeError(io::Error),
+    InvalidSnapshot(String),
+    CorruptedChunk(String),
+}
+
+impl std::error::Error for Error {}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::RemoteError(error) => write!(f, "{}", error),
+            Error::RuntimeError(error) => write!(f, "Runtime error: {}", error),
+            Error::InvalidSnapshot(msg) => write!(f, "Invalid snapshot: {}", msg),
+            Error::CorruptedChunk(hash) => write!(f, "Corrupted chunk: {}", hash),
+        }
+    }
+}
+
+impl From<remote::Error> for Error {
+    fn from(error: remote::Error) -> Self {
+        Error::RemoteError(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::RuntimeError(error)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// A file, folder or symlink of a snapshot.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Node {
+    /// Path relative to the restore destination, / separated
+    pub path: String,
+    pub kind: Kind,
+    /// Unix permissions
+    pub mode: u32,
+    pub size: u64,
+    /// Hashes of the chunks of a file, in order
+    #[serde(default)]
+    pub chunks: Vec<String>,
+    /// Target of a symlink
+    pub target: Option<String>,
+}
+
+/// The manifest of a backup execution, uploaded into the snapshots folder.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Snapshot {
+    pub time: DateTime<Utc>,
+    pub nodes: Vec<Node>,
+}
+
+impl Snapshot {
+    /// Total size of the files of the snapshot.
+    pub fn size(&self) -> u64 {
+        self.nodes.iter().map(|node| node.size).sum()
+    }
+}
+
+/// Content-defined chunker: the chunk boundaries depend on the content (a gear hash
+/// of the last 64 bytes), thus an insertion in a file changes only the chunks around it.
+pub struct Chunker {
+    gear: [u64; 256],
+}
+
+impl Default for Chunker {
+    fn default() -> Self {
+        // The table is part of the repository format: changing it (or the chunk sizes)
+        // changes every boundary, and no chunk would be shared with the previous snapshots.
+        let mut gear = [0u64; 256];
+        let mut state: u64 = 0x6261_6375_7062_6163;
+        for value in gear.iter_mut() {
+            // splitmix64
+            state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            *value = z ^ (z >> 31);
+        }
+        Chunker { gear }
+    }
+}
+
+impl Chunker {
+    /// Returns the length of the first chunk of `data`. When no boundary is found,
+    /// the chunk is the whole data (up to MAX_CHUNK): the caller must pass at least
+    /// MAX_CHUNK bytes, but at the end of the file.
+    pub fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= MIN_CHUNK {
+            return data.len();
+        }
+        let end = data.len().min(MAX_CHUNK);
+        let mut hash: u64 = 0;
+        for (i, byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK) {
+            hash = (hash << 1).wrapping_add(self.gear[*byte as usize]);
+            if hash & BOUNDARY_MASK == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+/// Returns the SHA-256 of `data`, the name of a chunk.
+fn hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Returns true if `name` is the name of a chunk (a SHA-256 in hex).
+fn is_chunk(name: &str) -> bool {
+    name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Returns the file name of the objects returned by Remote::enumerate, that are
+/// full paths or paths relative to the remote root, depending on the remote.
+fn file_names(objects: Vec<String>) -> Vec<String> {
+    objects
+        .iter()
+        .filter_map(|object| Path::new(object).file_name()?.to_str())
+        .map(String::from)
+        .collect()
+}
+
+/// Returns `path` as a string, for the manifest. The paths that are not valid UTF-8
+/// can't be stored in the manifest.
+fn utf8(path: &Path) -> io::Result<String> {
+    path.to_str().map(String::from).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a valid UTF-8 path", path.to_string_lossy()),
+        )
+    })
+}
+
+/// Fails when one of the folders containing `relative` is a symlink in `dest`:
+/// writing through it would write outside of dest.
+async fn check_parents(dest: &Path, relative: &Path) -> Result<(), Error> {
+    let mut current = dest.to_path_buf();
+    if let Some(parent) = relative.parent() {
+        for component in parent.components() {
+            current.push(component);
+            let is_symlink = fs::symlink_metadata(&current)
+                .await
+                .is_ok_and(|metadata| metadata.file_type().is_symlink());
+            if is_symlink {
+                return Err(Error::InvalidSnapshot(format!(
+                    "{} is inside the symlink {}",
+                    relative.display(),
+                    current.display()
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A deduplicated repository stored in `path` on a remote: the files are split in
+/// content-defined chunks, stored once (compressed with zstd) in the chunks folder,
+/// and every backup execution uploads a snapshot manifest listing its chunks.
+pub struct Repository<'a> {
+    /// Name of the backup, for the logs
+    pub name: &'a str,
+    pub remote: &'a (dyn remote::Remote + Send + Sync),
+    pub path: PathBuf,
+    pub retry: &'a RetryPolicy,
+}
+
+impl<'a> Repository<'a> {
+    pub fn new(
+        name: &'a str,
+        remote: &'a (dyn remote::Remote + Send + Sync),
+        path: &Path,
+        retry: &'a RetryPolicy,
+    ) -> Repository<'a> {
+        Repository {
+            name,
+            remote,
+            path: path.to_path_buf(),
+            retry,
+        }
+    }
+
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.path.join(CHUNKS).join(hash)
+    }
+
+    /// Lists the remote paths of the snapshots, from the oldest to the most recent.
+    pub async fn snapshots(&self) -> Result<Vec<PathBuf>, Error> {
+        let folder = self.path.join(SNAPSHOTS);
+        let objects = self
+            .retry
+            .run(self.name, "Listing", || self.remote.enumerate(&folder))
+            .await?;
+        let mut names: Vec<String> = file_names(objects)
+            .into_iter()
+            .filter(|name| name.ends_with(".toml"))
+            .collect();
+        // The snapshot names start with the date
+        names.sort();
+        Ok(names.into_iter().map(|name| folder.join(name)).collect())
+    }
+
+    /// Downloads and parses the snapshot `path`.
+    pub async fn snapshot(&self, path: &Path) -> Result<Snapshot, Error> {
+        let downloaded = NamedTempFile::new()?;
+        self.retry
+            .run(self.name, "Download", || {
+                self.remote.download_file(path, downloaded.path())
+            })
+            .await?;
+        let content = fs::read_to_string(downloaded.path()).await?;
+        toml::from_str(&content)
+            .map_err(|error| Error::InvalidSnapshot(format!("{}: {}", path.display(), error)))
+    }
+
+    /// Lists the hashes of the chunks stored in the repository.
+    async fn chunks(&self) -> Result<HashSet<String>, Error> {
+        let folder = self.path.join(CHUNKS);
+        let objects = self
+            .retry
+            .run(self.name, "Listing", || self.remote.enumerate(&folder))
+            .await?;
+        Ok(file_names(objects)
+            .into_iter()
+            .filter(|name| is_chunk(name))
+            .collect())
+    }
+
+    /// Creates a snapshot of the local `paths`, each one with its path in the snapshot.
+    /// Only the chunks not already in the repository are uploaded, then the manifest.
+    /// Returns the uploaded objects, the manifest last.
+    pub async fn backup(&self, paths: &[(PathBuf, PathBuf)]) -> Result<Vec<remote::Upload>, Error> {
+        // On the first execution the chunks folder doesn't exist, and listing it fails
+        let mut known = match self.chunks().await {
+            Ok(known) => known,
+            Err(error) => {
+                warn!(
+                    "[{}] Unable to list the chunks, uploading all of them: {}",
+                    self.name, error
+                );
+                HashSet::new()
+            }
+        };
+
+        let chunker = Chunker::default();
+        let mut uploads = vec![];
+        let mut nodes = vec![];
+        for (local, path) in paths {
+            let path = utf8(path)?;
+            let metadata = fs::symlink_metadata(local).await?;
+            let mode = metadata.permissions().mode();
+            let file_type = metadata.file_type();
+            let node = if file_type.is_symlink() {
+                let target = fs::read_link(local).await?;
+                Node {
+                    path: path.clone(),
+                    kind: Kind::Symlink,
+                    mode,
+                    size: 0,
+                    chunks: vec![],
+                    target: Some(utf8(&target)?),
+                }
+            } else if file_type.is_dir() {
+                Node {
+                    path: path.clone(),
+                    kind: Kind::Dir,
+                    mode,
+                    size: 0,
+                    chunks: vec![],
+                    target: None,
+                }
+            } else {
+                let chunks = self
+                    .store_file(local, &chunker, &mut known, &mut uploads)
+                    .await?;
+                Node {
+                    path: path.clone(),
+                    kind: Kind::File,
+                    mode,
+                    size: metadata.len(),
+                    chunks,
+                    target: None,
+                }
+            };
+            nodes.push(node);
+        }
+
+        let now = Utc::now();
+        let snapshot = Snapshot { time: now, nodes };
+        let content = toml::to_string(&snapshot).map_err(|error| {
+            Error::InvalidSnapshot(format!("unable to serialize the manifest: {}", error))
+        })?;
+        let manifest = NamedTempFile::new()?;
+        fs::write(manifest.path(), content).await?;
+        let remote_path = self
+            .path
+            .join(SNAPSHOTS)
+            .join(format!("{}.toml", now.format("%Y-%m-%d-%H.%M.%S")));
+        let upload = self
+            .retry
+            .run(self.name, "Upload", || {
+                self.remote.upload_file(manifest.path(), &remote_path)
+            })
+            .await?;
+        info!(
+            "[{}] Snapshot {} uploaded, {} new chunks",
+            self.name,
+            remote_path.display(),
+            uploads.len()
+        );
+        uploads.push(upload);
+        Ok(uploads)
+    }
+
+    /// Splits the file `path` in chunks, uploading the ones not in `known`.
+    /// Returns the hashes of the chunks.
+    async fn store_file(
+        &self,
+        path: &Path,
+        chunker: &Chunker,
+        known: &mut HashSet<String>,
+        uploads: &mut Vec<remote::Upload>,
+    ) -> Result<Vec<String>, Error> {
+        let mut file = fs::File::open(path).await?;
+        let mut buffer = Vec::with_capacity(MAX_CHUNK);
+        let mut eof = false;
+        let mut chunks = vec![];
+        loop {
+            if !eof {
+                let missing = (MAX_CHUNK - buffer.len()) as u64;
+                (&mut file).take(missing).read_to_end(&mut buffer).await?;
+                eof = buffer.len() < MAX_CHUNK;
+            }
+            if buffer.is_empty() {
+                break;
+            }
+            let length = chunker.cut(&buffer);
+            let data: Vec<u8> = buffer.drain(..length).collect();
+            let hash = hash(&data);
+            if !known.contains(&hash) {
+                uploads.push(self.upload_chunk(&hash, &data).await?);
+                known.insert(hash.clone());
+            }
+            chunks.push(hash);
+        }
+        Ok(chunks)
+    }
+
+    async fn upload_chunk(&self, hash: &str, data: &[u8]) -> Result<remote::Upload, Error> {
+        let mut encoder = ZstdEncoder::new(Vec::new());
+        encoder.write_all(data).await?;
+        encoder.shutdown().await?;
+        let compressed = NamedTempFile::new()?;
+        fs::write(compressed.path(), encoder.into_inner()).await?;
+
+        let remote_path = self.chunk_path(hash);
+        Ok(self
+            .retry
+            .run(self.name, "Upload", || {
+                self.remote.upload_file(compressed.path(), &remote_path)
+            })
+            .await?)
+    }
+
+    async fn download_chunk(&self, hash: &str) -> Result<Vec<u8>, Error> {
+        let downloaded = NamedTempFile::new()?;
+        let remote_path = self.chunk_path(hash);
+        self.retry
+            .run(self.name, "Download", || {
+                self.remote.download_file(&remote_path, downloaded.path())
+            })
+            .await?;
+        let compressed = fs::read(downloaded.path()).await?;
+        let mut data = vec![];
+        ZstdDecoder::new(compressed.as_slice())
+            .read_to_end(&mut data)
+            .await
+            .map_err(|_| Error::CorruptedChunk(hash.to_string()))?;
+        if self::hash(&data) != hash {
+            return Err(Error::CorruptedChunk(hash.to_string()));
+        }
+        Ok(data)
+    }
+
+    /// Restores the snapshot `path` into the `dest` folder.
+    pub async fn restore(&self, path: &Path, dest: &Path) -> Result<Snapshot, Error> {
+        let snapshot = self.snapshot(path).await?;
+        info!(
+            "[{}] Restoring snapshot {} ({} files and folders)",
+            self.name,
+            path.display(),
+            snapshot.nodes.len()
+        );
+        for node in &snapshot.nodes {
+            let relative = Path::new(&node.path);
+            if relative.is_absolute()
+                || relative
+                    .components()
+                    .any(|component| component == std::path::Component::ParentDir)
+            {
+                return Err(Error::InvalidSnapshot(format!(
+                    "invalid path {}",
+                    node.path
+                )));
+            }
+        }
+        // The symlinks are created last, and nothing is written inside a symlink:
+        // a snapshot with a -> /etc and a/passwd would write outside dest.
+        let (symlinks, nodes): (Vec<&Node>, Vec<&Node>) = snapshot
+            .nodes
+            .iter()
+            .partition(|node| node.kind == Kind::Symlink);
+        for node in nodes.into_iter().chain(symlinks) {
+            let relative = Path::new(&node.path);
+            check_parents(dest, relative).await?;
+            let local = dest.join(relative);
+            if let Some(parent) = local.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            let is_symlink = fs::symlink_metadata(&local)
+                .await
+                .is_ok_and(|metadata| metadata.file_type().is_symlink());
+            if is_symlink {
+                fs::remove_file(&local).await?;
+            }
+            match node.kind {
+                Kind::Dir => fs::create_dir_all(&local).await?,
+                Kind::Symlink => {
+                    if fs::symlink_metadata(&local).await.is_ok() {
+                        fs::remove_file(&local).await?;
+                    }
+                    let target = node.target.as_ref().ok_or_else(|| {
+                        Error::InvalidSnapshot(format!("symlink {} without target", node.path))
+                    })?;
+                    fs::symlink(target, &local).await?;
+                }
+                Kind::File => {
+                    let mut file = fs::File::create(&local).await?;
+                    for hash in &node.chunks {
+                        file.write_all(&self.download_chunk(hash).await?).await?;
+                    }
+                    file.flush().await?;
+                    fs::set_permissions(&local, PermissionsExt::from_mode(node.mode)).await?;
+                }
+            }
+        }
+        // The folders are made read-only (if so) after writing their content
+        for node in snapshot.nodes.iter().filter(|node| node.kind == Kind::Dir) {
+            let local = dest.join(&node.path);
+            fs::set_permissions(&local, PermissionsExt::from_mode(node.mode)).await?;
+        }
+        Ok(snapshot)
+    }
+
+    /// Deletes the oldest snapshots, keeping the last `to_keep`, and then the chunks
+    /// no more referenced by the remaining snapshots. Returns the deleted objects.
+    pub async fn forget(&self, to_keep: usize) -> Result<Vec<String>, Error> {
+        let mut deleted = vec![];
+        let mut snapshots = self.snapshots().await?;
+        let to_delete = snapshots.len().saturating_sub(to_keep);
+        for snapshot in snapshots.drain(..to_delete) {
+            self.delete(&snapshot).await?;
+            deleted.push(snapshot.to_string_lossy().into_owned());
+        }
+
+        // A snapshot that can't be read aborts the collection: its chunks would be deleted
+        let mut referenced = HashSet::new();
+        for snapshot in &snapshots {
+            for node in self.snapshot(snapshot).await?.nodes {
+                referenced.extend(node.chunks);
+            }
+        }
+        for hash in self.chunks().await? {
+            if !referenced.contains(&hash) {
+                let path = self.chunk_path(&hash);
+                self.delete(&path).await?;
+                deleted.push(path.to_string_lossy().into_owned());
+            }
+        }
+        info!(
+            "[{}] Retention: {} snapshots kept, {} objects deleted",
+            self.name,
+            snapshots.len(),
+            deleted.len()
+        );
+        Ok(deleted)
+    }
+
+    async fn delete(&self, path: &Path) -> Result<(), Error> {
+        self.retry
+            .run(self.name, "Delete", || self.remote.delete(path))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LocalhostConfig;
+    use crate::remotes::localhost::Localhost;
+
+    /// Pseudo-random bytes, not compressible and without repetitions.
+    fn random(size: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn boundaries(chunker: &Chunker, mut data: &[u8]) -> Vec<usize> {
+        let mut lengths = vec![];
+        while !data.is_empty() {
+            let length = chunker.cut(data);
+            lengths.push(length);
+            data = &data[length..];
+        }
+        lengths
+    }
+
+    #[test]
+    fn test_chunker() {
+        let chunker = Chunker::default();
+        let data = random(16 * 1024 * 1024, 42);
+        let lengths = boundaries(&chunker, &data);
+        assert!(lengths.len() > 2);
+        assert_eq!(lengths.iter().sum::<usize>(), data.len());
+        for length in &lengths[..lengths.len() - 1] {
+            assert!((MIN_CHUNK..=MAX_CHUNK).contains(length));
+        }
+
+        // An insertion changes only the chunk containing it
+        let mut shifted = vec![0u8; 10];
+        shifted.extend(&data);
+        let shifted_lengths = boundaries(&chunker, &shifted);
+        assert_eq!(shifted_lengths[0], lengths[0] + 10);
+        assert_eq!(shifted_lengths[1..], lengths[1..]);
+    }
+
+    #[tokio::test]
+    async fn test_backup_restore_forget() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let local_dir = tempfile::tempdir().unwrap();
+        let remote = Localhost::new(
+            LocalhostConfig {
+                path: String::from(remote_dir.path().to_str().unwrap()),
+            },
+            "test",
+        )
+        .unwrap();
+        let retry = RetryPolicy::default();
+        let repository = Repository::new("test", &remote, Path::new("/repo"), &retry);
+
+        let data = local_dir.path().join("data");
+        std::fs::create_dir(&data).unwrap();
+        let big = random(6 * 1024 * 1024, 7);
+        std::fs::write(data.join("big"), &big).unwrap();
+        std::fs::write(data.join("empty"), "").unwrap();
+        std::os::unix::fs::symlink("big", data.join("link")).unwrap();
+        let paths: Vec<(PathBuf, PathBuf)> = ["", "/big", "/empty", "/link"]
+            .iter()
+            .map(|name| {
+                (
+                    PathBuf::from(format!("{}{}", data.display(), name)),
+                    PathBuf::from(format!("data{}", name)),
+                )
+            })
+            .collect();
+
+        let first = repository.backup(&paths).await.unwrap();
+        assert!(first.len() > 2);
+        // Nothing changed: only the manifest is uploaded
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let second = repository.backup(&paths).await.unwrap();
+        assert_eq!(second.len(), 1);
+
+        // Appending to the file uploads only its last chunks
+        let mut appended = big.clone();
+        appended.extend(random(1024, 8));
+        std::fs::write(data.join("big"), &appended).unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let third = repository.backup(&paths).await.unwrap();
+        assert!(third.len() < first.len());
+
+        let snapshots = repository.snapshots().await.unwrap();
+        assert_eq!(snapshots.len(), 3);
+        let dest = tempfile::tempdir().unwrap();
+        let snapshot = repository
+            .restore(&snapshots[0], dest.path())
+            .await
+            .unwrap();
+        assert_eq!(snapshot.size(), big.len() as u64);
+        assert_eq!(std::fs::read(dest.path().join("data/big")).unwrap(), big);
+        assert!(dest.path().join("data/empty").is_file());
+        assert_eq!(
+            std::fs::read_link(dest.path().join("data/link")).unwrap(),
+            PathBuf::from("big")
+        );
+
+        // The chunks of the first file version are collected with its snapshots
+        let deleted = repository.forget(1).await.unwrap();
+        assert!(deleted.len() > 2);
+        assert_eq!(repository.snapshots().await.unwrap().len(), 1);
+        let dest = tempfile::tempdir().unwrap();
+        let last = repository.snapshots().await.unwrap().pop().unwrap();
+        repository.restore(&last, dest.path()).await.unwrap();
+        assert_eq!(
+            std::fs::read(dest.path().join("data/big")).unwrap(),
+            appended
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backup_non_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let remote_dir = tempfile::tempdir().unwrap();
+        let local_dir = tempfile::tempdir().unwrap();
+        let remote = Localhost::new(
+            LocalhostConfig {
+                path: String::from(remote_dir.path().to_str().unwrap()),
+            },
+            "test",
+        )
+        .unwrap();
+        let retry = RetryPolicy::default();
+        let repository = Repository::new("test", &remote, Path::new("/repo"), &retry);
+
+        let name = std::ffi::OsStr::from_bytes(b"invalid-\xff");
+        let local = local_dir.path().join(name);
+        std::fs::write(&local, "content").unwrap();
+        let error = repository
+            .backup(&[(local, PathBuf::from(name))])
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(error, Error::RuntimeError(error) if error.kind() == io::ErrorKind::InvalidData)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_through_symlink() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let remote = Localhost::new(
+            LocalhostConfig {
+                path: String::from(remote_dir.path().to_str().unwrap()),
+            },
+            "test",
+        )
+        .unwrap();
+        let retry = RetryPolicy::default();
+        let repository = Repository::new("test", &remote, Path::new("/repo"), &retry);
+
+        let node = |path: &str, kind: Kind, target: Option<&Path>| Node {
+            path: String::from(path),
+            kind,
+            mode: 0o644,
+            size: 0,
+            chunks: vec![],
+            target: target.map(|target| target.to_str().unwrap().to_string()),
+        };
+        let snapshot = Snapshot {
+            time: Utc::now(),
+            nodes: vec![
+                node("a", Kind::Symlink, Some(outside.path())),
+                node("a/passwd", Kind::File, None),
+            ],
+        };
+        let snapshots = remote_dir.path().join("repo").join(SNAPSHOTS);
+        std::fs::create_dir_all(&snapshots).unwrap();
+        let manifest = snapshots.join("2020-01-01-00.00.00.toml");
+        std::fs::write(&manifest, toml::to_string(&snapshot).unwrap()).unwrap();
+
+        let manifest = Path::new("/repo")
+            .join(SNAPSHOTS)
+            .join("2020-01-01-00.00.00.toml");
+        let dest = tempfile::tempdir().unwrap();
+        assert!(repository.restore(&manifest, dest.path()).await.is_err());
+        assert!(!outside.path().join("passwd").exists());
+
+        // A symlink already in the destination is not followed either
+        let dest = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), dest.path().join("a")).unwrap();
+        assert!(matches!(
+            repository.restore(&manifest, dest.path()).await,
+            Err(Error::InvalidSnapshot(_))
+        ));
+        assert!(!outside.path().join("passwd").exists());
+    }
+}
@@ -0,0 +1,494 @@
+// Copyright 2022 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::backup::RetryPolicy;
+use crate::config::{BackupConfig, Compression};
+use crate::encryption;
+use crate::remotes::remote;
+use crate::repository::{self, Repository};
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::info;
+use regex::Regex;
+use tempfile::NamedTempFile;
+use tokio::fs;
+
+#[derive(Debug)]
+pub enum Error {
+    RemoteError(remote::Error),
+    RuntimeError(io::Error),
+    EncryptionError(encryption::Error),
+    RepositoryError(repository::Error),
+    NoArchive(PathBuf),
+    UnknownFormat(String),
+}
+
+impl std::error::Error for Error {}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::RemoteError(error) => write!(f, "{}", error),
+            Error::RuntimeError(error) => write!(f, "Runtime error: {}", error),
+            Error::EncryptionError(error) => write!(f, "{}", error),
+            Error::RepositoryError(error) => write!(f, "{}", error),
+            Error::NoArchive(path) => {
+                write!(f, "No archive found in remote path {}", path.display())
+            }
+            Error::UnknownFormat(name) => write!(f, "Unknown archive format: {}", name),
+        }
+    }
+}
+
+impl From<remote::Error> for Error {
+    fn from(error: remote::Error) -> Self {
+        Error::RemoteError(error)
+    }
+}
+
+impl From<encryption::Error> for Error {
+    fn from(error: encryption::Error) -> Self {
+        Error::EncryptionError(error)
+    }
+}
+
+impl From<repository::Error> for Error {
+    fn from(error: repository::Error) -> Self {
+        Error::RepositoryError(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::RuntimeError(error)
+    }
+}
+
+pub struct Restore {
+    pub name: String,
+    pub r#where: Box<dyn remote::Remote + Send + Sync>,
+    pub remote_path: PathBuf,
+    pub compress: bool,
+    /// The backup is stored as snapshots in a deduplicated repository
+    pub repository: bool,
+    /// Identity used to decrypt the .age archives
+    pub identity: Option<age::x25519::Identity>,
+}
+
+impl Restore {
+    pub fn new(
+        name: &str,
+        remote: Box<dyn remote::Remote + Send + Sync>,
+        config: &BackupConfig,
+    ) -> Restore {
+        Restore {
+            name: String::from(name),
+            r#where: remote,
+            remote_path: PathBuf::from(config.remote_path.clone()),
+            compress: config.compress || config.compression.is_some(),
+            repository: config.repository.unwrap_or(false),
+            identity: None,
+        }
+    }
+
+    /// Removes the YYYY-MM-DD-hh.mm- prefix and the codec (e.g. .gz, .zst.age) suffix
+    /// added by the remotes to the compressed files, returning the original file name.
+    fn original_name(archive: &str) -> String {
+        let name = archive.strip_suffix(encryption::SUFFIX).unwrap_or(archive);
+        let name = name
+            .strip_suffix(Compression::from_name(name).extension())
+            .unwrap();
+        Restore::date_prefix().replace(name, "").to_string()
+    }
+
+    /// The YYYY-MM-DD-hh.mm- prefix of the archive names.
+    fn date_prefix() -> Regex {
+        Regex::new(r"^\d{4}-\d{2}-\d{2}-\d{2}\.\d{2}-").unwrap()
+    }
+
+    /// Returns true if the file name starts with the date prefix of the archives.
+    pub(crate) fn is_archive(path: &str) -> bool {
+        let name = Path::new(path).file_name().unwrap().to_str().unwrap();
+        Restore::date_prefix().is_match(name)
+    }
+
+    /// Lists the archives (or the snapshots, for a repository) stored on the remote
+    /// for this backup, sorted from the oldest to the most recent.
+    pub async fn archives(&self) -> Result<Vec<String>, Error> {
+        if self.repository {
+            let retry = RetryPolicy::default();
+            let repository =
+                Repository::new(&self.name, self.r#where.as_ref(), &self.remote_path, &retry);
+            return Ok(repository
+                .snapshots()
+                .await?
+                .into_iter()
+                .map(|path| path.to_str().unwrap().to_string())
+                .collect());
+        }
+        // Some remotes (e.g. SSH) list the content of the subfolders too: only the
+        // archives directly inside remote_path belong to this backup
+        let folder = self.remote_path.to_str().unwrap().trim_matches('/');
+        let mut archives: Vec<String> = self
+            .r#where
+            .enumerate(&self.remote_path)
+            .await?
+            .into_iter()
+            .filter(|path| {
+                Path::new(path.trim_start_matches('/')).parent() == Some(Path::new(folder))
+            })
+            .filter(|path| Restore::is_archive(path))
+            .collect();
+        // The archive names start with the date, thus sorting by name
+        // means sorting by date.
+        archives.sort_by_key(|path| {
+            PathBuf::from(path)
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        });
+        Ok(archives)
+    }
+
+    /// Restores the backup into the `dest` folder.
+    ///
+    /// If the backup is compressed, `archive` selects which archive to restore (by name
+    /// or by the full remote path); when `None`, the most recent archive is restored.
+    /// Uncompressed backups are always restored by downloading the whole remote path.
+    ///
+    /// Returns the path of the restored file or folder.
+    pub async fn restore(&self, archive: Option<&str>, dest: &Path) -> Result<PathBuf, Error> {
+        if !dest.exists() {
+            fs::create_dir_all(dest).await?;
+        }
+
+        let remote = &self.r#where;
+        if self.repository {
+            return self.restore_snapshot(archive, dest).await;
+        }
+        if !self.compress {
+            info!(
+                "[{}] Downloading [{}] {} into {}",
+                self.name,
+                remote.name(),
+                self.remote_path.display(),
+                dest.display()
+            );
+            remote.download_folder(&self.remote_path, dest).await?;
+            return Ok(dest.to_path_buf());
+        }
+
+        let archive = match archive {
+            Some(archive) if archive.contains('/') => PathBuf::from(archive),
+            Some(archive) => self.remote_path.join(archive),
+            None => match self.archives().await?.pop() {
+                Some(archive) => PathBuf::from(archive),
+                None => return Err(Error::NoArchive(self.remote_path.clone())),
+            },
+        };
+
+        let mut archive_name = archive.file_name().unwrap().to_str().unwrap().to_string();
+        info!(
+            "[{}] Downloading [{}] {}",
+            self.name,
+            remote.name(),
+            archive.display()
+        );
+        let mut downloaded = NamedTempFile::new()?;
+        remote.download_file(&archive, downloaded.path()).await?;
+
+        if archive_name.ends_with(encryption::SUFFIX) {
+            let identity = match &self.identity {
+                Some(identity) => identity,
+                None => return Err(Error::EncryptionError(encryption::Error::MissingIdentity)),
+            };
+            let decrypted = NamedTempFile::new()?;
+            encryption::decrypt_file(downloaded.path(), decrypted.path(), identity).await?;
+            downloaded = decrypted;
+            archive_name = archive_name
+                .trim_end_matches(encryption::SUFFIX)
+                .to_string();
+        }
+
+        let compression = Compression::from_name(&archive_name);
+        let name = archive_name.strip_suffix(compression.extension()).unwrap();
+        if name.ends_with(".tar") {
+            remote::decompress_folder(downloaded.path(), dest, compression).await?;
+            Ok(dest.to_path_buf())
+        } else if compression != Compression::None || Restore::is_archive(name) {
+            let restored = dest.join(Restore::original_name(&archive_name));
+            remote::decompress_file(downloaded.path(), &restored, compression).await?;
+            Ok(restored)
+        } else {
+            Err(Error::UnknownFormat(archive_name))
+        }
+    }
+
+    /// Restores the snapshot `snapshot` (by name or remote path, the most recent one
+    /// when None) of a repository backup into `dest`. When the snapshot contains a
+    /// single file (e.g. a database dump) its path is returned, otherwise `dest`.
+    async fn restore_snapshot(
+        &self,
+        snapshot: Option<&str>,
+        dest: &Path,
+    ) -> Result<PathBuf, Error> {
+        let retry = RetryPolicy::default();
+        let repository =
+            Repository::new(&self.name, self.r#where.as_ref(), &self.remote_path, &retry);
+        let snapshot = match snapshot {
+            Some(snapshot) if snapshot.contains('/') => PathBuf::from(snapshot),
+            Some(snapshot) => self.remote_path.join(repository::SNAPSHOTS).join(snapshot),
+            None => match repository.snapshots().await?.pop() {
+                Some(snapshot) => snapshot,
+                None => return Err(Error::NoArchive(self.remote_path.clone())),
+            },
+        };
+        let restored = repository.restore(&snapshot, dest).await?;
+        match restored.nodes.as_slice() {
+            [node] if node.kind == repository::Kind::File => Ok(dest.join(&node.path)),
+            _ => Ok(dest.to_path_buf()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LocalhostConfig;
+    use crate::remotes::localhost::tests::PrefixListing;
+    use crate::remotes::localhost::Localhost;
+    use crate::remotes::remote::Remote;
+
+    fn backup_config(compress: bool) -> BackupConfig {
+        BackupConfig {
+            what: String::from("folders.test"),
+            r#where: String::from("localhost.test"),
+            when: String::from("daily 00:00"),
+            remote_path: String::from("/backup"),
+            compress,
+            ..Default::default()
+        }
+    }
+
+    fn localhost(path: &Path) -> Localhost {
+        let config = LocalhostConfig {
+            path: String::from(path.to_str().unwrap()),
+        };
+        Localhost::new(config, "test").unwrap()
+    }
+
+    #[test]
+    fn test_original_name() {
+        assert_eq!(
+            Restore::original_name("2022-01-01-00.30-service-dump.sql.gz"),
+            "service-dump.sql"
+        );
+        assert_eq!(Restore::original_name("Cargo.toml.gz"), "Cargo.toml");
+        assert_eq!(
+            Restore::original_name("2022-01-01-00.30-Cargo.toml.gz.age"),
+            "Cargo.toml"
+        );
+        assert_eq!(
+            Restore::original_name("2022-01-01-00.30-Cargo.toml.zst.age"),
+            "Cargo.toml"
+        );
+        assert_eq!(
+            Restore::original_name("2022-01-01-00.30-Cargo.toml"),
+            "Cargo.toml"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_compression() {
+        for compression in [Compression::Zstd, Compression::Xz, Compression::None] {
+            let remote_dir = tempfile::tempdir().unwrap();
+            let local_dir = tempfile::tempdir().unwrap();
+            let remote = localhost(remote_dir.path());
+            let options = remote::ArchiveOptions {
+                compression,
+                level: compression.levels().map(|levels| *levels.end()),
+                ..Default::default()
+            };
+
+            let file = remote
+                .upload_file_compressed(
+                    &PathBuf::from("Cargo.toml"),
+                    &PathBuf::from("/backup/Cargo.toml"),
+                    &options,
+                )
+                .await
+                .unwrap();
+            let folder = remote
+                .upload_folder_compressed(
+                    &[std::env::current_dir().unwrap().join("src")],
+                    &PathBuf::from("/backup/src"),
+                    &options,
+                )
+                .await
+                .unwrap();
+            assert!(folder
+                .remote_path
+                .to_str()
+                .unwrap()
+                .ends_with(&format!("-src.tar{}", compression.extension())));
+
+            let restore = Restore::new("test", Box::new(remote), &backup_config(true));
+            assert_eq!(restore.archives().await.unwrap().len(), 2);
+
+            let restored = restore
+                .restore(file.remote_path.to_str(), local_dir.path())
+                .await
+                .unwrap();
+            assert_eq!(restored, local_dir.path().join("Cargo.toml"));
+            assert_eq!(
+                std::fs::read("Cargo.toml").unwrap(),
+                std::fs::read(restored).unwrap()
+            );
+
+            let restored = restore
+                .restore(folder.remote_path.to_str(), local_dir.path())
+                .await
+                .unwrap();
+            assert_eq!(
+                std::fs::read("src/lib.rs").unwrap(),
+                std::fs::read(restored.join("src").join("lib.rs")).unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restore_compressed_file() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let local_dir = tempfile::tempdir().unwrap();
+        let remote = localhost(remote_dir.path());
+
+        remote
+            .upload_file_compressed(
+                &PathBuf::from("Cargo.toml"),
+                &PathBuf::from("/backup/Cargo.toml"),
+                &remote::ArchiveOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        let restore = Restore::new("test", Box::new(remote), &backup_config(true));
+        assert_eq!(restore.archives().await.unwrap().len(), 1);
+
+        let restored = restore.restore(None, local_dir.path()).await.unwrap();
+        assert_eq!(restored, local_dir.path().join("Cargo.toml"));
+        assert_eq!(
+            std::fs::read("Cargo.toml").unwrap(),
+            std::fs::read(restored).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_compressed_folder() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let local_dir = tempfile::tempdir().unwrap();
+        let remote = localhost(remote_dir.path());
+
+        remote
+            .upload_folder_compressed(
+                &[std::env::current_dir().unwrap().join("src")],
+                &PathBuf::from("/backup/src"),
+                &remote::ArchiveOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        let restore = Restore::new("test", Box::new(remote), &backup_config(true));
+        let restored = restore.restore(None, local_dir.path()).await.unwrap();
+        assert!(restored.join("src").join("lib.rs").exists());
+    }
+
+    #[tokio::test]
+    async fn test_archives_only_in_remote_path() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        for path in [
+            "backup/2022-01-01-00.00-Cargo.toml.gz",
+            "backup/nested/2030-01-01-00.00-Cargo.toml.gz",
+            "backup-old/2030-01-01-00.00-Cargo.toml.gz",
+        ] {
+            let path = remote_dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "bacup").unwrap();
+        }
+
+        // The remote lists the siblings sharing the name as prefix and the subfolders
+        let remote = PrefixListing::new(remote_dir.path());
+        assert_eq!(
+            remote.enumerate(Path::new("/backup")).await.unwrap().len(),
+            3
+        );
+        let restore = Restore::new("test", Box::new(remote), &backup_config(true));
+        assert_eq!(
+            restore.archives().await.unwrap(),
+            vec!["backup/2022-01-01-00.00-Cargo.toml.gz"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_no_archive() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let local_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(remote_dir.path().join("backup")).unwrap();
+
+        let remote = localhost(remote_dir.path());
+        let restore = Restore::new("test", Box::new(remote), &backup_config(true));
+        assert!(matches!(
+            restore.restore(None, local_dir.path()).await,
+            Err(Error::NoArchive(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_restore_encrypted_folder() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let local_dir = tempfile::tempdir().unwrap();
+        let remote = localhost(remote_dir.path());
+        let identity = age::x25519::Identity::generate();
+
+        let upload = remote
+            .upload_folder_compressed(
+                &[std::env::current_dir().unwrap().join("src")],
+                &PathBuf::from("/backup/src"),
+                &remote::ArchiveOptions {
+                    recipient: Some(identity.to_public()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(upload
+            .remote_path
+            .to_str()
+            .unwrap()
+            .ends_with("-src.tar.gz.age"));
+
+        let mut restore = Restore::new("test", Box::new(remote), &backup_config(true));
+        assert!(matches!(
+            restore.restore(None, local_dir.path()).await,
+            Err(Error::EncryptionError(encryption::Error::MissingIdentity))
+        ));
+
+        restore.identity = Some(identity);
+        let restored = restore.restore(None, local_dir.path()).await.unwrap();
+        assert!(restored.join("src").join("lib.rs").exists());
+    }
+}
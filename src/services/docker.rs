@@ -13,12 +13,12 @@
 // limitations under the License.
 
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::string::String;
 use std::vec::Vec;
 
 use crate::config::DockerConfig;
-use crate::services::service::{Dump, Service};
+use crate::services::service::{stderr_excerpt, Dump, Service};
 
 use which::which;
 
@@ -33,12 +33,19 @@ pub struct Docker {
     pub name: String,
     pub cmd: PathBuf,
     pub args: Vec<String>,
+    pub restore_args: Option<Vec<String>>,
+    /// Archived volume, None when the dump is the output of a command
+    pub volume: Option<String>,
+    pub min_size: u64,
 }
 
 #[derive(Debug)]
 pub enum Error {
     CommandNotFound(which::Error),
     RuntimeError(io::Error),
+    InvalidConfiguration(String),
+    DumpFailed { code: Option<i32>, stderr: String },
+    InvalidDump(String),
 }
 
 impl std::error::Error for Error {}
@@ -47,60 +54,170 @@ impl fmt::Display for Error {
         match self {
             Error::CommandNotFound(error) => write!(f, "Command not found: {}", error),
             Error::RuntimeError(error) => write!(f, "Runtime error: {}", error),
+            Error::InvalidConfiguration(msg) => write!(f, "Invalid configuration: {}", msg),
+            Error::DumpFailed { code, stderr } => write!(
+                f,
+                "Dump failed, exit code {}: {}",
+                code.unwrap_or(-1),
+                stderr
+            ),
+            Error::InvalidDump(msg) => write!(f, "Invalid dump: {}", msg),
         }
     }
 }
 
 impl Docker {
+    /// Arguments of the dump and restore commands. In the volume mode, a helper
+    /// container mounts the volume and streams its content as a tar archive.
+    fn commands(config: &DockerConfig) -> Result<(Vec<String>, Option<Vec<String>>), Error> {
+        match (&config.container_name, &config.command, &config.volume) {
+            (Some(container_name), Some(command), None) => {
+                // No -t: the TTY would mangle binary dumps and mix stderr into them
+                let mut args = vec![String::from("exec"), container_name.clone()];
+                args.extend(command.split_whitespace().map(String::from));
+
+                // The restore command reads the dump from stdin, hence -i
+                let restore_args = config.restore_command.as_ref().map(|restore_command| {
+                    let mut restore_args = vec![
+                        String::from("exec"),
+                        String::from("-i"),
+                        container_name.clone(),
+                    ];
+                    restore_args.extend(restore_command.split_whitespace().map(String::from));
+                    restore_args
+                });
+                Ok((args, restore_args))
+            }
+            (None, None, Some(volume)) if config.restore_command.is_none() => {
+                let image = config.image.as_deref().unwrap_or("busybox");
+                // The image is never pulled: it must be available locally
+                let args = [
+                    "run",
+                    "--rm",
+                    "--pull=never",
+                    "-v",
+                    &format!("{}:/volume:ro", volume),
+                    image,
+                    "tar",
+                    "-C",
+                    "/volume",
+                    "-cf",
+                    "-",
+                    ".",
+                ];
+                let restore_args = [
+                    "run",
+                    "--rm",
+                    "--pull=never",
+                    "-i",
+                    "-v",
+                    &format!("{}:/volume", volume),
+                    image,
+                    "tar",
+                    "-C",
+                    "/volume",
+                    "-xf",
+                    "-",
+                ];
+                Ok((
+                    args.iter().map(|arg| arg.to_string()).collect(),
+                    Some(restore_args.iter().map(|arg| arg.to_string()).collect()),
+                ))
+            }
+            _ => Err(Error::InvalidConfiguration(String::from(
+                "either container_name and command (and restore_command), or volume are required",
+            ))),
+        }
+    }
+
     pub async fn new(config: DockerConfig, name: &str) -> Result<Docker, Error> {
-        let cmd = match which("docker") {
+        let (args, restore_args) = Docker::commands(&config)?;
+        let cmd = match which(config.cli.as_deref().unwrap_or("docker")) {
             Err(error) => return Err(Error::CommandNotFound(error)),
             Ok(cmd) => cmd,
         };
 
-        let args = vec!["run", "--rm", "hello-world"];
-        let status = Command::new(&cmd)
-            .args(&args)
-            .stdout(Stdio::null())
-            .status()
-            .await;
-        if status.is_err() {
-            return Err(Error::RuntimeError(status.err().unwrap()));
+        // Check that the volume exists, or that the container is running,
+        // without pulling anything from the network.
+        let probe = match (&config.volume, &config.container_name) {
+            (Some(volume), _) => vec!["volume", "inspect", volume],
+            (None, Some(container_name)) => vec![
+                "inspect",
+                "--type",
+                "container",
+                "--format",
+                "{{.State.Running}}",
+                container_name,
+            ],
+            (None, None) => {
+                return Err(Error::InvalidConfiguration(String::from(
+                    "either container_name or volume is required",
+                )))
+            }
+        };
+        let output = match Command::new(&cmd).args(&probe).output().await {
+            Err(error) => return Err(Error::RuntimeError(error)),
+            Ok(output) => output,
+        };
+        if !output.status.success() {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "{} {} failed, exit code {}: {}",
+                cmd.display(),
+                probe.join(" "),
+                output.status.code().unwrap_or(-1),
+                stderr_excerpt(&output.stderr)
+            ))));
         }
-        let code = status.unwrap().code().unwrap();
-        if code != 0 {
+        if config.volume.is_none() && std::str::from_utf8(&output.stdout).unwrap().trim() != "true"
+        {
             return Err(Error::RuntimeError(io::Error::other(format!(
-                "docker run hello-world failed, exit code {}",
-                code
+                "container {} is not running",
+                config.container_name.unwrap()
             ))));
         }
-
-        let mut args: Vec<String> = vec![
-            String::from("exec"),
-            String::from("-t"),
-            config.container_name,
-        ];
-        let split_command: Vec<String> = config
-            .command
-            .split_whitespace()
-            .map(String::from)
-            .collect();
-        args.extend(split_command);
+        if config.volume.is_some() {
+            let image = config.image.as_deref().unwrap_or("busybox");
+            let output = match Command::new(&cmd)
+                .args(["image", "inspect", "--format", "{{.Id}}", image])
+                .output()
+                .await
+            {
+                Err(error) => return Err(Error::RuntimeError(error)),
+                Ok(output) => output,
+            };
+            if !output.status.success() {
+                return Err(Error::RuntimeError(io::Error::other(format!(
+                    "image {} is not available locally and it's never pulled, run {} pull {}",
+                    image,
+                    cmd.display(),
+                    image
+                ))));
+            }
+        }
 
         Ok(Docker {
             name: String::from(name),
             args,
+            restore_args,
+            volume: config.volume,
+            min_size: config.min_size.unwrap_or(1),
             cmd,
         })
     }
+
+    /// The output of the command, or the tar archive of the volume.
+    fn dump_path(&self) -> PathBuf {
+        let extension = if self.volume.is_some() { "tar" } else { "dump" };
+        std::env::current_dir()
+            .unwrap()
+            .join(PathBuf::from(format!("{}.{}", self.name, extension)))
+    }
 }
 
 #[async_trait]
 impl Service for Docker {
     async fn list(&self) -> Vec<PathBuf> {
-        let dest = std::env::current_dir()
-            .unwrap()
-            .join(PathBuf::from(format!("{}.dump", self.name)));
+        let dest = self.dump_path();
 
         if metadata(&dest).await.is_ok() {
             return vec![dest];
@@ -109,9 +226,7 @@ impl Service for Docker {
     }
 
     async fn dump(&self) -> Result<Dump, Box<dyn std::error::Error>> {
-        let dest = std::env::current_dir()
-            .unwrap()
-            .join(PathBuf::from(format!("{}.dump", self.name)));
+        let dest = self.dump_path();
         let parent = dest.parent().unwrap();
         if !parent.exists() {
             return Err(Error::RuntimeError(io::Error::other(format!(
@@ -123,14 +238,170 @@ impl Service for Docker {
 
         let dest_file = File::create(&dest).await?;
 
-        match Command::new(&self.cmd)
+        // docker exec exits with the exit code of the command
+        let output = match Command::new(&self.cmd)
             .args(&self.args)
             .stdout(Stdio::from(dest_file.try_into_std().unwrap()))
+            .output()
+            .await
+        {
+            Err(error) => return Err(Error::RuntimeError(error).into()),
+            Ok(output) => output,
+        };
+        // On failure, dropping the dump removes the partial file
+        let dump = Dump { path: Some(dest) };
+        if !output.status.success() {
+            return Err(Error::DumpFailed {
+                code: output.status.code(),
+                stderr: stderr_excerpt(&output.stderr),
+            }
+            .into());
+        }
+
+        let size = metadata(dump.path.as_ref().unwrap()).await?.len();
+        if size < self.min_size {
+            return Err(Error::InvalidDump(format!(
+                "{} is {} bytes, less than min_size ({} bytes)",
+                dump.path.as_ref().unwrap().display(),
+                size,
+                self.min_size
+            ))
+            .into());
+        }
+        Ok(dump)
+    }
+
+    async fn restore(&self, dump: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let restore_args = match &self.restore_args {
+            Some(restore_args) => restore_args,
+            None => {
+                return Err(Error::RuntimeError(io::Error::other(format!(
+                    "No restore_command configured for docker.{}",
+                    self.name
+                )))
+                .into())
+            }
+        };
+
+        let dump_file = File::open(dump).await?;
+
+        // docker exec -i container restore_command < dump, or tar -x in the volume
+        let status = match Command::new(&self.cmd)
+            .args(restore_args)
+            .stdin(Stdio::from(dump_file.try_into_std().unwrap()))
+            .stdout(Stdio::null())
             .status()
             .await
         {
-            Ok(_) => Ok(Dump { path: Some(dest) }),
-            Err(error) => Err(Error::RuntimeError(error).into()),
+            Err(error) => return Err(Error::RuntimeError(error).into()),
+            Ok(status) => status,
+        };
+
+        if !status.success() {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "{} {} failed, exit code {}",
+                self.cmd.display(),
+                restore_args.join(" "),
+                status.code().unwrap_or(-1)
+            )))
+            .into());
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands_container() {
+        let config = DockerConfig {
+            container_name: Some(String::from("db")),
+            command: Some(String::from("pg_dumpall -c -U postgres")),
+            restore_command: Some(String::from("psql -U postgres")),
+            ..Default::default()
+        };
+        let (args, restore_args) = Docker::commands(&config).unwrap();
+        assert_eq!(
+            args,
+            vec!["exec", "db", "pg_dumpall", "-c", "-U", "postgres"]
+        );
+        assert_eq!(
+            restore_args.unwrap(),
+            vec!["exec", "-i", "db", "psql", "-U", "postgres"]
+        );
+    }
+
+    #[test]
+    fn test_commands_volume() {
+        let config = DockerConfig {
+            volume: Some(String::from("data")),
+            ..Default::default()
+        };
+        let (args, restore_args) = Docker::commands(&config).unwrap();
+        assert_eq!(
+            args,
+            vec![
+                "run",
+                "--rm",
+                "--pull=never",
+                "-v",
+                "data:/volume:ro",
+                "busybox",
+                "tar",
+                "-C",
+                "/volume",
+                "-cf",
+                "-",
+                "."
+            ]
+        );
+        assert!(restore_args.unwrap().contains(&String::from("-xf")));
+    }
+
+    #[tokio::test]
+    async fn test_new_invalid_configuration() {
+        let config = DockerConfig {
+            container_name: Some(String::from("db")),
+            volume: Some(String::from("data")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Docker::new(config, "test").await,
+            Err(Error::InvalidConfiguration(_))
+        ));
+
+        let config = DockerConfig {
+            volume: Some(String::from("data")),
+            cli: Some(String::from("wat-container-cli")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Docker::new(config, "test").await,
+            Err(Error::CommandNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_dump_and_restore_volume() {
+        let status = Command::new("docker")
+            .args(["volume", "create", "bacup_test_volume"])
+            .stdout(Stdio::null())
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success());
+
+        let config = DockerConfig {
+            volume: Some(String::from("bacup_test_volume")),
+            min_size: Some(512),
+            ..Default::default()
+        };
+        let docker = Docker::new(config, "test_service_docker").await.unwrap();
+        let dump = docker.dump().await.unwrap();
+        assert_eq!(docker.list().await, vec![dump.path.clone().unwrap()]);
+        assert!(docker.restore(dump.path.as_ref().unwrap()).await.is_ok());
     }
 }
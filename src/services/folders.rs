@@ -20,17 +20,28 @@ use async_trait::async_trait;
 
 use tokio::fs;
 
+use crate::filter::{is_regular, Filter};
 use crate::services::service::{Dump, Service};
 
+/// A glob pattern and the folder containing the matched paths.
 #[derive(Clone)]
-pub struct Folder {
+struct Location {
     pattern: String,
+    root: PathBuf,
+}
+
+#[derive(Clone)]
+pub struct Folder {
+    locations: Vec<Location>,
+    filter: Option<Filter>,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
     IsNotAbsolute(PathBuf),
     DoesNotExist(PathBuf),
+    InvalidFilter(String),
+    InvalidConfiguration(String),
 }
 
 impl std::error::Error for Error {}
@@ -39,12 +50,14 @@ impl fmt::Display for Error {
         match self {
             Error::IsNotAbsolute(path) => write!(f, "Path {} is not absolute", path.display()),
             Error::DoesNotExist(path) => write!(f, "Path {} does not exist", path.display()),
+            Error::InvalidFilter(msg) => write!(f, "Invalid filter: {}", msg),
+            Error::InvalidConfiguration(msg) => write!(f, "Invalid configuration: {}", msg),
         }
     }
 }
 
-impl Folder {
-    pub async fn new(pattern: &str) -> Result<Folder, Error> {
+impl Location {
+    async fn new(pattern: &str) -> Result<Location, Error> {
         for token in &["*", "?", "["] {
             if pattern.contains(token) {
                 let base_path = pattern.split(token).next().unwrap();
@@ -58,8 +71,14 @@ impl Folder {
                     return Err(Error::DoesNotExist(PathBuf::from(base_path)));
                 }
 
-                return Ok(Folder {
+                let root = if base_path.is_dir() {
+                    base_path.to_path_buf()
+                } else {
+                    base_path.parent().unwrap().to_path_buf()
+                };
+                return Ok(Location {
                     pattern: String::from(pattern),
+                    root,
                 });
             }
         }
@@ -67,8 +86,9 @@ impl Folder {
         // Single file backup support - not a pattern nor a folder.
         if let Ok(meta) = fs::metadata(pattern).await {
             if meta.is_file() {
-                return Ok(Folder {
+                return Ok(Location {
                     pattern: String::from(pattern),
+                    root: Path::new(pattern).parent().unwrap().to_path_buf(),
                 });
             }
         }
@@ -79,24 +99,165 @@ impl Folder {
         if !path.exists() {
             return Err(Error::DoesNotExist(PathBuf::from(path)));
         }
-        Ok(Folder {
+        Ok(Location {
             pattern: String::from(path.join("**").join("*").to_str().unwrap()),
+            root: path.to_path_buf(),
         })
     }
 }
 
+impl Folder {
+    pub async fn new(pattern: &str) -> Result<Folder, Error> {
+        Folder::from_patterns(&[String::from(pattern)]).await
+    }
+
+    /// Creates the service listing the union of the paths matched by `patterns`
+    /// (folders, files or glob patterns). The roots of the patterns must have different
+    /// names, since they are stored in the remote in folders named after them.
+    pub async fn from_patterns(patterns: &[String]) -> Result<Folder, Error> {
+        if patterns.is_empty() {
+            return Err(Error::InvalidConfiguration(String::from(
+                "at least one pattern is required",
+            )));
+        }
+        let mut locations = vec![];
+        for pattern in patterns {
+            locations.push(Location::new(pattern).await?);
+        }
+
+        let folder = Folder {
+            locations,
+            filter: None,
+        };
+        let roots = folder.roots();
+        if roots.len() > 1 {
+            let mut names = vec![];
+            for root in &roots {
+                if roots
+                    .iter()
+                    .any(|other| other != root && root.starts_with(other))
+                {
+                    return Err(Error::InvalidConfiguration(format!(
+                        "the folder {} is inside another folder of the same service",
+                        root.display()
+                    )));
+                }
+                match root.file_name() {
+                    Some(name) if !names.contains(&name) => names.push(name),
+                    _ => {
+                        return Err(Error::InvalidConfiguration(format!(
+                            "the folder {} can't be backed up together with other folders with the same name",
+                            root.display()
+                        )))
+                    }
+                }
+            }
+        }
+        Ok(folder)
+    }
+
+    /// Excludes from the backup the paths matching the `exclude` patterns and, when
+    /// `ignore_file` is true, the patterns of the .bacupignore file in every root.
+    pub fn with_exclude(mut self, exclude: &[String], ignore_file: bool) -> Result<Folder, Error> {
+        if exclude.is_empty() && !ignore_file {
+            return Ok(self);
+        }
+        for root in self.roots() {
+            let filter = match Filter::new(&root, exclude, ignore_file) {
+                Ok(filter) => filter,
+                Err(error) => return Err(Error::InvalidFilter(error.to_string())),
+            };
+            match &mut self.filter {
+                Some(current) => current.extend(filter),
+                None => self.filter = Some(filter),
+            }
+        }
+        Ok(self)
+    }
+}
+
 #[async_trait]
 impl Service for Folder {
     async fn list(&self) -> Vec<PathBuf> {
-        glob(&self.pattern)
-            .unwrap()
-            .map(|pb_ge| pb_ge.unwrap())
+        let mut paths = vec![];
+        for location in &self.locations {
+            paths.extend(glob(&location.pattern).unwrap().map(|pb_ge| pb_ge.unwrap()));
+        }
+        if self.locations.len() > 1 {
+            paths.sort();
+            paths.dedup();
+        }
+
+        // Sockets, pipes and devices can't be backed up
+        paths
+            .into_iter()
+            .filter(|path| is_regular(path))
+            .filter(|path| match &self.filter {
+                Some(filter) => !filter.is_excluded(path, path.is_dir()),
+                None => true,
+            })
             .collect::<Vec<PathBuf>>()
     }
 
+    fn filter(&self) -> Option<Filter> {
+        self.filter.clone()
+    }
+
+    fn roots(&self) -> Vec<PathBuf> {
+        let mut roots: Vec<PathBuf> = vec![];
+        for location in &self.locations {
+            if !roots.contains(&location.root) {
+                roots.push(location.root.clone());
+            }
+        }
+        roots
+    }
+
     async fn dump(&self) -> Result<Dump, Box<dyn std::error::Error>> {
         Ok(Dump { path: None })
     }
+
+    async fn restore(&self, dump: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if !dump.exists() {
+            return Err(Error::DoesNotExist(dump.to_path_buf()).into());
+        }
+
+        // With multiple roots, the dump contains a folder for every root
+        let roots = self.roots();
+        if roots.len() > 1 {
+            for root in roots {
+                let path = dump.join(root.file_name().unwrap());
+                if path.is_dir() {
+                    copy_into(&path, &root).await?;
+                }
+            }
+            return Ok(());
+        }
+        copy_into(dump, &roots[0]).await
+    }
+}
+
+/// Copies the file `dump` into `root` or, if `dump` is a folder, its content
+/// preserving the relative layout.
+async fn copy_into(dump: &Path, root: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if dump.is_file() {
+        fs::copy(dump, root.join(dump.file_name().unwrap())).await?;
+        return Ok(());
+    }
+
+    let pattern = dump.join("**").join("*");
+    for path in glob(pattern.to_str().unwrap())? {
+        let path = path?;
+        if path.is_file() {
+            let dest = root.join(path.strip_prefix(dump).unwrap());
+            let parent = dest.parent().unwrap();
+            if !parent.exists() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::copy(&path, dest).await?;
+        }
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -137,7 +298,7 @@ mod tests {
         assert!(folder.dump().await.is_ok());
 
         let files = folder.list().await;
-        assert!(files.len() > 0);
+        assert!(!files.is_empty());
 
         let git_info = cwd.join(".git").join("info");
         assert!(files.contains(&git_info));
@@ -173,12 +334,138 @@ mod tests {
         assert!(folder.dump().await.is_ok());
 
         let files = folder.list().await;
-        assert!(files.len() > 0);
+        assert!(!files.is_empty());
 
         let lib_path = cwd.join("src").join("lib.rs");
         assert!(files.contains(&lib_path));
     }
 
+    #[tokio::test]
+    async fn test_list_exclude() {
+        let root = tempfile::tempdir().unwrap();
+        for path in [
+            "src/main.rs",
+            "node_modules/a/index.js",
+            "app.log",
+            "keep.log",
+        ] {
+            let path = root.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "bacup").unwrap();
+        }
+        std::fs::write(root.path().join(".bacupignore"), "*.log\n!keep.log\n").unwrap();
+
+        let folder = Folder::new(root.path().to_str().unwrap())
+            .await
+            .unwrap()
+            .with_exclude(&[String::from("node_modules")], true)
+            .unwrap();
+        let files = folder.list().await;
+        assert!(files.contains(&root.path().join("src").join("main.rs")));
+        assert!(files.contains(&root.path().join("keep.log")));
+        assert!(!files.contains(&root.path().join("app.log")));
+        assert!(!files
+            .iter()
+            .any(|path| path.starts_with(root.path().join("node_modules"))));
+        assert!(folder.filter().is_some());
+
+        // Without the filter, everything is listed
+        let folder = Folder::new(root.path().to_str().unwrap()).await.unwrap();
+        assert!(folder.list().await.contains(&root.path().join("app.log")));
+        assert!(folder.filter().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_from_patterns() {
+        let cwd = env::current_dir().unwrap();
+        assert!(matches!(
+            Folder::from_patterns(&[]).await,
+            Err(Error::InvalidConfiguration(_))
+        ));
+        // A root can't contain another root
+        assert!(matches!(
+            Folder::from_patterns(&[
+                String::from(cwd.join("src").join("services").to_str().unwrap()),
+                String::from(cwd.join("Cargo.toml").to_str().unwrap()),
+            ])
+            .await,
+            Err(Error::InvalidConfiguration(_))
+        ));
+
+        let other = tempfile::tempdir().unwrap();
+        std::fs::create_dir(other.path().join("services")).unwrap();
+        let folder = Folder::from_patterns(&[
+            String::from(cwd.join("src").join("services").to_str().unwrap()),
+            String::from(
+                cwd.join("src")
+                    .join("remotes")
+                    .join("*.rs")
+                    .to_str()
+                    .unwrap(),
+            ),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(
+            folder.roots(),
+            vec![
+                cwd.join("src").join("services"),
+                cwd.join("src").join("remotes")
+            ]
+        );
+        let files = folder.list().await;
+        assert!(files.contains(&cwd.join("src").join("services").join("folders.rs")));
+        assert!(files.contains(&cwd.join("src").join("remotes").join("ssh.rs")));
+
+        // The roots are stored in folders named after them
+        assert!(matches!(
+            Folder::from_patterns(&[
+                String::from(cwd.join("src").join("services").to_str().unwrap()),
+                String::from(other.path().join("services").to_str().unwrap()),
+            ])
+            .await,
+            Err(Error::InvalidConfiguration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_restore_roots() {
+        let root = tempfile::tempdir().unwrap();
+        for name in ["a", "b"] {
+            std::fs::create_dir(root.path().join(name)).unwrap();
+        }
+        let folder = Folder::from_patterns(&[
+            String::from(root.path().join("a").to_str().unwrap()),
+            String::from(root.path().join("b").to_str().unwrap()),
+        ])
+        .await
+        .unwrap();
+
+        let dump = tempfile::tempdir().unwrap();
+        for path in ["a/x/1.txt", "b/2.txt"] {
+            let path = dump.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "bacup").unwrap();
+        }
+        folder.restore(dump.path()).await.unwrap();
+        assert!(root.path().join("a").join("x").join("1.txt").exists());
+        assert!(root.path().join("b").join("2.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_restore_folder() {
+        let root = tempfile::tempdir().unwrap();
+        let folder = Folder::new(root.path().to_str().unwrap()).await.unwrap();
+
+        let cwd = env::current_dir().unwrap();
+        assert!(folder.restore(&cwd.join("src")).await.is_ok());
+        assert!(root.path().join("lib.rs").exists());
+        assert!(root.path().join("services").join("folders.rs").exists());
+
+        assert!(folder.restore(&cwd.join("Cargo.toml")).await.is_ok());
+        assert!(root.path().join("Cargo.toml").exists());
+    }
+
     #[tokio::test]
     async fn test_non_existing_abolute() {
         let cwd = env::current_dir()
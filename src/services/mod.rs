@@ -14,5 +14,9 @@
 
 pub mod docker;
 pub mod folders;
+pub mod mongodb;
+pub mod mysql;
 pub mod postgresql;
+pub mod redis;
 pub mod service;
+pub mod sqlite;
@@ -0,0 +1,367 @@
+// Copyright 2022 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    string::String,
+    vec::Vec,
+};
+use tokio::process::Command;
+
+use async_trait::async_trait;
+use which::which;
+
+use tokio::{fs::metadata, fs::File, io};
+
+use crate::config::MySqlConfig;
+use crate::services::service::{Dump, Service};
+
+#[derive(Clone)]
+pub struct MySql {
+    pub name: String,
+    /// None when all the databases are dumped
+    pub db_name: Option<String>,
+    pub cmd: PathBuf,
+    /// Connection arguments, shared by mysqladmin, mysql and mysqldump
+    pub args: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    CommandNotFound(which::Error),
+    RuntimeError(io::Error),
+    InvalidConfiguration(String),
+}
+
+impl std::error::Error for Error {}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::CommandNotFound(error) => write!(f, "Command not found: {}", error),
+            Error::RuntimeError(error) => write!(f, "Runtime error: {}", error),
+            Error::InvalidConfiguration(msg) => write!(f, "Invalid configuration: {}", msg),
+        }
+    }
+}
+
+impl MySql {
+    /// Arguments for connecting to the server described in `config`.
+    /// The option file must be the first argument.
+    fn connection_args(config: &MySqlConfig) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(option_file) = &config.option_file {
+            args.push(format!(
+                "--defaults-extra-file={}",
+                shellexpand::tilde(option_file)
+            ));
+        }
+        args.push(format!(
+            "--host={}",
+            config.host.as_deref().unwrap_or("localhost")
+        ));
+        args.push(format!("--port={}", config.port.unwrap_or(3306)));
+        if let Some(username) = &config.username {
+            args.push(format!("--user={}", username));
+        }
+        args
+    }
+
+    pub async fn new(config: MySqlConfig, name: &str) -> Result<MySql, Error> {
+        let all_databases = config.all_databases.unwrap_or(false);
+        let db_name = match (&config.db_name, all_databases) {
+            (Some(db_name), false) => Some(db_name.clone()),
+            (None, true) => None,
+            _ => {
+                return Err(Error::InvalidConfiguration(String::from(
+                    "exactly one of db_name and all_databases = true is required",
+                )))
+            }
+        };
+        let args = MySql::connection_args(&config);
+
+        let cmd = match which("mysqladmin") {
+            Err(error) => return Err(Error::CommandNotFound(error)),
+            Ok(cmd) => cmd,
+        };
+        let output = match Command::new(cmd)
+            .args(args.iter().map(String::as_str).chain(["ping"]))
+            .output()
+            .await
+        {
+            Err(error) => return Err(Error::RuntimeError(error)),
+            Ok(output) => output,
+        };
+        if !output.status.success() {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "mysqladmin ping failed, exit code {}: {}",
+                output.status.code().unwrap_or(-1),
+                std::str::from_utf8(&output.stderr).unwrap().trim()
+            ))));
+        }
+
+        // Find mysql and use it to check if the db exists and we can connect with
+        // the provided credentials
+        let cmd = match which("mysql") {
+            Err(error) => return Err(Error::CommandNotFound(error)),
+            Ok(cmd) => cmd,
+        };
+        let query = match &db_name {
+            Some(db_name) => format!(
+                "SELECT 1 FROM INFORMATION_SCHEMA.SCHEMATA WHERE SCHEMA_NAME='{}'",
+                db_name.replace('\'', "''")
+            ),
+            None => String::from("SELECT 1"),
+        };
+        let output = match Command::new(cmd)
+            .args(args.iter().map(String::as_str).chain([
+                "--batch",
+                "--skip-column-names",
+                "-e",
+                &query,
+            ]))
+            .output()
+            .await
+        {
+            Err(error) => return Err(Error::RuntimeError(error)),
+            Ok(output) => output,
+        };
+
+        if !output.status.success() {
+            return Err(Error::RuntimeError(io::Error::other(
+                std::str::from_utf8(&output.stderr).unwrap().trim(),
+            )));
+        }
+        let stdout = std::str::from_utf8(&output.stdout).unwrap().trim();
+        if stdout != "1" {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "database {} does not exist or the user is not allowed to access it",
+                db_name.unwrap_or_default()
+            ))));
+        }
+
+        let cmd = match which("mysqldump") {
+            Err(error) => return Err(Error::CommandNotFound(error)),
+            Ok(cmd) => cmd,
+        };
+
+        Ok(MySql {
+            name: String::from(name),
+            db_name,
+            cmd,
+            args,
+        })
+    }
+
+    fn dump_path(&self) -> PathBuf {
+        std::env::current_dir()
+            .unwrap()
+            .join(PathBuf::from(format!("{}-dump.sql", self.name)))
+    }
+}
+
+#[async_trait]
+impl Service for MySql {
+    async fn list(&self) -> Vec<PathBuf> {
+        let dest = self.dump_path();
+        if metadata(&dest).await.is_ok() {
+            return vec![dest];
+        }
+        return vec![];
+    }
+
+    async fn dump(&self) -> Result<Dump, Box<dyn std::error::Error>> {
+        let dest = self.dump_path();
+        let parent = dest.parent().unwrap();
+        if !parent.exists() {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "Folder {} does not exist.",
+                parent.display()
+            )))
+            .into());
+        }
+
+        // --databases adds the CREATE DATABASE and USE statements to the dump, thus
+        // it can be restored without specifying the database.
+        let mut dump_args = vec![
+            String::from("--single-transaction"),
+            String::from("--routines"),
+            String::from("--events"),
+            format!("--result-file={}", dest.display()),
+        ];
+        match &self.db_name {
+            Some(db_name) => {
+                dump_args.push(String::from("--databases"));
+                dump_args.push(db_name.clone());
+            }
+            None => dump_args.push(String::from("--all-databases")),
+        }
+
+        let status = match Command::new(&self.cmd)
+            .args(self.args.iter().chain(&dump_args))
+            .status()
+            .await
+        {
+            Err(error) => return Err(Error::RuntimeError(error).into()),
+            Ok(status) => status,
+        };
+        // On failure, dropping the dump removes the partial file
+        let dump = Dump { path: Some(dest) };
+        if !status.success() {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "mysqldump failed, exit code {}",
+                status.code().unwrap_or(-1)
+            )))
+            .into());
+        }
+        Ok(dump)
+    }
+
+    async fn restore(&self, dump: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if !dump.is_file() {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "Dump {} does not exist or is not a file.",
+                dump.display()
+            )))
+            .into());
+        }
+        let cmd = match which("mysql") {
+            Err(error) => return Err(Error::CommandNotFound(error).into()),
+            Ok(cmd) => cmd,
+        };
+
+        let dump_file = File::open(dump).await?;
+
+        // mysql < dump
+        let status = match Command::new(&cmd)
+            .args(&self.args)
+            .stdin(Stdio::from(dump_file.try_into_std().unwrap()))
+            .stdout(Stdio::null())
+            .status()
+            .await
+        {
+            Err(error) => return Err(Error::RuntimeError(error).into()),
+            Ok(status) => status,
+        };
+
+        if !status.success() {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "mysql failed, exit code {}",
+                status.code().unwrap_or(-1)
+            )))
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    const USERNAME: &str = "root";
+    const DB_NAME: &str = "mysql";
+    const HOST: &str = "127.0.0.1";
+    const PORT: u16 = 3306;
+    const NAME: &str = "test_service_mysql";
+
+    fn config() -> MySqlConfig {
+        MySqlConfig {
+            username: Some(String::from(USERNAME)),
+            option_file: None,
+            db_name: Some(String::from(DB_NAME)),
+            all_databases: None,
+            host: Some(String::from(HOST)),
+            port: Some(PORT),
+        }
+    }
+
+    #[test]
+    fn test_connection_args() {
+        let mut config = config();
+        config.option_file = Some(String::from("/etc/bacup/my.cnf"));
+        assert_eq!(
+            MySql::connection_args(&config),
+            vec![
+                "--defaults-extra-file=/etc/bacup/my.cnf",
+                "--host=127.0.0.1",
+                "--port=3306",
+                "--user=root"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_new_invalid_configuration() {
+        let mut config = config();
+        config.all_databases = Some(true);
+        assert!(matches!(
+            MySql::new(config, NAME).await,
+            Err(Error::InvalidConfiguration(_))
+        ));
+
+        let mut config = self::config();
+        config.db_name = None;
+        assert!(matches!(
+            MySql::new(config, NAME).await,
+            Err(Error::InvalidConfiguration(_))
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_new_connection_ok() {
+        assert!(MySql::new(config(), NAME).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_new_connection_fail_db_name() {
+        let mut config = config();
+        config.db_name = Some(String::from("wat"));
+        assert!(MySql::new(config, NAME).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_new_connection_fail_host() {
+        let mut config = config();
+        config.host = Some(String::from("wat"));
+        assert!(MySql::new(config, NAME).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_new_connection_fail_port() {
+        let mut config = config();
+        config.port = Some(69);
+        assert!(MySql::new(config, NAME).await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_dump_and_restore_success() {
+        let db = MySql::new(config(), NAME).await.unwrap();
+        let dump = db.dump().await.unwrap();
+        assert!(db.restore(dump.path.as_ref().unwrap()).await.is_ok());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_dump_all_databases() {
+        let mut config = config();
+        config.db_name = None;
+        config.all_databases = Some(true);
+        let db = MySql::new(config, NAME).await.unwrap();
+        assert!(db.dump().await.is_ok());
+    }
+}
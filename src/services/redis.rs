@@ -0,0 +1,243 @@
+// Copyright 2022 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    string::String,
+    vec::Vec,
+};
+use tokio::process::Command;
+
+use async_trait::async_trait;
+use which::which;
+
+use tokio::{fs, fs::metadata, io};
+
+use crate::config::RedisConfig;
+use crate::services::service::{Dump, Service};
+
+#[derive(Clone)]
+pub struct Redis {
+    pub name: String,
+    pub cmd: PathBuf,
+    pub args: Vec<String>,
+    /// Passed to redis-cli in the REDISCLI_AUTH variable, not on the command line
+    pub password: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    CommandNotFound(which::Error),
+    RuntimeError(io::Error),
+}
+
+impl std::error::Error for Error {}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::CommandNotFound(error) => write!(f, "Command not found: {}", error),
+            Error::RuntimeError(error) => write!(f, "Runtime error: {}", error),
+        }
+    }
+}
+
+impl Redis {
+    pub async fn new(config: RedisConfig, name: &str) -> Result<Redis, Error> {
+        let host = config.host.unwrap_or_else(|| String::from("localhost"));
+        let port = config.port.unwrap_or(6379);
+        let password = match &config.password_file {
+            Some(path) => match fs::read_to_string(shellexpand::tilde(path).as_ref()).await {
+                Ok(password) => Some(password.trim().to_string()),
+                Err(error) => return Err(Error::RuntimeError(error)),
+            },
+            None => None,
+        };
+
+        let cmd = match which("redis-cli") {
+            Err(error) => return Err(Error::CommandNotFound(error)),
+            Ok(cmd) => cmd,
+        };
+
+        let redis = Redis {
+            name: String::from(name),
+            cmd,
+            args: vec![
+                String::from("-h"),
+                host,
+                String::from("-p"),
+                port.to_string(),
+            ],
+            password,
+        };
+
+        // redis-cli exits with 0 even when the command fails (e.g. NOAUTH),
+        // thus the reply is checked.
+        let output = match redis.command().arg("PING").output().await {
+            Err(error) => return Err(Error::RuntimeError(error)),
+            Ok(output) => output,
+        };
+        let stdout = std::str::from_utf8(&output.stdout).unwrap().trim();
+        if !output.status.success() || stdout != "PONG" {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "redis-cli PING failed: {} {}",
+                stdout,
+                std::str::from_utf8(&output.stderr).unwrap().trim()
+            ))));
+        }
+
+        Ok(redis)
+    }
+
+    /// redis-cli, with the connection arguments and the password.
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.cmd);
+        command.args(&self.args);
+        if let Some(password) = &self.password {
+            command.env("REDISCLI_AUTH", password);
+        }
+        command
+    }
+
+    fn dump_path(&self) -> PathBuf {
+        std::env::current_dir()
+            .unwrap()
+            .join(PathBuf::from(format!("{}-dump.rdb", self.name)))
+    }
+}
+
+#[async_trait]
+impl Service for Redis {
+    async fn list(&self) -> Vec<PathBuf> {
+        let dest = self.dump_path();
+        if metadata(&dest).await.is_ok() {
+            return vec![dest];
+        }
+        return vec![];
+    }
+
+    async fn dump(&self) -> Result<Dump, Box<dyn std::error::Error>> {
+        let dest = self.dump_path();
+        let parent = dest.parent().unwrap();
+        if !parent.exists() {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "Folder {} does not exist.",
+                parent.display()
+            )))
+            .into());
+        }
+
+        // --rdb asks the server for a snapshot (the same of BGSAVE) and streams it into
+        // dest, thus the server data folder doesn't have to be accessible.
+        let output = match self
+            .command()
+            .arg("--rdb")
+            .arg(&dest)
+            .stdout(Stdio::null())
+            .output()
+            .await
+        {
+            Err(error) => return Err(Error::RuntimeError(error).into()),
+            Ok(output) => output,
+        };
+        // On failure, dropping the dump removes the partial snapshot
+        let dump = Dump { path: Some(dest) };
+        if !output.status.success() {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "redis-cli --rdb failed, exit code {}: {}",
+                output.status.code().unwrap_or(-1),
+                std::str::from_utf8(&output.stderr).unwrap().trim()
+            )))
+            .into());
+        }
+        Ok(dump)
+    }
+
+    async fn restore(&self, dump: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        // Redis loads the RDB file only at startup: it can't be loaded into a running server.
+        Err(Error::RuntimeError(io::Error::other(format!(
+            "an RDB snapshot can't be loaded into a running server: stop redis.{}, \
+            replace its RDB file (CONFIG GET dir, CONFIG GET dbfilename) with {} \
+            and start it again",
+            self.name,
+            dump.display()
+        )))
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    const HOST: &str = "localhost";
+    const PORT: u16 = 6379;
+    const NAME: &str = "test_service_redis";
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_new_connection_ok() {
+        let config = RedisConfig {
+            host: Some(String::from(HOST)),
+            port: Some(PORT),
+            password_file: None,
+        };
+        assert!(Redis::new(config, NAME).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_new_connection_fail_port() {
+        let config = RedisConfig {
+            host: Some(String::from(HOST)),
+            port: Some(69),
+            password_file: None,
+        };
+        assert!(Redis::new(config, NAME).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_new_missing_password_file() {
+        let config = RedisConfig {
+            host: Some(String::from(HOST)),
+            port: Some(PORT),
+            password_file: Some(String::from("/wat/password")),
+        };
+        assert!(matches!(
+            Redis::new(config, NAME).await,
+            Err(Error::RuntimeError(_))
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_dump_success() {
+        let config = RedisConfig {
+            host: Some(String::from(HOST)),
+            port: Some(PORT),
+            password_file: None,
+        };
+
+        let redis = Redis::new(config, NAME).await.unwrap();
+        let dump = redis.dump().await.unwrap();
+        let mut magic = [0u8; 5];
+        std::io::Read::read_exact(
+            &mut std::fs::File::open(dump.path.as_ref().unwrap()).unwrap(),
+            &mut magic,
+        )
+        .unwrap();
+        assert_eq!(&magic, b"REDIS");
+        assert!(redis.restore(dump.path.as_ref().unwrap()).await.is_err());
+    }
+}
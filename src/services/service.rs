@@ -12,10 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use dyn_clone::DynClone;
 
+use crate::filter::Filter;
+
 use async_trait::async_trait;
 
 pub struct Dump {
@@ -25,19 +27,68 @@ pub struct Dump {
 impl Drop for Dump {
     fn drop(&mut self) {
         if let Some(path) = &self.path {
-            // If we created a dump file, we should take care of removing it
-            if path.exists() {
-                #[allow(unused_must_use)]
-                {
-                    std::fs::remove_file(path);
-                }
+            // If we created a dump file (or folder), we should take care of removing it
+            #[allow(unused_must_use)]
+            if path.is_dir() {
+                std::fs::remove_dir_all(path);
+            } else if path.exists() {
+                std::fs::remove_file(path);
             }
         }
     }
 }
 
+/// The last lines of the `stderr` of a failed dump command, short enough for
+/// the error messages and the notifications.
+pub fn stderr_excerpt(stderr: &[u8]) -> String {
+    const MAX_LINES: usize = 10;
+    const MAX_CHARS: usize = 1024;
+    let stderr = String::from_utf8_lossy(stderr);
+    let lines: Vec<&str> = stderr.trim().lines().collect();
+    let excerpt = lines[lines.len().saturating_sub(MAX_LINES)..].join("\n");
+    let count = excerpt.chars().count();
+    if count > MAX_CHARS {
+        return format!(
+            "...{}",
+            excerpt.chars().skip(count - MAX_CHARS).collect::<String>()
+        );
+    }
+    excerpt
+}
+
 #[async_trait]
 pub trait Service: DynClone {
     async fn dump(&self) -> Result<Dump, Box<dyn std::error::Error>>;
+    async fn restore(&self, dump: &Path) -> Result<(), Box<dyn std::error::Error>>;
     async fn list(&self) -> Vec<PathBuf>;
+
+    /// The paths excluded from the archives and the synced folders, if any.
+    fn filter(&self) -> Option<Filter> {
+        None
+    }
+
+    /// The folders containing the listed files. When they are more than one, each
+    /// folder is stored in the remote in its own folder, named after it.
+    fn roots(&self) -> Vec<PathBuf> {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stderr_excerpt() {
+        assert_eq!(stderr_excerpt(b""), "");
+        assert_eq!(stderr_excerpt(b"  error: wat\n"), "error: wat");
+
+        let stderr: String = (0..20).map(|i| format!("line {}\n", i)).collect();
+        let excerpt = stderr_excerpt(stderr.as_bytes());
+        assert!(excerpt.starts_with("line 10\n"));
+        assert!(excerpt.ends_with("line 19"));
+
+        let excerpt = stderr_excerpt("x".repeat(2000).as_bytes());
+        assert_eq!(excerpt.len(), 3 + 1024);
+    }
 }
@@ -0,0 +1,253 @@
+// Copyright 2022 Paolo Galeone <nessuno@nerdz.eu>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    string::String,
+};
+use tokio::process::Command;
+
+use async_trait::async_trait;
+use which::which;
+
+use tokio::{fs, fs::metadata, io};
+
+use crate::config::SqliteConfig;
+use crate::services::service::{Dump, Service};
+
+#[derive(Clone)]
+pub struct Sqlite {
+    pub name: String,
+    pub path: PathBuf,
+    pub cmd: PathBuf,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    CommandNotFound(which::Error),
+    RuntimeError(io::Error),
+    DoesNotExist(PathBuf),
+}
+
+impl std::error::Error for Error {}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::CommandNotFound(error) => write!(f, "Command not found: {}", error),
+            Error::RuntimeError(error) => write!(f, "Runtime error: {}", error),
+            Error::DoesNotExist(path) => write!(f, "Database {} does not exist", path.display()),
+        }
+    }
+}
+
+/// Quotes `path` as an argument of the sqlite3 dot-commands.
+fn quote(path: &Path) -> String {
+    format!(
+        "\"{}\"",
+        path.to_str()
+            .unwrap()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+    )
+}
+
+impl Sqlite {
+    pub async fn new(config: SqliteConfig, name: &str) -> Result<Sqlite, Error> {
+        let path = PathBuf::from(shellexpand::tilde(&config.path).to_string());
+        if !path.is_file() {
+            return Err(Error::DoesNotExist(path));
+        }
+
+        let cmd = match which("sqlite3") {
+            Err(error) => return Err(Error::CommandNotFound(error)),
+            Ok(cmd) => cmd,
+        };
+
+        // Check that the file is a database we can read
+        let sqlite = Sqlite {
+            name: String::from(name),
+            path,
+            cmd,
+        };
+        sqlite
+            .execute(&["-readonly"], "SELECT count(*) FROM sqlite_master")
+            .await?;
+        Ok(sqlite)
+    }
+
+    /// Executes the sqlite3 `command` (SQL or dot-command) on the database.
+    async fn execute(&self, options: &[&str], command: &str) -> Result<(), Error> {
+        let output = match Command::new(&self.cmd)
+            .arg("-bail")
+            .args(options)
+            .arg(&self.path)
+            .arg(command)
+            .output()
+            .await
+        {
+            Err(error) => return Err(Error::RuntimeError(error)),
+            Ok(output) => output,
+        };
+
+        // sqlite3 can exit with 0 even if the dot-commands fail
+        let stderr = std::str::from_utf8(&output.stderr).unwrap().trim();
+        if !output.status.success() || !stderr.is_empty() {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "sqlite3 {} failed on {}: {}",
+                command,
+                self.path.display(),
+                stderr
+            ))));
+        }
+        Ok(())
+    }
+
+    fn dump_path(&self) -> PathBuf {
+        std::env::current_dir()
+            .unwrap()
+            .join(PathBuf::from(format!("{}-dump.db", self.name)))
+    }
+}
+
+#[async_trait]
+impl Service for Sqlite {
+    async fn list(&self) -> Vec<PathBuf> {
+        let dest = self.dump_path();
+        if metadata(&dest).await.is_ok() {
+            return vec![dest];
+        }
+        return vec![];
+    }
+
+    async fn dump(&self) -> Result<Dump, Box<dyn std::error::Error>> {
+        let dest = self.dump_path();
+        let parent = dest.parent().unwrap();
+        if !parent.exists() {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "Folder {} does not exist.",
+                parent.display()
+            )))
+            .into());
+        }
+        if dest.exists() {
+            fs::remove_file(&dest).await?;
+        }
+
+        // The online backup API copies a consistent snapshot of the database,
+        // even while the application writes to it.
+        let dump = Dump { path: Some(dest) };
+        self.execute(
+            &[],
+            &format!(".backup {}", quote(dump.path.as_ref().unwrap())),
+        )
+        .await?;
+        Ok(dump)
+    }
+
+    async fn restore(&self, dump: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if !dump.is_file() {
+            return Err(Error::RuntimeError(io::Error::other(format!(
+                "Dump {} does not exist or is not a file.",
+                dump.display()
+            )))
+            .into());
+        }
+
+        // .restore replaces the content of the database using the backup API, thus
+        // the database file is locked, and not overwritten, while the application uses it.
+        self.execute(&[], &format!(".restore {}", quote(dump)))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn create_database(path: &Path, value: &str) {
+        let status = Command::new("sqlite3")
+            .arg(path)
+            .arg(format!(
+                "CREATE TABLE IF NOT EXISTS t(v TEXT); DELETE FROM t; INSERT INTO t VALUES('{}');",
+                value
+            ))
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success());
+    }
+
+    async fn value(path: &Path) -> String {
+        let output = Command::new("sqlite3")
+            .arg(path)
+            .arg("SELECT v FROM t")
+            .output()
+            .await
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(
+            quote(Path::new("/tmp/a \"b\"\\c.db")),
+            "\"/tmp/a \\\"b\\\"\\\\c.db\""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_new_does_not_exist() {
+        let config = SqliteConfig {
+            path: String::from("/wat/app.db"),
+        };
+        assert!(matches!(
+            Sqlite::new(config, "test").await,
+            Err(Error::DoesNotExist(_))
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_new_not_a_database() {
+        let config = SqliteConfig {
+            path: String::from("Cargo.toml"),
+        };
+        assert!(matches!(
+            Sqlite::new(config, "test").await,
+            Err(Error::RuntimeError(_))
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_dump_and_restore_success() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("app.db");
+        create_database(&path, "first").await;
+
+        let config = SqliteConfig {
+            path: String::from(path.to_str().unwrap()),
+        };
+        let db = Sqlite::new(config, "test_service_sqlite").await.unwrap();
+        let dump = db.dump().await.unwrap();
+        assert_eq!(db.list().await, vec![dump.path.clone().unwrap()]);
+        assert_eq!(value(dump.path.as_ref().unwrap()).await, "first");
+
+        create_database(&path, "second").await;
+        db.restore(dump.path.as_ref().unwrap()).await.unwrap();
+        assert_eq!(value(&path).await, "first");
+    }
+}